@@ -0,0 +1,402 @@
+// API KEYS
+//   POST   /api-keys       creates a key, the key itself is only returned by this call
+//   GET    /api-keys       lists the keys, without the keys themselves
+//   DELETE /api-keys/{id}  revokes a key
+// Long-lived credentials for service-to-service calls, sent like the access tokens of the users :
+// 'Authorization: Bearer <key>'.
+// A key looks like 'rak_1a2b3c4d_<secret>'. Its prefix 'rak_1a2b3c4d' is stored in clear, to
+// recognize the key in lists and logs, while only a SHA-256 hash of the whole key is stored : the
+// keys cannot be read from the database. A key only has the scopes given when it was created, and
+// cannot create a key with scopes it does not have itself.
+// The first key is created with the 'create-api-key' command of the binary.
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::digest::{digest, SHA256};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{format_scopes, now, parse_scopes, random_bytes, Principal, RequireScope, Scope};
+use crate::db::{Db, DbError};
+use crate::error::{AppError, Problem};
+use crate::extract::{Id, Json, Path};
+use crate::state::AppState;
+
+// first characters of every key, to tell them apart from the access tokens
+pub const KEY_PREFIX: &str = "rak_";
+
+// 'last_used_at' is written at most once per this number of seconds and key
+const LAST_USED_PRECISION_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    #[schema(example = "rak_1a2b3c4d")]
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    // seconds since the Unix epoch
+    pub created_at: u64,
+    pub last_used_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+}
+
+// Body of 'POST /api-keys'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+// Answer of 'POST /api-keys' : the only time the key is sent.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    #[schema(example = "rak_1a2b3c4d_TwlVuTj6Jbm8w8bOYtq6MzIYc6LVN2dc0JH8zS4Ju3Q")]
+    pub key: String,
+}
+
+// Storage of the keys in the database.
+#[derive(Clone)]
+pub struct ApiKeyStore {
+    db: Db,
+}
+
+const API_KEY_COLUMNS: &str = "id, name, prefix, scopes, created_at, last_used_at, revoked_at";
+
+fn api_key_from_row(row: &Row<'_>) -> rusqlite::Result<ApiKey> {
+    let scopes: String = row.get(3)?;
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        prefix: row.get(2)?,
+        scopes: parse_scopes(&scopes),
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+        revoked_at: row.get(6)?,
+    })
+}
+
+// hexadecimal SHA-256 of the key, the only form of the key stored. The reset tokens of the
+// passwords are stored the same way.
+pub fn hash(key: &str) -> String {
+    digest(&SHA256, key.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+impl ApiKeyStore {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    pub async fn create(&self, body: CreateApiKey) -> Result<CreatedApiKey, DbError> {
+        let id: String = random_bytes::<4>()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let prefix = format!("{KEY_PREFIX}{id}");
+        let key = format!("{prefix}_{}", URL_SAFE_NO_PAD.encode(random_bytes::<32>()));
+        let mut scopes = body.scopes;
+        scopes.sort_by_key(|scope| scope.as_str());
+        scopes.dedup();
+        let mut api_key = ApiKey {
+            id: 0,
+            name: body.name,
+            prefix,
+            scopes,
+            created_at: now(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        let hashed = hash(&key);
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO api_keys (name, prefix, hash, scopes, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        api_key.name,
+                        api_key.prefix,
+                        hashed,
+                        format_scopes(&api_key.scopes),
+                        api_key.created_at
+                    ],
+                )?;
+                api_key.id = conn.last_insert_rowid() as u64;
+                Ok(CreatedApiKey { api_key, key })
+            })
+            .await
+    }
+
+    pub async fn list(&self) -> Result<Vec<ApiKey>, DbError> {
+        self.db
+            .call(|conn| {
+                let mut statement = conn.prepare(&format!(
+                    "SELECT {API_KEY_COLUMNS} FROM api_keys ORDER BY id"
+                ))?;
+                let keys = statement
+                    .query_map([], api_key_from_row)?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(keys)
+            })
+            .await
+    }
+
+    // Returns whether the key exists. Revoking a key twice keeps the first revocation date.
+    pub async fn revoke(&self, id: u64) -> Result<bool, DbError> {
+        self.db
+            .call(move |conn| {
+                let updated = conn.execute(
+                    "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, ?2) WHERE id = ?1",
+                    params![id, now()],
+                )?;
+                Ok(updated > 0)
+            })
+            .await
+    }
+
+    // The key matching this secret, unless it is revoked. Records the time of its use.
+    pub async fn authenticate(&self, key: String) -> Result<Option<ApiKey>, DbError> {
+        self.db
+            .call(move |conn| {
+                let api_key = conn
+                    .query_row(
+                        &format!(
+                            "SELECT {API_KEY_COLUMNS} FROM api_keys
+                             WHERE hash = ?1 AND revoked_at IS NULL"
+                        ),
+                        [hash(&key)],
+                        api_key_from_row,
+                    )
+                    .optional()?;
+                let Some(mut api_key) = api_key else {
+                    return Ok(None);
+                };
+                // a busy key is not written on each request
+                let now = now();
+                if api_key
+                    .last_used_at
+                    .is_none_or(|used| used + LAST_USED_PRECISION_SECS <= now)
+                {
+                    conn.execute(
+                        "UPDATE api_keys SET last_used_at = ?2 WHERE id = ?1",
+                        params![api_key.id, now],
+                    )?;
+                    api_key.last_used_at = Some(now);
+                }
+                Ok(Some(api_key))
+            })
+            .await
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api-keys", get(list_api_keys).require(Scope::KeysRead))
+        .route("/api-keys", post(create_api_key).require(Scope::KeysWrite))
+        .route(
+            "/api-keys/{id}",
+            delete(revoke_api_key).require(Scope::KeysWrite),
+        )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api-keys",
+    tag = "api-keys",
+    security(("bearer" = ["keys:read"])),
+    responses((status = 200, description = "Every key, revoked or not", body = [ApiKey])),
+)]
+async fn list_api_keys(State(state): State<AppState>) -> Result<Json<Vec<ApiKey>>, AppError> {
+    Ok(Json(state.api_keys.list().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api-keys",
+    tag = "api-keys",
+    security(("bearer" = ["keys:write"])),
+    request_body = CreateApiKey,
+    responses(
+        (status = 201, description = "The created key, keep it : it cannot be read again", body = CreatedApiKey),
+        (status = 403, description = "A scope of the new key is not granted to the caller", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn create_api_key(
+    State(state): State<AppState>,
+    principal: Principal,
+    Json(body): Json<CreateApiKey>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(scope) = body
+        .scopes
+        .iter()
+        .find(|scope| !principal.has_scope(**scope))
+    {
+        return Err(AppError::forbidden(format!(
+            "cannot grant the scope '{scope}' : it is not granted to the caller"
+        )));
+    }
+    let created = state.api_keys.create(body).await?;
+    tracing::info!(
+        id = created.api_key.id,
+        prefix = created.api_key.prefix,
+        "API key created"
+    );
+    let location = format!("/api-keys/{}", created.api_key.id);
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(created),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api-keys/{id}",
+    tag = "api-keys",
+    security(("bearer" = ["keys:write"])),
+    params(("id" = u64, Path, description = "Identifier of the key")),
+    responses(
+        (status = 204, description = "The key is revoked"),
+        (status = 404, description = "No such key", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn revoke_api_key(
+    State(state): State<AppState>,
+    Path(Id(id)): Path<Id>,
+) -> Result<StatusCode, AppError> {
+    if state.api_keys.revoke(id).await? {
+        tracing::info!(id, "API key revoked");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::not_found(format!("API key {id} does not exist")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, middleware};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+
+    async fn create(state: &AppState, scopes: Vec<Scope>) -> CreatedApiKey {
+        let body = CreateApiKey {
+            name: String::from("service"),
+            scopes,
+        };
+        state.api_keys.create(body).await.unwrap()
+    }
+
+    async fn send(state: &AppState, request: Request<Body>) -> StatusCode {
+        let app = routes()
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                crate::auth::authenticate,
+            ))
+            .with_state(state.clone());
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    fn list(key: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/api-keys")
+            .header(header::AUTHORIZATION, format!("Bearer {key}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn set_last_used_at(state: &AppState, id: u64, last_used_at: u64) {
+        let updated = state.db.call(move |conn| {
+            conn.execute(
+                "UPDATE api_keys SET last_used_at = ?2 WHERE id = ?1",
+                params![id, last_used_at],
+            )?;
+            Ok(())
+        });
+        updated.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_only_the_hash_of_the_key() {
+        let state = AppState::test(&Config::default());
+        let created = create(&state, vec![Scope::UsersRead]).await;
+        // the secret itself may contain '_'
+        let prefix = format!("{}_", created.api_key.prefix);
+        let secret = created.key.strip_prefix(&prefix).unwrap();
+
+        let row = state.db.call(|conn| {
+            Ok(conn.query_row(
+                "SELECT name || prefix || hash || scopes FROM api_keys",
+                [],
+                |row| row.get::<_, String>(0),
+            )?)
+        });
+        let row = row.await.unwrap();
+        assert!(row.contains(&hash(&created.key)));
+        assert!(!row.contains(secret));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_grant_a_scope_the_caller_does_not_have() {
+        let state = AppState::test(&Config::default());
+        let caller = create(&state, vec![Scope::KeysWrite]).await.key;
+        let create = |scopes: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/api-keys")
+                .header(header::AUTHORIZATION, format!("Bearer {caller}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(
+                    r#"{{"name": "other", "scopes": {scopes}}}"#
+                )))
+                .unwrap()
+        };
+
+        let status = send(&state, create(r#"["keys:write", "users:read"]"#)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = send(&state, create(r#"["keys:write"]"#)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_revoked_key() {
+        let state = AppState::test(&Config::default());
+        let created = create(&state, vec![Scope::KeysRead]).await;
+
+        assert_eq!(send(&state, list(&created.key)).await, StatusCode::OK);
+        assert!(state.api_keys.revoke(created.api_key.id).await.unwrap());
+        let status = send(&state, list(&created.key)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn writes_the_last_use_at_most_once_a_minute() {
+        let state = AppState::test(&Config::default());
+        let created = create(&state, vec![Scope::KeysRead]).await;
+        let id = created.api_key.id;
+        let last_used_at = || async {
+            let api_key = state.api_keys.authenticate(created.key.clone()).await;
+            api_key.unwrap().unwrap().last_used_at.unwrap()
+        };
+
+        assert!(last_used_at().await >= created.api_key.created_at);
+        let recently = now() - LAST_USED_PRECISION_SECS + 5;
+        set_last_used_at(&state, id, recently).await;
+        assert_eq!(last_used_at().await, recently);
+        set_last_used_at(&state, id, now() - LAST_USED_PRECISION_SECS).await;
+        assert!(last_used_at().await > recently);
+    }
+}
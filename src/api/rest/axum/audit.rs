@@ -0,0 +1,676 @@
+// AUDIT
+//   GET /audit  the entries of the audit log, a page at a time, filtered
+// Every request that may change something (POST, PUT, PATCH and DELETE) on a route of the API is
+// appended to the audit log, a file of JSON lines ('audit.path') :
+//   {"seq":7,"time":1792296614,"request_id":"3f0c...","actor":"user:1","action":"user.updated",
+//    "target":"user:5","method":"PATCH","path":"/users/5","status":200,
+//    "changes":{"email":{"before":"sam@shire.com","after":"sam@bagend.shire"}},
+//    "prev":"9c4e...","hash":"b51a..."}
+// The routes describe what they changed by adding an 'AuditChange' to their answer, with the state
+// of the resource before and after : the entry holds the fields that differ. Without it, the
+// action is the method and the route ('PUT /users/{id}/roles/{name}'), and the target the path.
+// The refused requests are recorded too, with their status, but never the bodies : the middleware
+// runs before the rate limits, the authentication and the body limit, so their refusals ('429',
+// '413', ...) are recorded as well as the '401' and '403' of the routes.
+// The log is only appended to. Each entry holds the hash of the previous one ('prev') and its own
+// hash, the SHA-256 of the entry without 'hash' : changing, inserting or removing an entry breaks
+// the chain from there, which the 'verify-audit' command finds. Removing the last entries keeps
+// a valid chain : the hash printed by 'verify-audit' can be kept elsewhere, to compare later.
+// Reading requires the 'audit:read' scope.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
+    routing::get,
+    Router,
+};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::auth::{now, Principal, RequireScope, Scope};
+use crate::config::AuditConfig;
+use crate::error::{AppError, ErrorCode, Problem};
+use crate::extract::{Json, Query};
+use crate::idempotency::IDEMPOTENT_REPLAYED;
+use crate::logging::REQUEST_ID_HEADER;
+use crate::pagination;
+use crate::state::AppState;
+
+// 'prev' of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// A field changed by a request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Change {
+    // null when the resource is created
+    pub before: Value,
+    // null when the resource is deleted
+    pub after: Value,
+}
+
+// An entry of the log, as written in the file.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AuditEntry {
+    // number of the entry, from 1
+    pub seq: u64,
+    // seconds since the Unix epoch
+    pub time: u64,
+    pub request_id: Option<String>,
+    // 'user:<id>', 'api_key:<id>' or 'anonymous'
+    pub actor: String,
+    #[schema(example = "user.updated")]
+    pub action: String,
+    #[schema(example = "user:5")]
+    pub target: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub changes: BTreeMap<String, Change>,
+    // hash of the previous entry
+    pub prev: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    // hexadecimal SHA-256 of the entry without its hash
+    fn digest(&self) -> String {
+        let unsigned = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        // serializing strings, integers and JSON values cannot fail
+        let json = serde_json::to_vec(&unsigned).unwrap_or_default();
+        digest(&SHA256, &json)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+// What a route changed, added to its answer :
+//   (AuditChange::new("user.updated", format!("user:{id}")).before(&old).after(&new), Json(new))
+#[derive(Debug, Clone)]
+pub struct AuditChange {
+    action: String,
+    target: String,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+impl AuditChange {
+    pub fn new(action: &str, target: String) -> Self {
+        Self {
+            action: action.to_owned(),
+            target,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn before(mut self, resource: &impl Serialize) -> Self {
+        self.before = serde_json::to_value(resource).ok();
+        self
+    }
+
+    pub fn after(mut self, resource: &impl Serialize) -> Self {
+        self.after = serde_json::to_value(resource).ok();
+        self
+    }
+
+    // the fields that differ between the two states
+    fn changes(&self) -> BTreeMap<String, Change> {
+        let fields = |state: &Option<Value>| match state {
+            Some(Value::Object(fields)) => fields.clone(),
+            _ => serde_json::Map::new(),
+        };
+        let (before, after) = (fields(&self.before), fields(&self.after));
+        let mut changes = BTreeMap::new();
+        for name in before.keys().chain(after.keys()) {
+            let change = Change {
+                before: before.get(name).cloned().unwrap_or(Value::Null),
+                after: after.get(name).cloned().unwrap_or(Value::Null),
+            };
+            if change.before != change.after {
+                changes.insert(name.clone(), change);
+            }
+        }
+        changes
+    }
+}
+
+impl IntoResponseParts for AuditChange {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.extensions_mut().insert(self);
+        Ok(res)
+    }
+}
+
+// The end of the chain, where the next entry is appended.
+struct Head {
+    file: File,
+    seq: u64,
+    hash: String,
+    // position of each entry in the file, so a page is read from its first entry
+    offsets: Vec<u64>,
+    // size of the file
+    len: u64,
+}
+
+// The audit log of the server.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    head: Arc<Mutex<Head>>,
+}
+
+impl AuditLog {
+    // Opens the log, created when missing, and reads its last entry to continue the chain.
+    pub fn open(config: &AuditConfig) -> io::Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let (offsets, len, last) = index(&config.path)?;
+        let mut head = Head {
+            file,
+            seq: 0,
+            hash: String::from(GENESIS),
+            offsets,
+            len,
+        };
+        if let Some(line) = last {
+            let last: AuditEntry = serde_json::from_str(&line).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the last entry is invalid ({err}), check the log with verify-audit"),
+                )
+            })?;
+            head.seq = last.seq;
+            head.hash = last.hash;
+        }
+        Ok(Self {
+            path: config.path.clone(),
+            head: Arc::new(Mutex::new(head)),
+        })
+    }
+
+    // Chains the entry to the last one and writes it to the disk.
+    async fn append(&self, mut entry: AuditEntry) -> io::Result<()> {
+        let head = self.head.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut head = head.lock().unwrap_or_else(|err| err.into_inner());
+            entry.seq = head.seq + 1;
+            entry.prev = head.hash.clone();
+            entry.hash = entry.digest();
+            let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+            line.push(b'\n');
+            head.file.write_all(&line)?;
+            head.file.sync_data()?;
+            head.seq = entry.seq;
+            head.hash = entry.hash;
+            let offset = head.len;
+            head.offsets.push(offset);
+            head.len += line.len() as u64;
+            Ok(())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    // The first 'limit' entries matching the filter, after the entry 'after'. The entries are
+    // numbered from 1 in the order of the file : the reading starts at the entry 'after + 1'.
+    async fn query(
+        &self,
+        filter: AuditFilter,
+        after: u64,
+        limit: usize,
+    ) -> io::Result<Vec<AuditEntry>> {
+        let start = {
+            let head = self.head.lock().unwrap_or_else(|err| err.into_inner());
+            usize::try_from(after)
+                .ok()
+                .and_then(|after| head.offsets.get(after).copied())
+        };
+        let Some(start) = start else {
+            return Ok(Vec::new());
+        };
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut entries = Vec::new();
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(start))?;
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let entry: AuditEntry = serde_json::from_str(&line).map_err(io::Error::other)?;
+                if entry.seq > after && filter.matches(&entry) {
+                    entries.push(entry);
+                    if entries.len() == limit {
+                        break;
+                    }
+                }
+            }
+            Ok(entries)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+// The position of each entry of the file, its size, and its last line that is not empty.
+fn index(path: &Path) -> io::Result<(Vec<u64>, u64, Option<String>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut offsets, mut len, mut last) = (Vec::new(), 0, None);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            return Ok((offsets, len, last));
+        }
+        let entry = line.trim_end_matches('\n');
+        if !entry.is_empty() {
+            offsets.push(len);
+            last = Some(entry.to_owned());
+        }
+        len += read as u64;
+    }
+}
+
+// Checks the chain of the log, for the 'verify-audit' command. Returns the number of entries and
+// the last hash, or the first broken line.
+pub fn verify(path: &Path) -> Result<(u64, String), String> {
+    let file = File::open(path).map_err(|err| format!("cannot open {}: {err}", path.display()))?;
+    let mut seq = 0;
+    let mut hash = String::from(GENESIS);
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let number = number + 1;
+        let line = line.map_err(|err| format!("line {number}: cannot be read: {err}"))?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: AuditEntry = serde_json::from_str(&line)
+            .map_err(|err| format!("line {number}: not an audit entry: {err}"))?;
+        if entry.seq != seq + 1 {
+            return Err(format!(
+                "line {number}: entry {} follows entry {seq}, an entry was inserted or removed",
+                entry.seq
+            ));
+        }
+        if entry.prev != hash {
+            return Err(format!(
+                "line {number}: entry {} is not chained to the previous entry",
+                entry.seq
+            ));
+        }
+        if entry.digest() != entry.hash {
+            return Err(format!(
+                "line {number}: entry {} was changed after it was written",
+                entry.seq
+            ));
+        }
+        seq = entry.seq;
+        hash = entry.hash;
+    }
+    Ok((seq, hash))
+}
+
+// Middleware appending the requests that may change something to the log. It runs before the
+// layers that may refuse a request, so the refused requests are recorded too : the client of the
+// request is given back with the answer by 'authenticate'. A replayed answer, which changed
+// nothing, is not recorded again.
+pub async fn audit(State(log): State<Option<AuditLog>>, request: Request, next: Next) -> Response {
+    let changes = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    let (Some(log), true) = (log, changes) else {
+        return next.run(request).await;
+    };
+    // the requests matching no route change nothing
+    let Some(route) = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| route.as_str().to_owned())
+    else {
+        return next.run(request).await;
+    };
+    let method = request.method().to_string();
+    let path = request.uri().path().to_owned();
+    let request_id = request_id(request.headers());
+
+    let response = next.run(request).await;
+    if response.headers().contains_key(IDEMPOTENT_REPLAYED) {
+        return response;
+    }
+    let actor = match response.extensions().get::<Principal>() {
+        Some(Principal::ApiKey(key)) => format!("api_key:{}", key.id),
+        Some(Principal::User { user, .. }) => format!("user:{}", user.id),
+        None => String::from("anonymous"),
+    };
+    let change = response.extensions().get::<AuditChange>();
+    let entry = AuditEntry {
+        seq: 0,
+        time: now(),
+        request_id,
+        actor,
+        action: change.map_or_else(
+            || format!("{method} {route}"),
+            |change| change.action.clone(),
+        ),
+        target: change.map_or_else(|| path.clone(), |change| change.target.clone()),
+        method,
+        path,
+        status: response.status().as_u16(),
+        changes: change.map(AuditChange::changes).unwrap_or_default(),
+        prev: String::new(),
+        hash: String::new(),
+    };
+    // the change is already applied : the answer is sent anyway
+    if let Err(err) = log.append(entry).await {
+        tracing::error!("cannot write to the audit log: {err}");
+    }
+    response
+}
+
+fn request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+// Filters of 'GET /audit', each one optional.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct AuditFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    // entries at or after this time, in seconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<u64>,
+    // entries before this time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<u64>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let equals = |filter: &Option<String>, value: &str| {
+            filter.as_deref().is_none_or(|filter| filter == value)
+        };
+        equals(&self.actor, &entry.actor)
+            && equals(&self.action, &entry.action)
+            && equals(&self.target, &entry.target)
+            && self.since.is_none_or(|since| entry.time >= since)
+            && self.until.is_none_or(|until| entry.time < until)
+    }
+
+    // the parameters of the first page
+    fn to_params(&self) -> Vec<(String, String)> {
+        // serializing strings and integers cannot fail
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value.as_object().cloned())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => (name, value),
+                value => (name, value.to_string()),
+            })
+            .collect()
+    }
+}
+
+// Position of the next page : the last entry of the page, with the filters of the listing.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditCursor {
+    filter: AuditFilter,
+    seq: u64,
+}
+
+// Parameters of 'GET /audit'.
+struct ListAudit {
+    filter: AuditFilter,
+    after: u64,
+    limit: usize,
+}
+
+impl ListAudit {
+    // Every invalid parameter is reported at once, each one as an error of its field.
+    fn parse(params: Vec<(String, String)>) -> Result<Self, AppError> {
+        let mut errors = Vec::new();
+        let mut limit = pagination::DEFAULT_LIMIT;
+        let mut cursor = None;
+        let mut filter = AuditFilter::default();
+        let time = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| String::from("must be a number of seconds since the Unix epoch"))
+        };
+        for (name, value) in params {
+            let parsed = match name.as_str() {
+                "limit" => pagination::parse_limit(&value).map(|value| limit = value),
+                "cursor" => {
+                    cursor = Some(value);
+                    Ok(())
+                }
+                "actor" => {
+                    filter.actor = Some(value);
+                    Ok(())
+                }
+                "action" => {
+                    filter.action = Some(value);
+                    Ok(())
+                }
+                "target" => {
+                    filter.target = Some(value);
+                    Ok(())
+                }
+                "since" => time(&value).map(|value| filter.since = Some(value)),
+                "until" => time(&value).map(|value| filter.until = Some(value)),
+                _ => Err(String::from(
+                    "unknown parameter, expected limit, cursor, actor, action, target, since or until",
+                )),
+            };
+            if let Err(message) = parsed {
+                errors.push((name, message));
+            }
+        }
+        if !errors.is_empty() {
+            let error = AppError::new(ErrorCode::InvalidQuery)
+                .with_detail("the query string has invalid parameters");
+            return Err(errors.into_iter().fold(error, |error, (name, message)| {
+                error.with_field(name, message)
+            }));
+        }
+
+        // the filters may be repeated with the cursor, but not changed
+        let Some(cursor) = cursor else {
+            return Ok(Self {
+                filter,
+                after: 0,
+                limit,
+            });
+        };
+        let cursor: AuditCursor = pagination::decode_cursor(&cursor)?;
+        if filter != AuditFilter::default() && cursor.filter != filter {
+            return Err(AppError::new(ErrorCode::InvalidQuery)
+                .with_field("cursor", "the cursor was given for other filters"));
+        }
+        Ok(Self {
+            filter: cursor.filter,
+            after: cursor.seq,
+            limit,
+        })
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/audit", get(list_audit).require(Scope::AuditRead))
+}
+
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "audit",
+    security(("bearer" = ["audit:read"])),
+    params(
+        ("limit" = Option<usize>, Query, description = "Entries of the page, 50 by default, 200 at most"),
+        ("cursor" = Option<String>, Query, description = "Position of the page, from the 'Link' header of the previous page"),
+        ("actor" = Option<String>, Query, description = "Only the entries of this client, such as 'user:1' or 'api_key:2'"),
+        ("action" = Option<String>, Query, description = "Only the entries of this action, such as 'user.updated'"),
+        ("target" = Option<String>, Query, description = "Only the entries of this target, such as 'user:5'"),
+        ("since" = Option<u64>, Query, description = "Only the entries at or after this time, in seconds since the Unix epoch"),
+        ("until" = Option<u64>, Query, description = "Only the entries before this time"),
+    ),
+    responses(
+        (status = 200, description = "A page of entries, oldest first, the next and first pages are in the 'Link' header", body = [AuditEntry]),
+        (status = 400, description = "Invalid query parameter", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The audit log is disabled", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn list_audit(
+    State(state): State<AppState>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(log) = state.audit else {
+        return Err(AppError::not_found("the audit log is disabled"));
+    };
+    let ListAudit {
+        filter,
+        after,
+        limit,
+    } = ListAudit::parse(params)?;
+    // one more entry tells whether there is a next page
+    let mut entries = log
+        .query(filter.clone(), after, limit + 1)
+        .await
+        .map_err(AppError::internal)?;
+    let next = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|last| {
+            pagination::encode_cursor(&AuditCursor {
+                filter: filter.clone(),
+                seq: last.seq,
+            })
+        })
+    } else {
+        None
+    };
+    let links = pagination::links("/audit", limit, filter.to_params(), next);
+    Ok((links, Json(entries)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(target: &str) -> AuditEntry {
+        AuditEntry {
+            seq: 0,
+            time: now(),
+            request_id: None,
+            actor: String::from("api_key:1"),
+            action: String::from("user.updated"),
+            target: target.to_owned(),
+            method: String::from("PATCH"),
+            path: String::new(),
+            status: 200,
+            changes: BTreeMap::new(),
+            prev: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    // A log of 3 entries in a temporary directory, and its lines.
+    async fn log(dir: &Path) -> (AuditLog, Vec<String>) {
+        let config = AuditConfig {
+            enabled: true,
+            path: dir.join("audit.log"),
+        };
+        let log = AuditLog::open(&config).unwrap();
+        for target in ["user:1", "user:2", "user:3"] {
+            log.append(entry(target)).await.unwrap();
+        }
+        let lines = fs::read_to_string(&config.path).unwrap();
+        (log, lines.lines().map(str::to_owned).collect())
+    }
+
+    #[tokio::test]
+    async fn verify_finds_an_edited_an_inserted_or_a_removed_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log, lines) = log(dir.path()).await;
+        let (count, _) = verify(&log.path).unwrap();
+        assert_eq!(count, 3);
+        let check = |lines: Vec<String>| {
+            fs::write(&log.path, lines.join("\n")).unwrap();
+            verify(&log.path).unwrap_err()
+        };
+
+        let mut edited = lines.clone();
+        edited[1] = edited[1].replace("user:2", "user:9");
+        assert_eq!(
+            check(edited),
+            "line 2: entry 2 was changed after it was written"
+        );
+        let mut inserted = lines.clone();
+        inserted.insert(1, lines[0].clone());
+        assert_eq!(
+            check(inserted),
+            "line 2: entry 1 follows entry 1, an entry was inserted or removed"
+        );
+        let mut removed = lines.clone();
+        removed.remove(1);
+        assert_eq!(
+            check(removed),
+            "line 2: entry 3 follows entry 1, an entry was inserted or removed"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_a_page_from_the_entry_after_the_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log, _) = log(dir.path()).await;
+        // the log is opened again, the positions of the entries are read from the file
+        let log = AuditLog::open(&AuditConfig {
+            enabled: true,
+            path: log.path.clone(),
+        })
+        .unwrap();
+        log.append(entry("user:4")).await.unwrap();
+
+        let page = log.query(AuditFilter::default(), 1, 2).await.unwrap();
+        let seqs: Vec<u64> = page.iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [2, 3]);
+        let page = log.query(AuditFilter::default(), 3, 2).await.unwrap();
+        assert_eq!(page[0].target, "user:4");
+        assert!(log
+            .query(AuditFilter::default(), 4, 2)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
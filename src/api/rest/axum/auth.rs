@@ -0,0 +1,994 @@
+// AUTHENTICATION
+//   POST /auth/login    signs a user in, returns an access token and a refresh token
+//   POST /auth/refresh  exchanges a refresh token for a new pair of tokens
+//   POST /auth/logout   revokes the session of the access token
+//   GET  /auth/me       the user of the access token
+// Both tokens are JWT signed with 'auth.secret' (HS256). The access token is short-lived and sent
+// with each request in the 'Authorization: Bearer <token>' header, the refresh token lives longer
+// and is only sent to '/auth/refresh'.
+// Each login opens a session, stored in the database. A refresh token can only be used once : it
+// is replaced by a new one at each refresh (rotation). An old refresh token used again was likely
+// stolen, so the whole session is revoked. The access tokens of a revoked session, or of a user
+// who is no longer active, are rejected at once.
+// Users sign in with their username and password (see 'passwords.rs').
+// AUTHORIZATION
+// The 'authenticate' middleware identifies the client of each request from its 'Authorization'
+// header : a user with an access token, or a service with an API key (see 'api_keys.rs'). Without
+// it, a browser is identified by its session cookie (see 'cookies.rs'). Routes declare the scope
+// they need with 'require' :
+//   get(list_users).require(Scope::UsersRead)
+// A user signed in has the scopes of its roles (see 'roles.rs'), an API key only the scopes it was
+// created with.
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, Extensions, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, MethodRouter},
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use rusqlite::{params, OptionalExtension, Transaction, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::api_keys::{ApiKey, KEY_PREFIX};
+use crate::config::AuthConfig;
+use crate::cookies;
+use crate::db::Db;
+use crate::error::{AppError, Problem};
+use crate::events::{UserEventKind, UserEvents};
+use crate::extract::Json;
+use crate::passwords::{Password, Passwords, Verified};
+use crate::roles::user_scopes;
+use crate::state::AppState;
+use crate::users::{select_user, user_from_row, User, MAX_SIGN_IN_COUNT, USER_COLUMNS};
+
+// 'iss' claim of the tokens
+const ISSUER: &str = "rest-api-axum";
+
+// Body of 'POST /auth/login'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Login {
+    pub username: String,
+    #[schema(value_type = String, format = Password)]
+    pub password: Password,
+}
+
+// Body of 'POST /auth/refresh'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Refresh {
+    pub refresh_token: String,
+}
+
+// Answer of '/auth/login' and '/auth/refresh' (RFC 6749).
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Tokens {
+    pub access_token: String,
+    #[schema(example = "Bearer")]
+    pub token_type: &'static str,
+    // lifetime of the access token, in seconds
+    pub expires_in: u64,
+    pub refresh_token: String,
+    // lifetime of the refresh token, in seconds
+    pub refresh_expires_in: u64,
+}
+
+// What a client is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Scope {
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    #[serde(rename = "keys:read")]
+    KeysRead,
+    #[serde(rename = "keys:write")]
+    KeysWrite,
+    #[serde(rename = "logs:read")]
+    LogsRead,
+    #[serde(rename = "logs:write")]
+    LogsWrite,
+    #[serde(rename = "roles:read")]
+    RolesRead,
+    #[serde(rename = "roles:write")]
+    RolesWrite,
+    #[serde(rename = "audit:read")]
+    AuditRead,
+    #[serde(rename = "messages:write")]
+    MessagesWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 10] = [
+        Scope::UsersRead,
+        Scope::UsersWrite,
+        Scope::KeysRead,
+        Scope::KeysWrite,
+        Scope::LogsRead,
+        Scope::LogsWrite,
+        Scope::RolesRead,
+        Scope::RolesWrite,
+        Scope::AuditRead,
+        Scope::MessagesWrite,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+            Scope::KeysRead => "keys:read",
+            Scope::KeysWrite => "keys:write",
+            Scope::LogsRead => "logs:read",
+            Scope::LogsWrite => "logs:write",
+            Scope::RolesRead => "roles:read",
+            Scope::RolesWrite => "roles:write",
+            Scope::AuditRead => "audit:read",
+            Scope::MessagesWrite => "messages:write",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == value)
+            .ok_or_else(|| {
+                let all: Vec<&str> = Scope::ALL.iter().map(|scope| scope.as_str()).collect();
+                format!("'{value}' is not one of {}", all.join(", "))
+            })
+    }
+}
+
+// Scopes as stored in the database : separated by spaces.
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+    scopes.join(" ")
+}
+
+// a scope unknown to this version of the server is ignored
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes
+        .split_whitespace()
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TokenType {
+    Access,
+    Refresh,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    // identifier of the user
+    sub: String,
+    // identifier of the session
+    sid: String,
+    // identifier of the token itself
+    jti: String,
+    typ: TokenType,
+    iss: String,
+    iat: u64,
+    exp: u64,
+}
+
+// Issues and checks the tokens.
+#[derive(Clone)]
+pub struct Auth {
+    keys: Arc<Keys>,
+    access_ttl: Duration,
+    refresh_ttl: Duration,
+    db: Db,
+}
+
+struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    // signs the cookies, see 'cookies.rs'
+    cookies: hmac::Key,
+}
+
+// A new session, or a session after a refresh.
+struct Issued {
+    tokens: Tokens,
+    refresh_jti: String,
+    expires_at: u64,
+}
+
+impl Auth {
+    // Without a configured secret, a random one is generated : see 'AuthConfig::secret'.
+    pub fn new(config: &AuthConfig, db: Db) -> Self {
+        let secret = match config.secret.as_str() {
+            "" => random_bytes::<32>().to_vec(),
+            secret => secret.as_bytes().to_vec(),
+        };
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[ISSUER]);
+        validation.set_required_spec_claims(&["exp", "sub", "iss"]);
+        // the tokens are issued and checked by the same clock
+        validation.leeway = 0;
+        Self {
+            keys: Arc::new(Keys {
+                encoding: EncodingKey::from_secret(&secret),
+                decoding: DecodingKey::from_secret(&secret),
+                validation,
+                cookies: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            }),
+            access_ttl: Duration::from_secs(config.access_ttl_secs),
+            refresh_ttl: Duration::from_secs(config.refresh_ttl_secs),
+            db,
+        }
+    }
+
+    fn token(&self, user: u64, session: &str, typ: TokenType, ttl: Duration) -> (String, Claims) {
+        let iat = now();
+        let claims = Claims {
+            sub: user.to_string(),
+            sid: session.to_string(),
+            jti: random_id(),
+            typ,
+            iss: ISSUER.to_string(),
+            iat,
+            exp: iat + ttl.as_secs(),
+        };
+        // signing with HMAC cannot fail
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &self.keys.encoding)
+            .unwrap_or_default();
+        (token, claims)
+    }
+
+    fn issue(&self, user: u64, session: &str) -> Issued {
+        let (access_token, _) = self.token(user, session, TokenType::Access, self.access_ttl);
+        let (refresh_token, refresh) =
+            self.token(user, session, TokenType::Refresh, self.refresh_ttl);
+        Issued {
+            tokens: Tokens {
+                access_token,
+                token_type: "Bearer",
+                expires_in: self.access_ttl.as_secs(),
+                refresh_token,
+                refresh_expires_in: self.refresh_ttl.as_secs(),
+            },
+            refresh_jti: refresh.jti,
+            expires_at: refresh.exp,
+        }
+    }
+
+    // Checks the signature, the expiry and the type of the token.
+    fn decode(&self, token: &str, typ: TokenType) -> Result<Claims, AppError> {
+        let claims =
+            jsonwebtoken::decode::<Claims>(token, &self.keys.decoding, &self.keys.validation)
+                .map_err(|err| match err.kind() {
+                    ErrorKind::ExpiredSignature => AppError::unauthorized("the token has expired"),
+                    _ => AppError::unauthorized(format!("invalid token: {err}")),
+                })?
+                .claims;
+        if claims.typ != typ {
+            return Err(AppError::unauthorized(match typ {
+                TokenType::Access => "not an access token",
+                TokenType::Refresh => "not a refresh token",
+            }));
+        }
+        Ok(claims)
+    }
+
+    // HMAC-SHA256 of the value with the secret, in base64url.
+    pub fn sign(&self, value: &str) -> String {
+        URL_SAFE_NO_PAD.encode(hmac::sign(&self.keys.cookies, value.as_bytes()))
+    }
+
+    // Whether the signature was made by 'sign' for this value, compared in constant time.
+    pub fn verify_signature(&self, value: &str, signature: &str) -> bool {
+        URL_SAFE_NO_PAD
+            .decode(signature)
+            .is_ok_and(|tag| hmac::verify(&self.keys.cookies, value.as_bytes(), &tag).is_ok())
+    }
+
+    // Opens a session with tokens for the user with this username and password.
+    async fn login(
+        &self,
+        body: Login,
+        passwords: &Passwords,
+        events: &UserEvents,
+    ) -> Result<Tokens, AppError> {
+        let auth = self.clone();
+        self.sign_in(body, passwords, events, move |tx, user| {
+            let session = random_id();
+            let issued = auth.issue(user.id, &session);
+            tx.execute(
+                "INSERT INTO sessions (id, user_id, refresh_jti, expires_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![session, user.id, issued.refresh_jti, issued.expires_at],
+            )?;
+            Ok(issued.tokens)
+        })
+        .await
+    }
+
+    // Checks the username and password, then counts the sign in and opens a session with 'open',
+    // in the same transaction. The password is checked before the transaction, so a slow hash
+    // does not hold the database. The new count is published as a 'user.updated' event.
+    pub async fn sign_in<T, F>(
+        &self,
+        body: Login,
+        passwords: &Passwords,
+        events: &UserEvents,
+        open: F,
+    ) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction<'_>, &User) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let username = body.username;
+        let found: Option<(u64, Option<String>)> = self
+            .db
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT id, password_hash FROM users WHERE username = ?1 COLLATE NOCASE
+                         ORDER BY id LIMIT 1",
+                        [username],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        let (id, hash) = found.unzip();
+        let verified = passwords.verify(body.password, hash.flatten()).await?;
+        let (Some(id), Verified::Right { rehash }) = (id, verified) else {
+            return Err(AppError::unauthorized("unknown username or wrong password"));
+        };
+
+        let (opened, user) = self
+            .db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                // the expired sessions are of no use anymore
+                tx.execute("DELETE FROM sessions WHERE expires_at < ?1", [now()])?;
+                let Some(mut user) = select_user(&tx, id)? else {
+                    return Ok(Err(AppError::unauthorized(
+                        "unknown username or wrong password",
+                    )));
+                };
+                if !user.active {
+                    return Ok(Err(inactive(&user)));
+                }
+                // the count stops at the largest value SQLite can store
+                user.sign_in_count = (user.sign_in_count + 1).min(MAX_SIGN_IN_COUNT);
+                user.version += 1;
+                tx.execute(
+                    "UPDATE users SET sign_in_count = ?2, version = version + 1 WHERE id = ?1",
+                    params![user.id, user.sign_in_count],
+                )?;
+                // the password was hashed with another cost than the configuration
+                if let Some(rehash) = rehash {
+                    tx.execute(
+                        "UPDATE users SET password_hash = ?2 WHERE id = ?1",
+                        params![user.id, rehash],
+                    )?;
+                }
+                let opened = open(&tx, &user)?;
+                tx.commit()?;
+                tracing::info!(user = user.id, "user signed in");
+                Ok(Ok((opened, user)))
+            })
+            .await??;
+        events.publish(UserEventKind::Updated, &user);
+        Ok(opened)
+    }
+
+    // Replaces the refresh token of the session by a new one.
+    async fn refresh(&self, claims: Claims) -> Result<Tokens, AppError> {
+        let auth = self.clone();
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let session: Option<(u64, String, bool)> = tx
+                    .query_row(
+                        "SELECT user_id, refresh_jti, revoked FROM sessions WHERE id = ?1",
+                        [&claims.sid],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .optional()?;
+                let Some((user_id, jti, false)) = session else {
+                    return Ok(Err(AppError::unauthorized("the session is revoked")));
+                };
+                if jti != claims.jti {
+                    tx.execute(
+                        "UPDATE sessions SET revoked = 1 WHERE id = ?1",
+                        [&claims.sid],
+                    )?;
+                    tx.commit()?;
+                    tracing::warn!(user = user_id, "refresh token reused, session revoked");
+                    return Ok(Err(AppError::unauthorized(
+                        "the refresh token was already used, the session is revoked",
+                    )));
+                }
+                match select_user(&tx, user_id)? {
+                    Some(user) if user.active => {}
+                    Some(user) => return Ok(Err(inactive(&user))),
+                    None => return Ok(Err(AppError::unauthorized("the user does not exist"))),
+                }
+                let issued = auth.issue(user_id, &claims.sid);
+                tx.execute(
+                    "UPDATE sessions SET refresh_jti = ?2, expires_at = ?3 WHERE id = ?1",
+                    params![claims.sid, issued.refresh_jti, issued.expires_at],
+                )?;
+                tx.commit()?;
+                Ok(Ok(issued.tokens))
+            })
+            .await?
+    }
+
+    async fn logout(&self, session: String) -> Result<(), AppError> {
+        self.db
+            .call(move |conn| {
+                conn.execute("UPDATE sessions SET revoked = 1 WHERE id = ?1", [session])?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    // The user of an access token with the scopes of its roles, as long as its session is open and
+    // the user is active.
+    async fn authenticate(&self, claims: Claims) -> Result<Principal, AppError> {
+        let id: u64 = claims
+            .sub
+            .parse()
+            .map_err(|_| AppError::unauthorized("invalid token: the subject is not a user"))?;
+        let session = claims.sid;
+        let sid = session.clone();
+        let user = self
+            .db
+            .call(move |conn| {
+                let user = conn
+                    .query_row(
+                        &format!(
+                            "SELECT {USER_COLUMNS} FROM users WHERE id = ?2 AND EXISTS (
+                                SELECT 1 FROM sessions
+                                WHERE id = ?1 AND user_id = users.id AND revoked = 0
+                             )"
+                        ),
+                        params![sid, id],
+                        user_from_row,
+                    )
+                    .optional()?;
+                match user {
+                    Some(user) => Ok(Some((user_scopes(conn, id)?, user))),
+                    None => Ok(None),
+                }
+            })
+            .await?;
+        match user {
+            Some((scopes, user)) if user.active => Ok(Principal::User {
+                user,
+                session,
+                scopes,
+            }),
+            Some((_, user)) => Err(inactive(&user)),
+            None => Err(AppError::unauthorized("the session is revoked")),
+        }
+    }
+}
+
+pub fn inactive(user: &User) -> AppError {
+    AppError::forbidden(format!("user {} is not active", user.id))
+}
+
+// seconds since the Unix epoch, as in the 'exp' and 'iat' claims
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    // the random generator of the system only fails on unsupported platforms
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("random generator of the system");
+    bytes
+}
+
+// random identifier of a session or a token
+pub fn random_id() -> String {
+    URL_SAFE_NO_PAD.encode(random_bytes::<16>())
+}
+
+// The client of a request.
+#[derive(Debug, Clone)]
+pub enum Principal {
+    // a user signed in with '/auth/login', with the scopes of its roles
+    User {
+        user: User,
+        session: String,
+        scopes: Vec<Scope>,
+    },
+    ApiKey(ApiKey),
+}
+
+impl Principal {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match self {
+            Principal::User { scopes, .. } => scopes.contains(&scope),
+            Principal::ApiKey(key) => key.scopes.contains(&scope),
+        }
+    }
+}
+
+// Middleware adding the 'Principal' of the request to its extensions. A request without an
+// 'Authorization' header nor a session cookie goes on anonymously. So does a request with invalid
+// credentials, e.g. an expired access token sent to '/auth/login' or '/auth/refresh' : only the
+// routes requiring a client answer with the error of its credentials.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let principal = match principal(&state, request.method(), request.headers()).await {
+        Ok(principal) => principal,
+        Err(err) => match err.try_clone() {
+            Some(err) => {
+                request.extensions_mut().insert(Rejected(Arc::new(err)));
+                None
+            }
+            // the database failed, the credentials may well be valid
+            None => return err.into_response(),
+        },
+    };
+    if let Some(principal) = &principal {
+        request.extensions_mut().insert(principal.clone());
+    }
+    let mut response = next.run(request).await;
+    // the layers called before, such as the audit, learn the client with the answer
+    if let Some(principal) = principal {
+        response.extensions_mut().insert(principal);
+    }
+    response
+}
+
+// The error of the credentials refused by 'authenticate'.
+#[derive(Clone)]
+struct Rejected(Arc<AppError>);
+
+// The answer to a request without a client, on a route requiring one.
+fn unauthenticated(extensions: &Extensions) -> AppError {
+    extensions
+        .get::<Rejected>()
+        .and_then(|rejected| rejected.0.try_clone())
+        .unwrap_or_else(|| {
+            AppError::unauthorized("send a token or an API key in the 'Authorization' header")
+        })
+}
+
+async fn principal(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Option<Principal>, AppError> {
+    // the browsers send a session cookie instead
+    let Some(token) = bearer(headers)? else {
+        return cookies::principal(state, method, headers).await;
+    };
+    if token.starts_with(KEY_PREFIX) {
+        return match state.api_keys.authenticate(token.to_string()).await? {
+            Some(key) => Ok(Some(Principal::ApiKey(key))),
+            None => Err(AppError::unauthorized("unknown or revoked API key")),
+        };
+    }
+    let claims = state.auth.decode(token, TokenType::Access)?;
+    Ok(Some(state.auth.authenticate(claims).await?))
+}
+
+// reads 'Authorization: Bearer <token>', 'None' without the header
+fn bearer(headers: &HeaderMap) -> Result<Option<&str>, AppError> {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| Some(token.trim()))
+        .ok_or_else(|| AppError::unauthorized("expected 'Authorization: Bearer <token>'"))
+}
+
+// Adding it to the arguments of a handler requires a client : the request is answered with
+// '401 Unauthorized' without credentials, or with the error of its invalid credentials.
+impl<S: Send + Sync> FromRequestParts<S> for Principal {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, AppError> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or_else(|| unauthenticated(&parts.extensions))
+    }
+}
+
+// The signed in user of a request, for the routes only available to users, not to API keys.
+pub struct AuthUser {
+    pub user: User,
+    pub session: String,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, AppError> {
+        match Principal::from_request_parts(parts, state).await? {
+            Principal::User { user, session, .. } => Ok(AuthUser { user, session }),
+            Principal::ApiKey(_) => Err(AppError::forbidden("only available to signed in users")),
+        }
+    }
+}
+
+// Restricts the routes of a method router to the clients having a scope.
+pub trait RequireScope {
+    fn require(self, scope: Scope) -> Self;
+}
+
+impl<S: Clone + Send + Sync + 'static> RequireScope for MethodRouter<S> {
+    fn require(self, scope: Scope) -> Self {
+        self.route_layer(middleware::from_fn_with_state(scope, check_scope))
+    }
+}
+
+async fn check_scope(State(scope): State<Scope>, request: Request, next: Next) -> Response {
+    match request.extensions().get::<Principal>() {
+        Some(principal) if principal.has_scope(scope) => next.run(request).await,
+        Some(_) => AppError::forbidden(format!("the scope '{scope}' is required")).into_response(),
+        None => unauthenticated(request.extensions()).into_response(),
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/login", post(login))
+        .route("/auth/refresh", post(refresh))
+        .route("/auth/logout", post(logout))
+        .route("/auth/me", get(me))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/login",
+    tag = "auth",
+    request_body = Login,
+    responses(
+        (status = 200, description = "The user is signed in", body = Tokens),
+        (status = 401, description = "Unknown username or wrong password", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The user is not active", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn login(
+    State(state): State<AppState>,
+    Json(body): Json<Login>,
+) -> Result<Json<Tokens>, AppError> {
+    Ok(Json(
+        state
+            .auth
+            .login(body, &state.passwords, &state.events)
+            .await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/refresh",
+    tag = "auth",
+    request_body = Refresh,
+    responses(
+        (status = 200, description = "New tokens, the refresh token given is no longer valid", body = Tokens),
+        (status = 401, description = "Invalid, expired or already used refresh token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The user is not active", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn refresh(
+    State(state): State<AppState>,
+    Json(body): Json<Refresh>,
+) -> Result<Json<Tokens>, AppError> {
+    let claims = state.auth.decode(&body.refresh_token, TokenType::Refresh)?;
+    Ok(Json(state.auth.refresh(claims).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/logout",
+    tag = "auth",
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "The session is revoked"),
+        (status = 401, description = "Invalid access token", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn logout(State(state): State<AppState>, auth: AuthUser) -> Result<StatusCode, AppError> {
+    state.auth.logout(auth.session).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/me",
+    tag = "auth",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The signed in user", body = User),
+        (status = 401, description = "Invalid access token", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn me(auth: AuthUser) -> Json<User> {
+    Json(auth.user)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::users::CreateUser;
+    use crate::validation::Violations;
+
+    // A user with a session opened at login, and its tokens.
+    async fn signed_in(state: &AppState) -> (User, Tokens) {
+        let body = CreateUser {
+            username: String::from("alice"),
+            email: String::from("alice@example.com"),
+            active: None,
+            sign_in_count: None,
+            password: None,
+        };
+        let user = state
+            .users
+            .create(body, None, Violations::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let issued = state.auth.issue(user.id, "session");
+        let (id, jti, expires_at) = (user.id, issued.refresh_jti, issued.expires_at);
+        state
+            .db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO sessions (id, user_id, refresh_jti, expires_at)
+                     VALUES ('session', ?1, ?2, ?3)",
+                    params![id, jti, expires_at],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        (user, issued.tokens)
+    }
+
+    // A config with the cheapest cost of Argon2id, so the tests hash fast.
+    fn cheap() -> Config {
+        let mut config = Config::default();
+        config.passwords.memory_kib = 8;
+        config.passwords.iterations = 1;
+        config
+    }
+
+    fn password(password: &str) -> Password {
+        serde_json::from_value(serde_json::json!(password)).unwrap()
+    }
+
+    // A user named 'sam' with the password 'correct horse', hashed by 'passwords'.
+    async fn with_password(state: &AppState, passwords: &Passwords, active: bool) -> User {
+        let body = CreateUser {
+            username: String::from("sam"),
+            email: String::from("sam@example.com"),
+            active: Some(active),
+            sign_in_count: None,
+            password: None,
+        };
+        let hash = passwords.hash(password("correct horse")).await.unwrap();
+        let user = state.users.create(body, Some(hash), Violations::default());
+        user.await.unwrap().unwrap()
+    }
+
+    async fn login_as(state: &AppState, username: &str, password: &str) -> Response {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/auth/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({ "username": username, "password": password }).to_string(),
+            ))
+            .unwrap();
+        let app = routes().with_state(state.clone());
+        app.oneshot(request).await.unwrap()
+    }
+
+    async fn password_hash(state: &AppState, user: u64) -> String {
+        let hash = state.db.call(move |conn| {
+            Ok(conn.query_row(
+                "SELECT password_hash FROM users WHERE id = ?1",
+                [user],
+                |row| row.get(0),
+            )?)
+        });
+        hash.await.unwrap()
+    }
+
+    fn expired(auth: &Auth, user: u64) -> String {
+        let (_, mut claims) = auth.token(user, "session", TokenType::Access, Duration::ZERO);
+        claims.exp -= 1;
+        jsonwebtoken::encode(&Header::default(), &claims, &auth.keys.encoding).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_an_expired_access_token() {
+        let state = AppState::test(&Config::default());
+        let (user, tokens) = signed_in(&state).await;
+
+        let claims = state
+            .auth
+            .decode(&tokens.access_token, TokenType::Access)
+            .unwrap();
+        assert!(state.auth.authenticate(claims).await.is_ok());
+        let err = state
+            .auth
+            .decode(&expired(&state.auth, user.id), TokenType::Access)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unauthorized: the token has expired");
+        // a refresh token is not an access token
+        assert!(state
+            .auth
+            .decode(&tokens.refresh_token, TokenType::Access)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn rotates_the_refresh_token_and_revokes_the_session_when_an_old_one_is_reused() {
+        let state = AppState::test(&Config::default());
+        let (_, tokens) = signed_in(&state).await;
+        let refresh = |token: &str| {
+            let auth = state.auth.clone();
+            let claims = auth.decode(token, TokenType::Refresh).unwrap();
+            async move { auth.refresh(claims).await }
+        };
+
+        let renewed = refresh(&tokens.refresh_token).await.unwrap();
+        assert_ne!(renewed.refresh_token, tokens.refresh_token);
+        let err = refresh(&tokens.refresh_token).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unauthorized: the refresh token was already used, the session is revoked"
+        );
+        // the thief and the user are both signed out
+        let err = refresh(&renewed.refresh_token).await.unwrap_err();
+        assert_eq!(err.to_string(), "unauthorized: the session is revoked");
+        let claims = state
+            .auth
+            .decode(&renewed.access_token, TokenType::Access)
+            .unwrap();
+        assert!(state.auth.authenticate(claims).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ignores_invalid_credentials_on_the_routes_not_requiring_a_client() {
+        let state = AppState::test(&Config::default());
+        let (user, _) = signed_in(&state).await;
+        let app = Router::new()
+            .route("/auth/refresh", post(|| async { StatusCode::NO_CONTENT }))
+            .route(
+                "/users",
+                get(|| async { StatusCode::NO_CONTENT }).require(Scope::UsersRead),
+            )
+            .route("/auth/me", get(me))
+            .layer(middleware::from_fn_with_state(state.clone(), authenticate))
+            .with_state(state.clone());
+        let send = |method: Method, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(
+                    header::AUTHORIZATION,
+                    format!("Bearer {}", expired(&state.auth, user.id)),
+                )
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = send(Method::POST, "/auth/refresh").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        for uri in ["/users", "/auth/me"] {
+            let response = send(Method::GET, uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert!(String::from_utf8_lossy(&body).contains("the token has expired"));
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_the_sign_in_and_publishes_the_user() {
+        let state = AppState::test(&cheap());
+        let user = with_password(&state, &state.passwords, true).await;
+
+        // the username is not case sensitive
+        let response = login_as(&state, "SAM", "correct horse").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let signed_in = state.users.get(user.id).await.unwrap().unwrap();
+        assert_eq!(signed_in.sign_in_count, user.sign_in_count + 1);
+        assert_eq!(signed_in.version, user.version + 1);
+        let (kind, data) = state.events.kept().pop().unwrap();
+        assert_eq!(kind, UserEventKind::Updated);
+        assert_eq!(data["sign_in_count"], user.sign_in_count + 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_wrong_password_without_counting_it() {
+        let state = AppState::test(&cheap());
+        let user = with_password(&state, &state.passwords, true).await;
+
+        for (username, password) in [("sam", "wrong horse"), ("frodo", "correct horse")] {
+            let response = login_as(&state, username, password).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let refused = state.users.get(user.id).await.unwrap().unwrap();
+        assert_eq!(refused.sign_in_count, user.sign_in_count);
+        assert_eq!(refused.version, user.version);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_inactive_user() {
+        let state = AppState::test(&cheap());
+        with_password(&state, &state.passwords, false).await;
+
+        let response = login_as(&state, "sam", "correct horse").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn rehashes_the_password_hashed_with_another_cost() {
+        let state = AppState::test(&cheap());
+        let mut config = cheap();
+        config.passwords.iterations = 2;
+        let previous = Passwords::new(&config.passwords, state.db.clone());
+        let user = with_password(&state, &previous, true).await;
+        assert!(password_hash(&state, user.id).await.contains("t=2"));
+
+        let response = login_as(&state, "sam", "correct horse").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(password_hash(&state, user.id).await.contains("t=1"));
+        // the new hash is checked like the old one
+        let response = login_as(&state, "sam", "correct horse").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,237 @@
+// COMPRESSION
+// Answers are compressed with gzip, brotli or zstd when the client accepts it ('Accept-Encoding')
+// and they are worth it : large enough ('compression.min_size_bytes') and of a content type that
+// compresses well ('compression.content_types'), so images or event streams are sent as they are.
+// Request bodies sent with 'Content-Encoding: gzip' (or 'br', 'zstd') are decompressed before
+// reaching the handlers. The size limits of 'limits' apply to the decompressed body, and an
+// unsupported encoding is refused with '415 Unsupported Media Type'.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Version},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tower_http::{
+    compression::{
+        predicate::{Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    decompression::RequestDecompressionLayer,
+    CompressionLevel,
+};
+
+use crate::config::{self, CompressionConfig, Encoding};
+use crate::error::{AppError, ErrorCode};
+
+// The compression of the answers. Without any encoding enabled, nothing is compressed.
+pub fn compression(config: &CompressionConfig) -> CompressionLayer<impl Predicate> {
+    let enabled = |encoding| config.enabled && config.encodings.contains(&encoding);
+    let content_types: Arc<[String]> = config.content_types.clone().into();
+    let compressible = move |_: StatusCode, _: Version, headers: &HeaderMap, _: &_| {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        // an event stream would be held by the encoder until its buffer is full
+        !content_type.starts_with("text/event-stream")
+            && content_types
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix.as_str()))
+    };
+    CompressionLayer::new()
+        .gzip(enabled(Encoding::Gzip))
+        .br(enabled(Encoding::Br))
+        .zstd(enabled(Encoding::Zstd))
+        .quality(match config.level {
+            config::CompressionLevel::Fastest => CompressionLevel::Fastest,
+            config::CompressionLevel::Default => CompressionLevel::Default,
+            config::CompressionLevel::Best => CompressionLevel::Best,
+        })
+        .compress_when(SizeAbove::new(config.min_size_bytes).and(compressible))
+}
+
+// The decompression of the request bodies, after 'check_encoding'.
+pub fn decompression(config: &CompressionConfig) -> RequestDecompressionLayer {
+    let accepted = |encoding| accepted(config).contains(&encoding);
+    RequestDecompressionLayer::new()
+        .gzip(accepted(Encoding::Gzip))
+        .br(accepted(Encoding::Br))
+        .zstd(accepted(Encoding::Zstd))
+}
+
+// the encodings accepted for the request bodies
+pub fn accepted(config: &CompressionConfig) -> Arc<[Encoding]> {
+    if config.decompress_requests {
+        config.encodings.clone().into()
+    } else {
+        Arc::new([])
+    }
+}
+
+// Middleware refusing the request bodies in an encoding that is not accepted, with a problem
+// document and the accepted encodings in 'Accept-Encoding' (RFC 7694).
+pub async fn check_encoding(
+    State(accepted): State<Arc<[Encoding]>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(encoding) = request.headers().get(header::CONTENT_ENCODING) else {
+        return next.run(request).await;
+    };
+    let supported = encoding == "identity"
+        || accepted
+            .iter()
+            .any(|accepted| encoding == accepted.as_str());
+    if supported {
+        return next.run(request).await;
+    }
+    let mut names = vec!["identity"];
+    names.extend(accepted.iter().map(|encoding| encoding.as_str()));
+    let names = names.join(", ");
+    let mut response = AppError::new(ErrorCode::UnsupportedMediaType)
+        .with_detail(format!(
+            "the content encoding {encoding:?} is not supported, use one of {names}"
+        ))
+        .into_response();
+    if let Ok(value) = HeaderValue::from_str(&names) {
+        response
+            .headers_mut()
+            .insert(header::ACCEPT_ENCODING, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use axum::{
+        body::{to_bytes, Body},
+        middleware,
+        routing::{get, post},
+        Router,
+    };
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::extract::{Json, Path};
+    use crate::security::{self, BodyLimits};
+
+    // The layers of the router around an echo and two answers of 'size' bytes, in the order of
+    // 'router', with bodies limited to 64 bytes.
+    fn app() -> Router {
+        let config = CompressionConfig::default();
+        let limits = LimitsConfig {
+            max_body_bytes: 64,
+            ..LimitsConfig::default()
+        };
+        let answer = |content_type: &'static str| {
+            move |Path(size): Path<usize>| async move {
+                ([(header::CONTENT_TYPE, content_type)], "a".repeat(size))
+            }
+        };
+        Router::new()
+            .route(
+                "/echo",
+                post(|Json(body): Json<serde_json::Value>| async move { Json(body) }),
+            )
+            .route("/json/{size}", get(answer("application/json")))
+            .route("/events/{size}", get(answer("text/event-stream")))
+            .layer(middleware::from_fn_with_state(
+                BodyLimits::new(&limits),
+                security::body_limit,
+            ))
+            .layer(decompression(&config))
+            .layer(middleware::from_fn_with_state(
+                accepted(&config),
+                check_encoding,
+            ))
+            .layer(compression(&config))
+    }
+
+    // The encoding and the size of an answer sent to a client accepting gzip.
+    async fn encoding_of(path: &str) -> (Option<String>, usize) {
+        let request = Request::get(path)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        let encoding = response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let size = match encoding.as_deref() {
+            Some("gzip") => {
+                let mut decoded = String::new();
+                GzDecoder::new(&body[..])
+                    .read_to_string(&mut decoded)
+                    .unwrap();
+                decoded.len()
+            }
+            _ => body.len(),
+        };
+        (encoding, size)
+    }
+
+    async fn post_echo(body: Vec<u8>, encoding: &str) -> Response {
+        let request = Request::post("/echo")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, encoding)
+            .body(Body::from(body))
+            .unwrap();
+        app().oneshot(request).await.unwrap()
+    }
+
+    fn gzip(body: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn compresses_the_answers_from_the_minimum_size() {
+        assert_eq!(encoding_of("/json/1023").await, (None, 1023));
+        assert_eq!(
+            encoding_of("/json/1024").await,
+            (Some(String::from("gzip")), 1024)
+        );
+    }
+
+    #[tokio::test]
+    async fn never_compresses_an_event_stream() {
+        assert_eq!(encoding_of("/events/4096").await, (None, 4096));
+    }
+
+    #[tokio::test]
+    async fn limits_a_gzip_body_once_decompressed() {
+        let body = "\"small\"";
+        let response = post_echo(gzip(body), "gzip").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let echoed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(echoed, body);
+
+        // a few bytes once compressed, but over the limit once decompressed
+        let body = format!("\"{}\"", "a".repeat(1024));
+        let compressed = gzip(&body);
+        assert!(compressed.len() < 64);
+        let response = post_echo(compressed, "gzip").await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_unsupported_encoding_with_the_accepted_ones() {
+        let response = post_echo(b"\"small\"".to_vec(), "compress").await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            response.headers()[header::ACCEPT_ENCODING],
+            "identity, zstd, br, gzip"
+        );
+        let response = post_echo(b"\"small\"".to_vec(), "identity").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
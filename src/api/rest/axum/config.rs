@@ -0,0 +1,1412 @@
+// CONFIGURATION
+// The server configuration is built from several layers, each one overriding the previous one :
+//   1. the defaults defined in this file (see the 'Default' implementations)
+//   2. a TOML file, given with '--config <FILE>' or the 'APP_CONFIG' environment variable
+//   3. 'APP_*' environment variables, where '__' separates a section from a key :
+//      'APP_SERVER__PORT=9090' overrides 'port' in the '[server]' section
+//   4. command line flags ('--port 9090', or the generic '--set server.port=9090')
+// Every layer is merged into a single TOML table, which is then deserialized into the typed
+// 'Config' structure and validated. Any problem is reported at startup as a 'ConfigError'.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use toml::{Table, Value};
+use tracing_subscriber::EnvFilter;
+
+use crate::auth::Scope;
+
+// prefix of the environment variables read by the configuration
+const ENV_PREFIX: &str = "APP_";
+// environment variable giving the configuration file, when '--config' is not used
+const ENV_CONFIG_FILE: &str = "APP_CONFIG";
+// separator between a section and a key in the environment variable names
+const ENV_SEPARATOR: &str = "__";
+
+// minimum length of the key signing the tokens
+const MIN_SECRET_BYTES: usize = 32;
+
+const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+// Command line flags of the 'rest-api-axum' binary.
+// Only the most common settings have a dedicated flag, any other one can be set with '--set'.
+// The flags are global, so they can be used with every command.
+#[derive(Debug, Default, Parser)]
+#[command(name = "rest-api-axum", version, about = "REST API example using Axum")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "TOML configuration file"
+    )]
+    pub config: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "IP", help = "Address to listen on")]
+    pub bind: Option<String>,
+    #[arg(short, long, global = true, help = "Port to listen on")]
+    pub port: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "LEVEL",
+        help = "Log level: trace, debug, info, warn or error"
+    )]
+    pub log_level: Option<String>,
+    #[arg(
+        long = "set",
+        global = true,
+        value_name = "KEY=VALUE",
+        help = "Any other setting, as a dotted key: --set limits.max_body_bytes=1024"
+    )]
+    pub overrides: Vec<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Run the server (default command)")]
+    Serve,
+    #[command(
+        about = "Probe the local server and exit with a non-zero code when it is not healthy"
+    )]
+    Healthcheck {
+        #[arg(long, default_value = "/healthz", help = "Path to probe")]
+        path: String,
+        #[arg(long, default_value_t = 5, help = "Maximum duration of the probe")]
+        timeout_secs: u64,
+    },
+    #[command(about = "Create an API key in the database and print it")]
+    CreateApiKey {
+        #[arg(long, help = "Name of the key, to recognize it")]
+        name: String,
+        #[arg(
+            long = "scope",
+            value_name = "SCOPE",
+            required = true,
+            value_delimiter = ',',
+            help = "Scopes of the key: users:read, users:write, keys:read, keys:write, logs:read, logs:write, roles:read, roles:write, audit:read, messages:write"
+        )]
+        scopes: Vec<Scope>,
+    },
+    #[command(
+        about = "Check that the entries of the audit log were not changed, inserted or removed"
+    )]
+    VerifyAudit,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub timeouts: TimeoutsConfig,
+    pub limits: LimitsConfig,
+    pub database: DatabaseConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub cookies: CookiesConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub compression: CompressionConfig,
+    pub websocket: WebSocketConfig,
+    pub events: EventsConfig,
+    pub idempotency: IdempotencyConfig,
+    pub passwords: PasswordsConfig,
+    pub mail: MailConfig,
+    pub audit: AuditConfig,
+    // the 'APP_*' environment variables that are not settings, logged by the server
+    #[serde(skip)]
+    pub ignored_env: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    // '0' lets the system pick a free port
+    pub port: u16,
+    // 'development' sends the causes of internal errors to the clients
+    pub environment: Environment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Development,
+    Production,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // level of every log, unless 'filter' is set
+    pub level: String,
+    // 'pretty' for humans, 'json' for log collectors
+    pub format: LogFormat,
+    // directives of 'tracing_subscriber::EnvFilter', replacing 'level' when set :
+    // 'info,rest_api_axum=debug' or 'info,[request{route=/users/.id.}]=debug'
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    // maximum duration of a request, the client receives a '408 Request Timeout' after it
+    pub request_secs: u64,
+    // on SIGTERM or SIGINT, time given to the requests in progress before they are aborted
+    pub shutdown_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // maximum size of a request body
+    pub max_body_bytes: usize,
+    // maximum size of the body of single routes, keyed by route as declared in the router
+    pub routes: BTreeMap<String, usize>,
+    // maximum number of requests processed at the same time, the others wait for a slot
+    pub max_concurrent_requests: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub mode: DatabaseMode,
+    // file of the database, only used by the 'file' mode
+    pub path: PathBuf,
+    // maximum number of connections, only used by the 'file' mode
+    pub pool_size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseMode {
+    // the data is lost when the process exits
+    Memory,
+    // the data is written to a file
+    File,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // serves HTTPS instead of HTTP on 'server.port'
+    pub enabled: bool,
+    // PEM file of the certificate chain, the certificate of the server first
+    pub cert_path: PathBuf,
+    // PEM file of the private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+    // the files are checked at this interval, and reloaded when they change
+    pub reload_secs: u64,
+    // port of a plain HTTP listener redirecting every request to HTTPS, none when not set
+    pub redirect_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // key signing the tokens, at least 32 bytes. When empty, a random key is generated at startup
+    // and the tokens are no longer valid after a restart
+    pub secret: String,
+    // lifetime of an access token, sent with each request
+    pub access_ttl_secs: u64,
+    // lifetime of a refresh token, exchanged for new tokens when the access token expires
+    pub refresh_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookiesConfig {
+    // names of the cookie of the session and of the cookie of its CSRF token
+    pub session_name: String,
+    pub csrf_name: String,
+    // lifetime of a session opened by '/auth/session', from its login
+    pub ttl_secs: u64,
+    // the cookies are only sent over HTTPS. Browsers also accept them from 'http://localhost'.
+    pub secure: bool,
+    // 'strict', 'lax' or 'none' : whether the browsers send the cookies with the requests started
+    // by other sites
+    pub same_site: SameSite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // quota of each client on the routes without their own quota
+    pub default: Quota,
+    // quotas of single routes, keyed by route as declared in the router : '/users/{id}'.
+    // Setting this table replaces the default one.
+    pub routes: BTreeMap<String, Quota>,
+    // the state of the clients idle for long enough to have their full quota again is dropped at
+    // this interval
+    pub evict_secs: u64,
+}
+
+// 'requests' per 'period_secs', of which at most 'burst' at once.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    pub requests: u32,
+    pub period_secs: u64,
+    // 'requests' when not set
+    pub burst: Option<u32>,
+}
+
+// Cross-origin requests of the browsers (CORS), refused unless their origin is allowed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // origins of the front-ends, such as 'https://app.example.com', or '*' for any origin.
+    // CORS is disabled when empty.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    // request headers a front-end may send
+    pub allowed_headers: Vec<String>,
+    // response headers a front-end may read, besides the simple ones
+    pub exposed_headers: Vec<String>,
+    // lets the browser send cookies and 'Authorization', not possible with the origin '*'
+    pub allow_credentials: bool,
+    // the browser keeps the answer of a preflight request this long
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    // 'X-Content-Type-Options: nosniff' and the headers below, unless they are empty
+    pub enabled: bool,
+    // 'Strict-Transport-Security', only sent when TLS is enabled, not sent when 0
+    pub hsts_max_age_secs: u64,
+    pub hsts_include_subdomains: bool,
+    pub content_security_policy: String,
+    // 'X-Frame-Options' : 'DENY' or 'SAMEORIGIN'
+    pub frame_options: String,
+    pub referrer_policy: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    // compresses the answers for the clients accepting it ('Accept-Encoding')
+    pub enabled: bool,
+    // offered for the answers, and accepted for the request bodies
+    pub encodings: Vec<Encoding>,
+    pub level: CompressionLevel,
+    // smaller answers are sent as they are : compressing them saves little or nothing
+    pub min_size_bytes: u64,
+    // content types of the answers compressed, by prefix : 'text/' compresses 'text/plain'
+    pub content_types: Vec<String>,
+    // decompresses the request bodies sent with 'Content-Encoding'. Their size once decompressed
+    // is limited by 'limits', so a small compressed body cannot fill the memory.
+    pub decompress_requests: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionLevel {
+    Fastest,
+    Default,
+    Best,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    // maximum size of a message sent by a client, a larger one closes its session
+    pub max_message_bytes: usize,
+    // messages waiting for each client : a client slower than that misses the oldest ones
+    pub buffer: usize,
+    // messages a session can send, a client sending more is disconnected
+    pub message_rate: Quota,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    // events kept for the clients resuming with 'Last-Event-ID'
+    pub backlog: usize,
+    // a comment is sent on an idle stream after this delay
+    pub keep_alive_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    // the 'Idempotency-Key' header is ignored when disabled
+    pub enabled: bool,
+    // how long the answer of a key is kept, a retry after it runs the request again
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordsConfig {
+    // cost of Argon2id : the memory used by one hash, in KiB, the number of passes over it and
+    // the number of threads. A higher cost slows down the guessing of stolen hashes, but also
+    // each login.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    // shortest password accepted
+    pub min_length: usize,
+    // lifetime of a token sent by '/auth/password/forgot'
+    pub reset_ttl_secs: u64,
+    // link mailed to reset a password, '{token}' is replaced by the reset token
+    pub reset_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    // how the mails are sent
+    pub sender: MailSenderKind,
+    // 'From' of the mails
+    pub from: String,
+    // directory of the mails written by the 'spool' sender, created when missing
+    pub spool_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailSenderKind {
+    // each mail is written to a file of 'spool_dir' instead of being sent, for development and
+    // tests
+    Spool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // records the requests changing something
+    pub enabled: bool,
+    // file of the entries, created when missing, only appended to
+    pub path: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8080,
+            environment: Environment::Production,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: String::from("info"),
+            format: LogFormat::Pretty,
+            filter: None,
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            request_secs: 30,
+            shutdown_secs: 30,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 2 * 1024 * 1024,
+            routes: BTreeMap::new(),
+            max_concurrent_requests: 1024,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            mode: DatabaseMode::File,
+            path: PathBuf::from("rest-api-axum.db"),
+            pool_size: 8,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: PathBuf::from("cert.pem"),
+            key_path: PathBuf::from("key.pem"),
+            reload_secs: 10,
+            redirect_port: None,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            access_ttl_secs: 15 * 60,
+            refresh_ttl_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for CookiesConfig {
+    fn default() -> Self {
+        Self {
+            session_name: String::from("session"),
+            csrf_name: String::from("csrf_token"),
+            ttl_secs: 8 * 60 * 60,
+            secure: true,
+            same_site: SameSite::Strict,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default: Quota::default(),
+            // slows down the guessing of credentials
+            routes: BTreeMap::from([
+                (
+                    String::from("/auth/login"),
+                    Quota {
+                        requests: 10,
+                        period_secs: 60,
+                        burst: None,
+                    },
+                ),
+                (
+                    String::from("/auth/session"),
+                    Quota {
+                        requests: 10,
+                        period_secs: 60,
+                        burst: None,
+                    },
+                ),
+                (
+                    String::from("/auth/password"),
+                    Quota {
+                        requests: 10,
+                        period_secs: 60,
+                        burst: None,
+                    },
+                ),
+                // and the sending of mails
+                (
+                    String::from("/auth/password/forgot"),
+                    Quota {
+                        requests: 5,
+                        period_secs: 60,
+                        burst: None,
+                    },
+                ),
+            ]),
+            evict_secs: 60,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: strings(&["GET", "POST", "PUT", "PATCH", "DELETE"]),
+            allowed_headers: strings(&[
+                "authorization",
+                "content-type",
+                "idempotency-key",
+                "if-match",
+                "if-none-match",
+                "x-csrf-token",
+                "x-request-id",
+            ]),
+            exposed_headers: strings(&[
+                "etag",
+                "idempotent-replayed",
+                "link",
+                "location",
+                "retry-after",
+                "x-request-id",
+                "ratelimit-limit",
+                "ratelimit-remaining",
+                "ratelimit-reset",
+                "ratelimit-policy",
+            ]),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            hsts_max_age_secs: 365 * 24 * 60 * 60,
+            hsts_include_subdomains: true,
+            // the API only serves JSON, and the Swagger UI of '/docs' its own files
+            content_security_policy: String::from(
+                "default-src 'self'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; \
+                 frame-ancestors 'none'",
+            ),
+            frame_options: String::from("DENY"),
+            referrer_policy: String::from("no-referrer"),
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            encodings: vec![Encoding::Zstd, Encoding::Br, Encoding::Gzip],
+            level: CompressionLevel::Default,
+            min_size_bytes: 1024,
+            content_types: vec![
+                String::from("application/json"),
+                String::from("application/problem+json"),
+                String::from("application/javascript"),
+                String::from("text/"),
+            ],
+            decompress_requests: true,
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 64 * 1024,
+            buffer: 256,
+            message_rate: Quota {
+                requests: 10,
+                period_secs: 1,
+                burst: Some(20),
+            },
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            keep_alive_secs: 15,
+        }
+    }
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for PasswordsConfig {
+    fn default() -> Self {
+        // the first recommendation of OWASP for Argon2id
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+            min_length: 8,
+            reset_ttl_secs: 60 * 60,
+            reset_url: String::from("http://localhost:8080/reset-password?token={token}"),
+        }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            sender: MailSenderKind::Spool,
+            from: String::from("rest-api-axum <no-reply@localhost>"),
+            spool_dir: PathBuf::from("mail"),
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: PathBuf::from("audit.log"),
+        }
+    }
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            requests: 600,
+            period_secs: 60,
+            burst: None,
+        }
+    }
+}
+
+impl LogConfig {
+    // the filter of the logs at startup
+    pub fn directives(&self) -> &str {
+        self.filter.as_deref().unwrap_or(&self.level)
+    }
+}
+
+impl Config {
+    // Loads the configuration from every layer, using the environment of the current process.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        Self::load_from(cli, std::env::vars())
+    }
+
+    // Same as 'load' but with the given environment, so the layering does not depend on the
+    // process environment.
+    pub fn load_from(
+        cli: &Cli,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let env: BTreeMap<String, String> = env
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+
+        // layer 2 : the file
+        let file = cli
+            .config
+            .clone()
+            .or_else(|| env.get(ENV_CONFIG_FILE).map(PathBuf::from));
+        let mut table = match file {
+            Some(path) => read_file(path)?,
+            None => Table::new(),
+        };
+
+        // the values of the environment and of the command line, by key : see 'typed' below
+        let mut raw = BTreeMap::new();
+
+        // layer 3 : the environment. The variables that are not settings, such as 'APP_NAME' set
+        // for another program, are ignored.
+        let mut ignored_env = Vec::new();
+        for (name, value) in &env {
+            if name == ENV_CONFIG_FILE {
+                continue;
+            }
+            let key = name[ENV_PREFIX.len()..]
+                .to_lowercase()
+                .replace(ENV_SEPARATOR, ".");
+            if !is_setting(&key) {
+                ignored_env.push(name.clone());
+                continue;
+            }
+            let origin = Origin::Env(name.clone());
+            set(&mut table, &key, Value::String(value.clone()), &origin)?;
+            raw.insert(key, (value.clone(), origin));
+        }
+
+        // layer 4 : the command line
+        let flags = [
+            ("server.bind", &cli.bind),
+            ("server.port", &cli.port),
+            ("log.level", &cli.log_level),
+        ];
+        for (key, value) in flags {
+            if let Some(value) = value {
+                let origin = Origin::Cli(key.to_string());
+                set(&mut table, key, Value::String(value.clone()), &origin)?;
+                raw.insert(key.to_string(), (value.clone(), origin));
+            }
+        }
+        for entry in &cli.overrides {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| ConfigError::Override(entry.clone()))?;
+            let (key, value) = (key.trim(), value.trim());
+            let origin = Origin::Cli(key.to_string());
+            set(&mut table, key, Value::String(value.to_string()), &origin)?;
+            raw.insert(key.to_string(), (value.to_string(), origin));
+        }
+
+        // layer 1 : the defaults, applied by 'serde(default)' during the deserialization
+        let mut config = typed(table, raw)?;
+        config.ignored_env = ignored_env;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks the values that the types alone cannot enforce.
+    // Every problem is collected, so they can all be fixed at once.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if !LOG_LEVELS.contains(&self.log.level.as_str()) {
+            problems.push(format!(
+                "log.level: '{}' is not one of {}",
+                self.log.level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        if let Err(err) = EnvFilter::try_new(self.log.directives()) {
+            problems.push(format!("log.filter: {err}"));
+        }
+        if self.timeouts.request_secs == 0 {
+            problems.push(String::from(
+                "timeouts.request_secs: must be greater than 0",
+            ));
+        }
+        if self.limits.max_body_bytes == 0 {
+            problems.push(String::from(
+                "limits.max_body_bytes: must be greater than 0",
+            ));
+        }
+        for (route, max) in &self.limits.routes {
+            if !route.starts_with('/') {
+                problems.push(format!(
+                    "limits.routes: '{route}' is not a route, it must start with '/'"
+                ));
+            }
+            if *max == 0 {
+                problems.push(format!("limits.routes.\"{route}\": must be greater than 0"));
+            }
+        }
+        if self.limits.max_concurrent_requests == 0 {
+            problems.push(String::from(
+                "limits.max_concurrent_requests: must be greater than 0",
+            ));
+        }
+        if self.database.pool_size == 0 {
+            problems.push(String::from("database.pool_size: must be greater than 0"));
+        }
+        if self.database.mode == DatabaseMode::File && self.database.path.as_os_str().is_empty() {
+            problems.push(String::from(
+                "database.path: must be set when database.mode is 'file'",
+            ));
+        }
+        if self.tls.enabled {
+            problems.extend(self.tls.validate(self.server.port));
+        }
+        if !self.auth.secret.is_empty() && self.auth.secret.len() < MIN_SECRET_BYTES {
+            problems.push(format!(
+                "auth.secret: must be at least {MIN_SECRET_BYTES} bytes long"
+            ));
+        }
+        if self.auth.access_ttl_secs == 0 {
+            problems.push(String::from("auth.access_ttl_secs: must be greater than 0"));
+        }
+        if self.auth.refresh_ttl_secs == 0 {
+            problems.push(String::from(
+                "auth.refresh_ttl_secs: must be greater than 0",
+            ));
+        }
+        problems.extend(self.cookies.validate());
+        // checked even when disabled : the quotas are computed at startup
+        problems.extend(self.rate_limit.validate());
+        problems.extend(self.cors.validate());
+        if self.compression.encodings.is_empty()
+            && (self.compression.enabled || self.compression.decompress_requests)
+        {
+            problems.push(String::from(
+                "compression.encodings: must not be empty when compression.enabled or \
+                 compression.decompress_requests is true",
+            ));
+        }
+        if self.security_headers.enabled {
+            problems.extend(self.security_headers.validate());
+        }
+        if self.websocket.max_message_bytes == 0 {
+            problems.push(String::from(
+                "websocket.max_message_bytes: must be greater than 0",
+            ));
+        }
+        if self.websocket.buffer == 0 {
+            problems.push(String::from("websocket.buffer: must be greater than 0"));
+        }
+        problems.extend(
+            self.websocket
+                .message_rate
+                .validate("websocket.message_rate"),
+        );
+        if self.events.backlog == 0 {
+            problems.push(String::from("events.backlog: must be greater than 0"));
+        }
+        if self.idempotency.enabled && self.idempotency.ttl_secs == 0 {
+            problems.push(String::from("idempotency.ttl_secs: must be greater than 0"));
+        }
+        if self.events.keep_alive_secs == 0 {
+            problems.push(String::from(
+                "events.keep_alive_secs: must be greater than 0",
+            ));
+        }
+        problems.extend(self.passwords.validate());
+        problems.extend(self.mail.validate());
+        if self.audit.enabled && self.audit.path.as_os_str().is_empty() {
+            problems.push(String::from(
+                "audit.path: must be set when audit.enabled is true",
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind, self.server.port)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.request_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.shutdown_secs)
+    }
+
+    // address of the listener redirecting HTTP to HTTPS, if any
+    pub fn redirect_addr(&self) -> Option<SocketAddr> {
+        self.tls
+            .redirect_port
+            .filter(|_| self.tls.enabled)
+            .map(|port| SocketAddr::new(self.server.bind, port))
+    }
+}
+
+impl TlsConfig {
+    // only checked when TLS is enabled
+    fn validate(&self, port: u16) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.cert_path.as_os_str().is_empty() {
+            problems.push(String::from(
+                "tls.cert_path: must be set when tls.enabled is true",
+            ));
+        }
+        if self.key_path.as_os_str().is_empty() {
+            problems.push(String::from(
+                "tls.key_path: must be set when tls.enabled is true",
+            ));
+        }
+        if self.reload_secs == 0 {
+            problems.push(String::from("tls.reload_secs: must be greater than 0"));
+        }
+        if self
+            .redirect_port
+            .is_some_and(|redirect| redirect == port && port != 0)
+        {
+            problems.push(format!(
+                "tls.redirect_port: {port} is already used by server.port"
+            ));
+        }
+        problems
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_secs)
+    }
+}
+
+impl CookiesConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        // the characters of a token (RFC 6265)
+        let valid = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+        };
+        for (key, name) in [
+            ("cookies.session_name", &self.session_name),
+            ("cookies.csrf_name", &self.csrf_name),
+        ] {
+            if !valid(name) {
+                problems.push(format!("{key}: '{name}' is not a valid cookie name"));
+            }
+        }
+        if self.session_name == self.csrf_name {
+            problems.push(String::from(
+                "cookies.csrf_name: must differ from cookies.session_name",
+            ));
+        }
+        if self.ttl_secs == 0 {
+            problems.push(String::from("cookies.ttl_secs: must be greater than 0"));
+        }
+        // the browsers refuse such cookies
+        if self.same_site == SameSite::None && !self.secure {
+            problems.push(String::from(
+                "cookies.same_site: cannot be 'none' when cookies.secure is false",
+            ));
+        }
+        problems
+    }
+}
+
+impl RateLimitConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = self.default.validate("rate_limit.default");
+        for (route, quota) in &self.routes {
+            if !route.starts_with('/') {
+                problems.push(format!(
+                    "rate_limit.routes: '{route}' is not a route, it must start with '/'"
+                ));
+            }
+            problems.extend(quota.validate(&format!("rate_limit.routes.\"{route}\"")));
+        }
+        if self.evict_secs == 0 {
+            problems.push(String::from(
+                "rate_limit.evict_secs: must be greater than 0",
+            ));
+        }
+        problems
+    }
+
+    pub fn evict_interval(&self) -> Duration {
+        Duration::from_secs(self.evict_secs)
+    }
+}
+
+impl PasswordsConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(err) = self.params() {
+            problems.push(format!(
+                "passwords: invalid cost (memory_kib, iterations, parallelism): {err}"
+            ));
+        }
+        if self.min_length == 0 {
+            problems.push(String::from("passwords.min_length: must be greater than 0"));
+        }
+        if self.reset_ttl_secs == 0 {
+            problems.push(String::from(
+                "passwords.reset_ttl_secs: must be greater than 0",
+            ));
+        }
+        if !self.reset_url.contains("{token}") {
+            problems.push(String::from("passwords.reset_url: must contain '{token}'"));
+        }
+        problems
+    }
+
+    pub fn params(&self) -> Result<argon2::Params, argon2::Error> {
+        argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+    }
+}
+
+impl MailConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.from.trim().is_empty() || self.from.contains(['\r', '\n']) {
+            problems.push(String::from("mail.from: must be a single line address"));
+        }
+        if self.sender == MailSenderKind::Spool && self.spool_dir.as_os_str().is_empty() {
+            problems.push(String::from(
+                "mail.spool_dir: must be set when mail.sender is 'spool'",
+            ));
+        }
+        problems
+    }
+}
+
+impl EventsConfig {
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_secs)
+    }
+}
+
+impl CorsConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for origin in &self.allowed_origins {
+            let valid = origin == "*"
+                || (origin.starts_with("https://") || origin.starts_with("http://"))
+                    && !origin.ends_with('/')
+                    && HeaderValue::from_str(origin).is_ok();
+            if !valid {
+                problems.push(format!(
+                    "cors.allowed_origins: '{origin}' is not '*' or an origin such as 'https://app.example.com'"
+                ));
+            }
+        }
+        if self.allow_credentials && self.allowed_origins.iter().any(|origin| origin == "*") {
+            problems.push(String::from(
+                "cors.allow_credentials: cannot be true when cors.allowed_origins has '*'",
+            ));
+        }
+        for method in &self.allowed_methods {
+            if Method::from_str(method).is_err() {
+                problems.push(format!(
+                    "cors.allowed_methods: '{method}' is not an HTTP method"
+                ));
+            }
+        }
+        let headers = [
+            ("allowed_headers", &self.allowed_headers),
+            ("exposed_headers", &self.exposed_headers),
+        ];
+        for (key, names) in headers {
+            for name in names {
+                if HeaderName::from_str(name).is_err() {
+                    problems.push(format!("cors.{key}: '{name}' is not a header name"));
+                }
+            }
+        }
+        problems
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_secs)
+    }
+}
+
+impl SecurityHeadersConfig {
+    // only checked when the headers are enabled
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let values = [
+            ("content_security_policy", &self.content_security_policy),
+            ("referrer_policy", &self.referrer_policy),
+        ];
+        for (key, value) in values {
+            if HeaderValue::from_str(value).is_err() {
+                problems.push(format!(
+                    "security_headers.{key}: '{value}' is not a valid header value"
+                ));
+            }
+        }
+        if !["", "DENY", "SAMEORIGIN"].contains(&self.frame_options.as_str()) {
+            problems.push(format!(
+                "security_headers.frame_options: '{}' is not one of DENY, SAMEORIGIN or empty",
+                self.frame_options
+            ));
+        }
+        problems
+    }
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Br => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+impl Quota {
+    fn validate(&self, key: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.requests == 0 {
+            problems.push(format!("{key}.requests: must be greater than 0"));
+        }
+        if self.period_secs == 0 {
+            problems.push(format!("{key}.period_secs: must be greater than 0"));
+        }
+        // the time between two requests is counted in nanoseconds
+        if self.requests > 0 && self.interval().is_zero() {
+            problems.push(format!(
+                "{key}.requests: must be at most one per nanosecond of period_secs"
+            ));
+        }
+        if self.burst == Some(0) {
+            problems.push(format!("{key}.burst: must be greater than 0"));
+        }
+        problems
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.requests)
+    }
+
+    // time between two requests at the sustained rate, only valid once validated
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.period_secs) / self.requests.max(1)
+    }
+}
+
+// where an overriding value comes from, to give a precise error message
+enum Origin {
+    Env(String),
+    Cli(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Env(name) => write!(f, "environment variable '{name}'"),
+            Origin::Cli(key) => write!(f, "command line flag for '{key}'"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // the configuration file cannot be read
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    // the configuration file is not valid TOML
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    // a '--set' flag is not of the form 'KEY=VALUE'
+    Override(String),
+    // an environment variable or a flag targets a key that is not a setting
+    Key {
+        key: String,
+        origin: String,
+    },
+    // the merged configuration does not match the expected types or values
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "cannot read '{}': {source}", path.display())
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "cannot parse '{}': {message}", path.display())
+            }
+            ConfigError::Override(entry) => {
+                write!(f, "'--set {entry}' is not of the form KEY=VALUE")
+            }
+            ConfigError::Key { key, origin } => {
+                write!(f, "'{key}' set by {origin} is not a valid setting")
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn read_file(path: PathBuf) -> Result<Table, ConfigError> {
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(source) => return Err(ConfigError::Io { path, source }),
+    };
+    content.parse::<Table>().map_err(|err| ConfigError::Parse {
+        path,
+        message: err.message().to_string(),
+    })
+}
+
+// Deserializes the merged layers. The values of the environment and of the command line ('raw')
+// are set as strings : when the setting of one of them is not a string, the deserialization fails
+// on its key, and it is read as a TOML value instead ('8080' is an integer, 'true' a boolean,
+// '["a", "b"]' an array). So the type of the setting decides : 'APP_AUTH__SECRET=12345' stays a
+// string while 'APP_SERVER__PORT=9090' is a number.
+fn typed(
+    mut table: Table,
+    mut raw: BTreeMap<String, (String, Origin)>,
+) -> Result<Config, ConfigError> {
+    loop {
+        let err: serde_path_to_error::Error<toml::de::Error> =
+            match serde_path_to_error::deserialize(Value::Table(table.clone())) {
+                Ok(config) => return Ok(config),
+                Err(err) => err,
+            };
+        // each value is read again once at most, the second failure is reported
+        let key = err.path().to_string();
+        match raw.remove(&key) {
+            Some((value, origin)) => set(&mut table, &key, parse_value(&value), &origin)?,
+            // the path of the failing key ('server.port') is kept in the error message
+            None => {
+                return Err(ConfigError::Invalid(vec![format!(
+                    "{key}: {}",
+                    err.inner().message()
+                )]))
+            }
+        }
+    }
+}
+
+// Whether the dotted key names a setting. A configuration holding this key alone is read : an
+// unknown field can only be this key.
+fn is_setting(key: &str) -> bool {
+    let mut table = Table::new();
+    let origin = Origin::Env(key.to_string());
+    if set(&mut table, key, Value::String(String::new()), &origin).is_err() {
+        return false;
+    }
+    match serde_path_to_error::deserialize::<_, Config>(Value::Table(table)) {
+        Ok(_) => true,
+        Err(err) => !err.inner().message().starts_with("unknown field"),
+    }
+}
+
+// Sets a dotted key ('server.port') in the table, creating the missing sections.
+fn set(table: &mut Table, key: &str, value: Value, origin: &Origin) -> Result<(), ConfigError> {
+    let invalid = || ConfigError::Key {
+        key: key.to_string(),
+        origin: origin.to_string(),
+    };
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts
+        .pop()
+        .filter(|last| !last.is_empty())
+        .ok_or_else(invalid)?;
+    let mut current = table;
+    for part in parts {
+        let entry = current
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+        current = entry.as_table_mut().ok_or_else(invalid)?;
+    }
+    current.insert(last.to_string(), value);
+    Ok(())
+}
+
+// The raw value as a TOML value when possible, as a plain string otherwise.
+fn parse_value(raw: &str) -> Value {
+    format!("value = {raw}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(env: &[(&str, &str)], args: &[&str]) -> Result<Config, ConfigError> {
+        let cli = Cli::parse_from(std::iter::once("rest-api-axum").chain(args.iter().copied()));
+        let env = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        Config::load_from(&cli, env)
+    }
+
+    #[test]
+    fn reads_the_values_with_the_type_of_their_setting() {
+        let config = load(
+            &[
+                ("APP_SERVER__PORT", "9090"),
+                ("APP_AUTH__SECRET", "12345678901234567890123456789012"),
+                (
+                    "APP_CORS__ALLOWED_ORIGINS",
+                    r#"["https://app.example.com"]"#,
+                ),
+                ("APP_TLS__ENABLED", "false"),
+            ],
+            &["--set", "log.filter=1234"],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.auth.secret, "12345678901234567890123456789012");
+        assert_eq!(config.cors.allowed_origins, ["https://app.example.com"]);
+        assert!(!config.tls.enabled);
+        assert_eq!(config.log.filter.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn ignores_the_variables_that_are_not_settings() {
+        let config = load(&[("APP_NAME", "shop"), ("APP_SERVER__NAME", "api")], &[]).unwrap();
+        assert_eq!(config.ignored_env, ["APP_NAME", "APP_SERVER__NAME"]);
+    }
+
+    #[test]
+    fn refuses_the_unknown_flags_and_the_invalid_values() {
+        assert!(matches!(
+            load(&[], &["--set", "server.name=api"]),
+            Err(ConfigError::Invalid(_))
+        ));
+        let Err(ConfigError::Invalid(problems)) = load(&[("APP_SERVER__PORT", "http")], &[]) else {
+            panic!("the port is not a number");
+        };
+        assert!(problems[0].starts_with("server.port: "), "{problems:?}");
+    }
+
+    #[test]
+    fn refuses_a_quota_without_time_between_two_requests_even_when_disabled() {
+        let Err(ConfigError::Invalid(problems)) = load(
+            &[
+                ("APP_RATE_LIMIT__ENABLED", "false"),
+                ("APP_RATE_LIMIT__DEFAULT__REQUESTS", "0"),
+            ],
+            &[],
+        ) else {
+            panic!("a quota without requests is accepted");
+        };
+        assert_eq!(
+            problems,
+            ["rate_limit.default.requests: must be greater than 0"]
+        );
+        let Err(ConfigError::Invalid(problems)) = load(
+            &[
+                ("APP_RATE_LIMIT__DEFAULT__REQUESTS", "2000000000"),
+                ("APP_RATE_LIMIT__DEFAULT__PERIOD_SECS", "1"),
+            ],
+            &[],
+        ) else {
+            panic!("a quota of more than a request per nanosecond is accepted");
+        };
+        assert_eq!(
+            problems,
+            ["rate_limit.default.requests: must be at most one per nanosecond of period_secs"]
+        );
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_environment() {
+        let config = load(&[("APP_SERVER__PORT", "9090")], &["--port", "9091"]).unwrap();
+        assert_eq!(config.server.port, 9091);
+    }
+}
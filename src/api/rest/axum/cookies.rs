@@ -0,0 +1,360 @@
+// COOKIE SESSIONS
+//   POST   /auth/session  signs a user in, the session is kept in a cookie
+//   DELETE /auth/session  revokes the session of the cookie and removes the cookies
+// For the browser tools, which cannot easily keep tokens : the session is stored in the database,
+// as the sessions of '/auth/login', and the browser only holds its identifier, signed with
+// 'auth.secret', in an 'HttpOnly' cookie that the scripts of the page cannot read :
+//   Set-Cookie: session=<id>.<signature>; Path=/; Max-Age=28800; HttpOnly; SameSite=Strict; Secure
+// The session expires 'cookies.ttl_secs' after the login. Each login opens a new session, and
+// revokes the one of the cookie sent with it, if any : a session planted in the browser before the
+// login is never used after it.
+// The browsers send the cookie with every request, even those started by another site (CSRF).
+// Besides 'SameSite', the changes (any method but GET, HEAD and OPTIONS) made with the cookie
+// require its CSRF token (double submit) : the token is sent in a second cookie, readable by the
+// scripts, and in the body of the login. The page sends it back in the 'X-CSRF-Token' header,
+// which another site can neither read nor set. The token is the signature of the session, so a
+// token of another session is refused too.
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{AppendHeaders, IntoResponse},
+    routing::post,
+    Router,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::auth::{inactive, now, random_id, AuthUser, Login, Principal, Scope};
+use crate::config::{CookiesConfig, SameSite};
+use crate::db::Db;
+use crate::error::{AppError, ErrorCode, Problem};
+use crate::extract::Json;
+use crate::roles::user_scopes;
+use crate::state::AppState;
+use crate::users::{user_from_row, User, USER_COLUMNS};
+
+const CSRF_HEADER: &str = "x-csrf-token";
+
+// Answer of 'POST /auth/session'.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CookieSession {
+    pub user: User,
+    // to send in the 'X-CSRF-Token' header of the changes, also in the CSRF cookie
+    pub csrf_token: String,
+    // lifetime of the session, in seconds
+    pub expires_in: u64,
+}
+
+// The sessions of the cookies.
+#[derive(Clone)]
+pub struct CookieSessions {
+    config: CookiesConfig,
+    db: Db,
+}
+
+impl CookieSessions {
+    pub fn new(config: &CookiesConfig, db: Db) -> Self {
+        Self {
+            config: config.clone(),
+            db,
+        }
+    }
+
+    // The user of an open session, with the scopes of its roles.
+    async fn user(&self, session: String) -> Result<Option<(User, Vec<Scope>)>, AppError> {
+        Ok(self
+            .db
+            .call(move |conn| {
+                let user = conn
+                    .query_row(
+                        &format!(
+                            "SELECT {USER_COLUMNS} FROM users WHERE id = (
+                                SELECT user_id FROM sessions
+                                WHERE id = ?1 AND kind = 'cookie' AND revoked = 0
+                                    AND expires_at >= ?2
+                             )"
+                        ),
+                        params![session, now()],
+                        user_from_row,
+                    )
+                    .optional()?;
+                match user {
+                    Some(user) => {
+                        let scopes = user_scopes(conn, user.id)?;
+                        Ok(Some((user, scopes)))
+                    }
+                    None => Ok(None),
+                }
+            })
+            .await?)
+    }
+
+    fn cookie(&self, name: &str, value: &str, max_age: u64, http_only: bool) -> HeaderValue {
+        let mut cookie = format!("{name}={value}; Path=/; Max-Age={max_age}");
+        if http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        cookie.push_str(match self.config.same_site {
+            SameSite::Strict => "; SameSite=Strict",
+            SameSite::Lax => "; SameSite=Lax",
+            SameSite::None => "; SameSite=None",
+        });
+        if self.config.secure {
+            cookie.push_str("; Secure");
+        }
+        // the names are checked by 'Config::validate', the values are base64url
+        HeaderValue::from_str(&cookie).unwrap_or(HeaderValue::from_static(""))
+    }
+
+    // 'Set-Cookie' of the session and of its CSRF token, or of their removal without a session.
+    fn set_cookies(
+        &self,
+        session: Option<(&str, &str)>,
+    ) -> AppendHeaders<[(header::HeaderName, HeaderValue); 2]> {
+        let (session, csrf, max_age) = match session {
+            Some((session, csrf)) => (session, csrf, self.config.ttl_secs),
+            None => ("", "", 0),
+        };
+        AppendHeaders([
+            (
+                header::SET_COOKIE,
+                self.cookie(&self.config.session_name, session, max_age, true),
+            ),
+            (
+                header::SET_COOKIE,
+                self.cookie(&self.config.csrf_name, csrf, max_age, false),
+            ),
+        ])
+    }
+}
+
+// The value of a cookie of the request.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// The identifier of the session of the cookie, when its signature is valid.
+fn session_id(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    let value = cookie(headers, &state.cookies.config.session_name)?;
+    let (session, signature) = value.split_once('.')?;
+    state
+        .auth
+        .verify_signature(&format!("session:{session}"), signature)
+        .then(|| session.to_owned())
+}
+
+fn csrf_token(state: &AppState, session: &str) -> String {
+    state.auth.sign(&format!("csrf:{session}"))
+}
+
+// The user of the session cookie of a request without 'Authorization', called by 'authenticate'.
+// An unknown, expired or revoked session is ignored : the request goes on anonymously, so the user
+// can still sign in again.
+pub async fn principal(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Option<Principal>, AppError> {
+    let Some(session) = session_id(state, headers) else {
+        return Ok(None);
+    };
+    let Some((user, scopes)) = state.cookies.user(session.clone()).await? else {
+        return Ok(None);
+    };
+    if !user.active {
+        return Err(inactive(&user));
+    }
+    let safe = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+    if !safe {
+        let header = headers
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let cookie = cookie(headers, &state.cookies.config.csrf_name);
+        let valid = match (header, cookie) {
+            (Some(header), Some(cookie)) => {
+                header == cookie
+                    && state
+                        .auth
+                        .verify_signature(&format!("csrf:{session}"), header)
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(
+                AppError::new(ErrorCode::InvalidCsrfToken).with_detail(format!(
+                    "send the token of the '{}' cookie in the 'X-CSRF-Token' header",
+                    state.cookies.config.csrf_name
+                )),
+            );
+        }
+    }
+    Ok(Some(Principal::User {
+        user,
+        session,
+        scopes,
+    }))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/auth/session", post(create_session).delete(delete_session))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/session",
+    tag = "auth",
+    request_body = Login,
+    responses(
+        (status = 200, description = "The user is signed in, the session and its CSRF token are in cookies", body = CookieSession,
+            headers(("Set-Cookie" = String, description = "Cookies of the session and of its CSRF token"))),
+        (status = 401, description = "Unknown username or wrong password", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The user is not active", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn create_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<Login>,
+) -> Result<impl IntoResponse, AppError> {
+    // the session of the cookie sent with the login, replaced by the new one
+    let replaced = session_id(&state, &headers);
+    let session = random_id();
+    let expires_at = now() + state.cookies.config.ttl_secs;
+    let id = session.clone();
+    let user = state
+        .auth
+        .sign_in(body, &state.passwords, &state.events, move |tx, user| {
+            if let Some(replaced) = replaced {
+                tx.execute(
+                    "UPDATE sessions SET revoked = 1 WHERE id = ?1 AND kind = 'cookie'",
+                    [replaced],
+                )?;
+            }
+            tx.execute(
+                "INSERT INTO sessions (id, user_id, refresh_jti, expires_at, kind)
+                 VALUES (?1, ?2, '', ?3, 'cookie')",
+                params![id, user.id, expires_at],
+            )?;
+            Ok(user.clone())
+        })
+        .await?;
+    let signed = format!(
+        "{session}.{}",
+        state.auth.sign(&format!("session:{session}"))
+    );
+    let csrf_token = csrf_token(&state, &session);
+    Ok((
+        state.cookies.set_cookies(Some((&signed, &csrf_token))),
+        Json(CookieSession {
+            user,
+            csrf_token,
+            expires_in: state.cookies.config.ttl_secs,
+        }),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/session",
+    tag = "auth",
+    responses(
+        (status = 204, description = "The session is revoked and its cookies removed"),
+        (status = 401, description = "No valid session", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing or invalid CSRF token", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn delete_session(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<impl IntoResponse, AppError> {
+    let session = auth.session;
+    state
+        .db
+        .call(move |conn| {
+            conn.execute("UPDATE sessions SET revoked = 1 WHERE id = ?1", [session])?;
+            Ok(())
+        })
+        .await?;
+    Ok((StatusCode::NO_CONTENT, state.cookies.set_cookies(None)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::users::CreateUser;
+    use crate::validation::Violations;
+
+    // Opens a cookie session of the user, returns the value of its session cookie and its CSRF
+    // token.
+    async fn open(state: &AppState, user: u64) -> (String, String) {
+        let session = random_id();
+        let id = session.clone();
+        state
+            .db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO sessions (id, user_id, refresh_jti, expires_at, kind)
+                     VALUES (?1, ?2, '', ?3, 'cookie')",
+                    params![id, user, now() + 60],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let signed = format!(
+            "{session}.{}",
+            state.auth.sign(&format!("session:{session}"))
+        );
+        (signed, csrf_token(state, &session))
+    }
+
+    fn headers(session: &str, csrf: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let cookies = match csrf {
+            Some(csrf) => format!("session={session}; csrf_token={csrf}"),
+            None => format!("session={session}"),
+        };
+        headers.insert(header::COOKIE, cookies.parse().unwrap());
+        if let Some(csrf) = csrf {
+            headers.insert(CSRF_HEADER, csrf.parse().unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn refuses_the_changes_without_the_csrf_token_of_the_session() {
+        let state = AppState::test(&Config::default());
+        let body = CreateUser {
+            username: String::from("sam"),
+            email: String::from("sam@example.com"),
+            active: None,
+            sign_in_count: None,
+            password: None,
+        };
+        let user = state.users.create(body, None, Violations::default());
+        let user = user.await.unwrap().unwrap();
+        let (session, csrf) = open(&state, user.id).await;
+        let (_, other_csrf) = open(&state, user.id).await;
+
+        // reading needs no token
+        let read = principal(&state, &Method::GET, &headers(&session, None)).await;
+        assert!(matches!(read, Ok(Some(Principal::User { .. }))));
+        let changed = principal(&state, &Method::POST, &headers(&session, Some(&csrf))).await;
+        assert!(matches!(changed, Ok(Some(Principal::User { .. }))));
+        for csrf in [None, Some(other_csrf.as_str())] {
+            let err = principal(&state, &Method::POST, &headers(&session, csrf))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().starts_with("invalid_csrf_token"), "{err}");
+        }
+    }
+}
@@ -0,0 +1,334 @@
+// DATABASE
+// Embedded SQLite database, no external server is needed.
+// Two modes are available (see 'database.mode' in the configuration) :
+//   - 'file'   : the data is written to 'database.path' and survives a restart
+//   - 'memory' : the data lives in memory and is lost when the process exits
+// Connections come from an 'r2d2' pool. SQLite calls are blocking, so they run on the blocking
+// thread pool of Tokio through 'Db::call' instead of blocking the async workers.
+use std::fmt;
+use std::time::Duration;
+
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+
+use crate::config::{DatabaseConfig, DatabaseMode};
+
+// Schema of the database. Each entry is applied once, in order, and the number of applied entries
+// is kept in 'PRAGMA user_version'. To change the schema, add an entry at the end : never edit an
+// entry already released.
+const MIGRATIONS: &[&str] = &[
+    // 1 : users of the structures tutorial
+    "
+    CREATE TABLE users (
+        id            INTEGER PRIMARY KEY AUTOINCREMENT,
+        active        INTEGER NOT NULL,
+        username      TEXT    NOT NULL,
+        email         TEXT    NOT NULL,
+        sign_in_count INTEGER NOT NULL
+    );
+",
+    // 2 : sessions opened by '/auth/login', see 'auth.rs'
+    "
+    CREATE TABLE sessions (
+        id          TEXT    PRIMARY KEY,
+        user_id     INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        refresh_jti TEXT    NOT NULL,
+        expires_at  INTEGER NOT NULL,
+        revoked     INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX sessions_user_id ON sessions (user_id);
+",
+    // 3 : API keys, see 'api_keys.rs'
+    "
+    CREATE TABLE api_keys (
+        id           INTEGER PRIMARY KEY AUTOINCREMENT,
+        name         TEXT    NOT NULL,
+        prefix       TEXT    NOT NULL UNIQUE,
+        hash         TEXT    NOT NULL UNIQUE,
+        scopes       TEXT    NOT NULL,
+        created_at   INTEGER NOT NULL,
+        last_used_at INTEGER,
+        revoked_at   INTEGER
+    );
+",
+    // 4 : roles of the users, see 'roles.rs'. The permissions of the built in roles live in the code.
+    "
+    CREATE TABLE roles (
+        name   TEXT PRIMARY KEY,
+        scopes TEXT NOT NULL
+    );
+    INSERT INTO roles (name, scopes) VALUES ('admin', ''), ('editor', ''), ('viewer', '');
+    CREATE TABLE user_roles (
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        role    TEXT    NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+        PRIMARY KEY (user_id, role)
+    );
+",
+    // 5 : sorts of the listing of the users, see 'list' in 'users.rs'
+    "
+    CREATE INDEX users_username ON users (username, id);
+    CREATE INDEX users_email ON users (email, id);
+    CREATE INDEX users_sign_in_count ON users (sign_in_count, id);
+",
+    // 6 : version of the users, increased by each change, see 'etag.rs'
+    "
+    ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+",
+    // 7 : answers of the requests with an 'Idempotency-Key', see 'idempotency.rs'. The status is
+    // null while the first request runs.
+    "
+    CREATE TABLE idempotency_keys (
+        client      TEXT    NOT NULL,
+        key         TEXT    NOT NULL,
+        fingerprint TEXT    NOT NULL,
+        status      INTEGER,
+        headers     TEXT,
+        body        BLOB,
+        expires_at  INTEGER NOT NULL,
+        PRIMARY KEY (client, key)
+    );
+    CREATE INDEX idempotency_keys_expires_at ON idempotency_keys (expires_at);
+",
+    // 8 : passwords of the users and their reset tokens, see 'passwords.rs'. A user without a
+    // password cannot sign in.
+    "
+    ALTER TABLE users ADD COLUMN password_hash TEXT;
+    CREATE TABLE password_resets (
+        hash       TEXT    PRIMARY KEY,
+        user_id    INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX password_resets_user_id ON password_resets (user_id);
+",
+    // 9 : sessions of the browsers, authenticated by a cookie instead of tokens, see 'cookies.rs'.
+    // Their 'refresh_jti' is empty.
+    "
+    ALTER TABLE sessions ADD COLUMN kind TEXT NOT NULL DEFAULT 'token';
+",
+];
+
+#[derive(Clone)]
+pub struct Db {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    // no connection could be taken from the pool
+    Pool(r2d2::Error),
+    Sqlite(rusqlite::Error),
+    // the blocking task running the query panicked or was cancelled
+    Task(tokio::task::JoinError),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Pool(err) => write!(f, "no database connection available: {err}"),
+            DbError::Sqlite(err) => write!(f, "database error: {err}"),
+            DbError::Task(err) => write!(f, "database task failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<r2d2::Error> for DbError {
+    fn from(err: r2d2::Error) -> Self {
+        DbError::Pool(err)
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(err: rusqlite::Error) -> Self {
+        DbError::Sqlite(err)
+    }
+}
+
+impl Db {
+    // Opens the database and creates or upgrades its schema.
+    pub fn open(config: &DatabaseConfig) -> Result<Self, DbError> {
+        let pool = match config.mode {
+            DatabaseMode::File => {
+                let manager = SqliteConnectionManager::file(&config.path).with_init(|conn| {
+                    // WAL lets readers work while a write is in progress
+                    conn.pragma_update(None, "journal_mode", "WAL")?;
+                    conn.pragma_update(None, "foreign_keys", "ON")?;
+                    conn.busy_timeout(Duration::from_secs(5))
+                });
+                Pool::builder().max_size(config.pool_size).build(manager)?
+            }
+            // Each in-memory connection has its own database : the pool keeps a single connection
+            // forever, so every request sees the same data.
+            DatabaseMode::Memory => {
+                let manager = SqliteConnectionManager::memory()
+                    .with_init(|conn| conn.pragma_update(None, "foreign_keys", "ON"));
+                Pool::builder()
+                    .max_size(1)
+                    .idle_timeout(None)
+                    .max_lifetime(None)
+                    .build(manager)?
+            }
+        };
+        let db = Self { pool };
+        migrate(&mut *db.connection()?)?;
+        Ok(db)
+    }
+
+    fn connection(&self) -> Result<PooledConnection<SqliteConnectionManager>, DbError> {
+        Ok(self.pool.get()?)
+    }
+
+    // Runs the function with a connection of the pool, on a thread where blocking is allowed.
+    // Writes spanning several statements should open a transaction with 'conn.transaction()' so
+    // they are applied entirely or not at all.
+    pub async fn call<T, F>(&self, f: F) -> Result<T, DbError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, DbError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || f(&mut *db.connection()?))
+            .await
+            .map_err(DbError::Task)?
+    }
+}
+
+// Applies the migrations not applied yet, in a single transaction.
+fn migrate(conn: &mut Connection) -> Result<(), DbError> {
+    let tx = conn.transaction()?;
+    let applied: usize = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if applied < MIGRATIONS.len() {
+        for migration in &MIGRATIONS[applied..] {
+            tx.execute_batch(migration)?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::digest::{digest, SHA256};
+
+    use super::*;
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    // the statements creating the tables and indexes, by name
+    fn schema(conn: &Connection) -> Vec<(String, String)> {
+        let mut statement = conn
+            .prepare("SELECT name, sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY name")
+            .unwrap();
+        let schema = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        schema.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn file(path: &std::path::Path) -> DatabaseConfig {
+        DatabaseConfig {
+            mode: DatabaseMode::File,
+            path: path.to_path_buf(),
+            pool_size: 4,
+        }
+    }
+
+    #[test]
+    fn upgrades_a_database_from_every_version() {
+        let mut latest = Connection::open_in_memory().unwrap();
+        migrate(&mut latest).unwrap();
+
+        for version in 0..MIGRATIONS.len() {
+            let mut conn = Connection::open_in_memory().unwrap();
+            for migration in &MIGRATIONS[..version] {
+                conn.execute_batch(migration).unwrap();
+            }
+            conn.pragma_update(None, "user_version", version).unwrap();
+
+            migrate(&mut conn).unwrap();
+            assert_eq!(user_version(&conn), MIGRATIONS.len());
+            assert_eq!(schema(&conn), schema(&latest), "from the version {version}");
+        }
+    }
+
+    #[test]
+    fn applies_each_migration_once_and_never_changes_them() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        // applied again, the first one would fail : the table 'users' exists
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        // the released migrations are applied to the existing databases, editing them would
+        // leave these databases with another schema : append a new migration instead
+        let released = MIGRATIONS[..9].concat();
+        let hash = digest(&SHA256, released.as_bytes());
+        let hash: String = hash.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(
+            hash,
+            "1959993ea4fbe080575d9c07f55b1d69bc28d739fd51ee6f2a78a24f03aedf47"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_data_of_a_file_and_not_of_the_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = file(&dir.path().join("users.db"));
+        let insert = |conn: &mut Connection| {
+            conn.execute(
+                "INSERT INTO users (active, username, email, sign_in_count)
+                 VALUES (1, 'sam', 'sam@example.com', 1)",
+                [],
+            )?;
+            Ok(())
+        };
+        let count = |conn: &mut Connection| {
+            Ok(conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get::<_, u64>(0))?)
+        };
+
+        let db = Db::open(&config).unwrap();
+        db.call(insert).await.unwrap();
+        let journal_mode = db.call(|conn| {
+            Ok(conn.pragma_query_value(None, "journal_mode", |row| row.get::<_, String>(0))?)
+        });
+        assert_eq!(journal_mode.await.unwrap(), "wal");
+        drop(db);
+        assert_eq!(Db::open(&config).unwrap().call(count).await.unwrap(), 1);
+
+        let memory = DatabaseConfig {
+            mode: DatabaseMode::Memory,
+            ..Default::default()
+        };
+        let db = Db::open(&memory).unwrap();
+        db.call(insert).await.unwrap();
+        // every call gets the same connection, so the same database
+        assert_eq!(db.call(count).await.unwrap(), 1);
+        assert_eq!(Db::open(&memory).unwrap().call(count).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn enforces_the_foreign_keys_on_every_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(&file(&dir.path().join("users.db"))).unwrap();
+
+        // held together, they are different connections of the pool
+        let connections: Vec<_> = (0..4).map(|_| db.connection().unwrap()).collect();
+        for conn in &connections {
+            let enabled: bool = conn
+                .pragma_query_value(None, "foreign_keys", |row| row.get(0))
+                .unwrap();
+            assert!(enabled);
+            let orphan = conn.execute(
+                "INSERT INTO sessions (id, user_id, refresh_jti, expires_at) VALUES ('s', 7, '', 0)",
+                [],
+            );
+            assert!(orphan.is_err());
+        }
+    }
+}
@@ -0,0 +1,445 @@
+// ERRORS
+// Every handler returns 'Result<_, AppError>'. An 'AppError' is sent to the client as a
+// "problem details" document (RFC 7807), with the 'application/problem+json' content type :
+//   {
+//     "type": "urn:rest-api-axum:problem:not_found",
+//     "title": "Resource not found",
+//     "status": 404,
+//     "code": "not_found",
+//     "detail": "user 42 does not exist",
+//     "errors": [{ "field": "email", "message": "..." }]
+//   }
+// 'code' is stable : clients can rely on it, while 'title' and 'detail' are meant for humans.
+// The cause of an internal error is logged on the server, but only sent to the client when the
+// server runs in the 'development' environment.
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::{
+    extract::rejection::{
+        JsonDataError, JsonRejection, PathRejection, QueryRejection, StringRejection,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::db::DbError;
+
+const PROBLEM_TYPE_PREFIX: &str = "urn:rest-api-axum:problem:";
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+// whether the causes of internal errors are sent to the clients, see 'expose_internal_errors'
+static EXPOSE_INTERNAL_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// Sends the causes of internal errors to the clients : only for development, they may contain
+// paths, queries or any other detail of the implementation.
+pub fn expose_internal_errors(expose: bool) {
+    EXPOSE_INTERNAL_ERRORS.store(expose, Ordering::Relaxed);
+}
+
+// The stable error codes, each one with its HTTP status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // the request is invalid, without more precise code
+    BadRequest,
+    // the body is not valid JSON
+    MalformedBody,
+    // the body is valid JSON but does not match the expected fields or types
+    InvalidBody,
+    // a parameter of the path does not match the expected type
+    InvalidPath,
+    // a parameter of the query string is unknown or invalid
+    InvalidQuery,
+    UnsupportedMediaType,
+    PayloadTooLarge,
+    // no valid credentials or token were given
+    Unauthorized,
+    // the client is known, but not allowed to do this
+    Forbidden,
+    // a change sent with a session cookie, without its CSRF token, see 'cookies.rs'
+    InvalidCsrfToken,
+    NotFound,
+    MethodNotAllowed,
+    // the resource already exists, or is in a state that does not allow the change
+    Conflict,
+    // an 'Idempotency-Key' sent again with another request, see 'idempotency.rs'
+    IdempotencyKeyReused,
+    // the resource changed since the client read it, see 'etag.rs'
+    PreconditionFailed,
+    // a change without the 'If-Match' header, see 'etag.rs'
+    PreconditionRequired,
+    // the client has used its quota of requests, see 'ratelimit.rs'
+    TooManyRequests,
+    // any failure of the server itself, its cause is never sent in production
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest
+            | ErrorCode::MalformedBody
+            | ErrorCode::InvalidPath
+            | ErrorCode::InvalidQuery => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidBody | ErrorCode::IdempotencyKeyReused => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::InvalidCsrfToken => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::MalformedBody => "malformed_body",
+            ErrorCode::InvalidBody => "invalid_body",
+            ErrorCode::InvalidPath => "invalid_path",
+            ErrorCode::InvalidQuery => "invalid_query",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::InvalidCsrfToken => "invalid_csrf_token",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::MethodNotAllowed => "method_not_allowed",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::IdempotencyKeyReused => "idempotency_key_reused",
+            ErrorCode::PreconditionFailed => "precondition_failed",
+            ErrorCode::PreconditionRequired => "precondition_required",
+            ErrorCode::TooManyRequests => "too_many_requests",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "Bad request",
+            ErrorCode::MalformedBody => "Malformed JSON body",
+            ErrorCode::InvalidBody => "Invalid body",
+            ErrorCode::InvalidPath => "Invalid path parameter",
+            ErrorCode::InvalidQuery => "Invalid query parameter",
+            ErrorCode::UnsupportedMediaType => "Unsupported media type",
+            ErrorCode::PayloadTooLarge => "Payload too large",
+            ErrorCode::Unauthorized => "Authentication required",
+            ErrorCode::Forbidden => "Access denied",
+            ErrorCode::InvalidCsrfToken => "Invalid CSRF token",
+            ErrorCode::NotFound => "Resource not found",
+            ErrorCode::MethodNotAllowed => "Method not allowed",
+            ErrorCode::Conflict => "Conflict",
+            ErrorCode::IdempotencyKeyReused => "Idempotency key reused",
+            ErrorCode::PreconditionFailed => "Precondition failed",
+            ErrorCode::PreconditionRequired => "Precondition required",
+            ErrorCode::TooManyRequests => "Too many requests",
+            ErrorCode::Internal => "Internal server error",
+        }
+    }
+}
+
+// An error about a single field of the request.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct AppError {
+    code: ErrorCode,
+    detail: Option<String>,
+    errors: Vec<FieldError>,
+    // cause of an internal error, never sent in production
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl AppError {
+    pub fn new(code: ErrorCode) -> Self {
+        Self {
+            code,
+            detail: None,
+            errors: Vec::new(),
+            source: None,
+        }
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound).with_detail(detail)
+    }
+
+    pub fn unauthorized(detail: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unauthorized).with_detail(detail)
+    }
+
+    pub fn forbidden(detail: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Forbidden).with_detail(detail)
+    }
+
+    pub fn internal(source: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self {
+            source: Some(Box::new(source)),
+            ..Self::new(ErrorCode::Internal)
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    // A copy of a client error, 'None' for an internal error whose source cannot be copied.
+    pub fn try_clone(&self) -> Option<Self> {
+        self.source.is_none().then(|| Self {
+            code: self.code,
+            detail: self.detail.clone(),
+            errors: self.errors.clone(),
+            source: None,
+        })
+    }
+
+    pub fn with_field(mut self, field: impl Into<String>, message: impl Into<String>) -> Self {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+        self
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code.as_str())?;
+        if let Some(detail) = &self.detail {
+            write!(f, ": {detail}")?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ": {source}")?;
+        }
+        Ok(())
+    }
+}
+
+// The document sent to the client.
+#[derive(Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(example = "urn:rest-api-axum:problem:not_found")]
+    problem_type: String,
+    #[schema(example = "Resource not found")]
+    title: &'static str,
+    #[schema(example = 404)]
+    status: u16,
+    // stable code of the error, see 'ErrorCode'
+    #[schema(example = "not_found")]
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.code.status();
+        if self.source.is_some() {
+            tracing::error!(error = %self, "internal error");
+        }
+        let detail = match &self.source {
+            Some(source) if EXPOSE_INTERNAL_ERRORS.load(Ordering::Relaxed) => {
+                Some(source.to_string())
+            }
+            _ => self.detail,
+        };
+        let problem = Problem {
+            problem_type: format!("{PROBLEM_TYPE_PREFIX}{}", self.code.as_str()),
+            title: self.code.title(),
+            status: status.as_u16(),
+            code: self.code.as_str(),
+            detail,
+            errors: self.errors,
+        };
+        // serializing this structure cannot fail : it only holds strings and integers
+        let body = serde_json::to_vec(&problem).unwrap_or_default();
+        let mut response =
+            (status, [(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)], body).into_response();
+        // tells the client how to authenticate (RFC 6750)
+        if self.code == ErrorCode::Unauthorized {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+        }
+        response
+    }
+}
+
+impl From<DbError> for AppError {
+    fn from(err: DbError) -> Self {
+        AppError::internal(err)
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        let code = match &rejection {
+            JsonRejection::JsonDataError(err) => return invalid_body(err),
+            JsonRejection::JsonSyntaxError(_) => ErrorCode::MalformedBody,
+            JsonRejection::MissingJsonContentType(_) => ErrorCode::UnsupportedMediaType,
+            _ if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            _ => ErrorCode::BadRequest,
+        };
+        AppError::new(code).with_detail(rejection.body_text())
+    }
+}
+
+// The JSON extractor of axum uses 'serde_path_to_error' : its error, found among the sources of
+// the rejection, gives the field at fault.
+fn invalid_body(rejection: &JsonDataError) -> AppError {
+    let mut source = std::error::Error::source(rejection);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>() {
+            let error = AppError::new(ErrorCode::InvalidBody)
+                .with_detail("the body does not match the expected fields");
+            // a missing field is reported on the object containing it, '.' for the whole body :
+            // the message names the field in this case
+            return match err.path().to_string().as_str() {
+                "." => error.with_detail(err.inner().to_string()),
+                path => error.with_field(path, err.inner().to_string()),
+            };
+        }
+        source = err.source();
+    }
+    AppError::new(ErrorCode::InvalidBody).with_detail(rejection.body_text())
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        AppError::new(ErrorCode::InvalidPath).with_detail(rejection.body_text())
+    }
+}
+
+impl From<StringRejection> for AppError {
+    fn from(rejection: StringRejection) -> Self {
+        let code = if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            ErrorCode::PayloadTooLarge
+        } else {
+            ErrorCode::BadRequest
+        };
+        AppError::new(code).with_detail(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::new(ErrorCode::InvalidQuery).with_detail(rejection.body_text())
+    }
+}
+
+// Answer for the routes that do not exist.
+pub async fn not_found() -> AppError {
+    AppError::not_found("no route matches this path")
+}
+
+// Answer for the routes that exist, but not with this method.
+pub async fn method_not_allowed() -> AppError {
+    AppError::new(ErrorCode::MethodNotAllowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the status, the content type and the problem document of the error
+    async fn sent(err: AppError) -> (StatusCode, String, serde_json::Value) {
+        let response = err.into_response();
+        let status = response.status();
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        let content_type = content_type.to_owned();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        let body = serde_json::from_slice(&body.await.unwrap()).unwrap();
+        (status, content_type, body)
+    }
+
+    #[tokio::test]
+    async fn sends_each_code_with_its_status_as_a_problem() {
+        let codes = [
+            (ErrorCode::BadRequest, 400, "bad_request"),
+            (ErrorCode::MalformedBody, 400, "malformed_body"),
+            (ErrorCode::InvalidBody, 422, "invalid_body"),
+            (ErrorCode::InvalidPath, 400, "invalid_path"),
+            (ErrorCode::InvalidQuery, 400, "invalid_query"),
+            (
+                ErrorCode::UnsupportedMediaType,
+                415,
+                "unsupported_media_type",
+            ),
+            (ErrorCode::PayloadTooLarge, 413, "payload_too_large"),
+            (ErrorCode::Unauthorized, 401, "unauthorized"),
+            (ErrorCode::Forbidden, 403, "forbidden"),
+            (ErrorCode::InvalidCsrfToken, 403, "invalid_csrf_token"),
+            (ErrorCode::NotFound, 404, "not_found"),
+            (ErrorCode::MethodNotAllowed, 405, "method_not_allowed"),
+            (ErrorCode::Conflict, 409, "conflict"),
+            (
+                ErrorCode::IdempotencyKeyReused,
+                422,
+                "idempotency_key_reused",
+            ),
+            (ErrorCode::PreconditionFailed, 412, "precondition_failed"),
+            (
+                ErrorCode::PreconditionRequired,
+                428,
+                "precondition_required",
+            ),
+            (ErrorCode::TooManyRequests, 429, "too_many_requests"),
+            (ErrorCode::Internal, 500, "internal"),
+        ];
+        for (code, status, name) in codes {
+            let err = AppError::new(code)
+                .with_detail("detail")
+                .with_field("email", "message");
+            let (answered, content_type, problem) = sent(err).await;
+            assert_eq!(answered.as_u16(), status, "{name}");
+            assert_eq!(content_type, PROBLEM_CONTENT_TYPE);
+            assert_eq!(
+                problem,
+                serde_json::json!({
+                    "type": format!("urn:rest-api-axum:problem:{name}"),
+                    "title": code.title(),
+                    "status": status,
+                    "code": name,
+                    "detail": "detail",
+                    "errors": [{ "field": "email", "message": "message" }],
+                })
+            );
+        }
+        // the optional members are left out
+        let (_, _, problem) = sent(AppError::new(ErrorCode::NotFound)).await;
+        assert_eq!(problem.as_object().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn hides_the_cause_of_an_internal_error_unless_exposed() {
+        let internal = || AppError::internal(std::io::Error::other("disk /var/lib/users.db full"));
+
+        let (status, _, problem) = sent(internal()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(problem.get("detail"), None);
+        assert!(internal().try_clone().is_none());
+
+        expose_internal_errors(true);
+        let (_, _, problem) = sent(internal()).await;
+        expose_internal_errors(false);
+        assert_eq!(problem["detail"], "disk /var/lib/users.db full");
+    }
+}
@@ -0,0 +1,190 @@
+// CONDITIONAL REQUESTS
+// Each user has a version, increased by every change of the user. It is sent as the 'ETag' of the
+// user ("3" for the version 3), and the clients send it back in the headers of RFC 9110 :
+//   If-None-Match  a 'GET' answers '304 Not Modified', without the body, when the client already
+//                  has the current version
+//   If-Match       required to change a user ('PUT', 'PATCH' and 'DELETE') : the change is only
+//                  applied to the version read by the client, else '412 Precondition Failed'.
+//                  Without it, the change is refused with '428 Precondition Required'.
+// Two clients changing the same user cannot overwrite each other unknowingly : the second one is
+// refused, and has to read the user again before changing it.
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::error::{AppError, ErrorCode};
+
+pub fn etag(version: u64) -> HeaderValue {
+    // digits between quotes are always a valid header value
+    HeaderValue::from_str(&format!("\"{version}\"")).unwrap_or(HeaderValue::from_static("\"\""))
+}
+
+// The entity tags of an 'If-Match' or 'If-None-Match' header.
+#[derive(Debug, Clone)]
+enum Tags {
+    // '*' : any version
+    Any,
+    // the tags as sent, quotes and weak prefix 'W/' included
+    List(Vec<String>),
+}
+
+impl Tags {
+    fn from_headers(headers: &HeaderMap, name: HeaderName) -> Option<Self> {
+        let mut tags = Vec::new();
+        for value in headers.get_all(name) {
+            for tag in value.to_str().unwrap_or_default().split(',') {
+                match tag.trim() {
+                    "" => {}
+                    "*" => return Some(Tags::Any),
+                    tag => tags.push(tag.to_owned()),
+                }
+            }
+        }
+        (!tags.is_empty()).then_some(Tags::List(tags))
+    }
+
+    // With the weak comparison, 'W/"3"' matches the version 3 too. The strong comparison, used
+    // before a change, only matches '"3"'.
+    fn matches(&self, version: u64, weak: bool) -> bool {
+        let current = format!("\"{version}\"");
+        match self {
+            Tags::Any => true,
+            Tags::List(tags) => tags.iter().any(|tag| match tag.strip_prefix("W/") {
+                Some(tag) => weak && *tag == current,
+                None => *tag == current,
+            }),
+        }
+    }
+}
+
+// The required 'If-Match' header of a change.
+#[derive(Debug, Clone)]
+pub struct IfMatch(Tags);
+
+impl IfMatch {
+    pub fn matches(&self, version: u64) -> bool {
+        self.0.matches(version, false)
+    }
+
+    // 'If-Match: *', for the tests of the changes
+    #[cfg(test)]
+    pub fn any() -> Self {
+        IfMatch(Tags::Any)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, AppError> {
+        Tags::from_headers(&parts.headers, header::IF_MATCH)
+            .map(IfMatch)
+            .ok_or_else(|| {
+                AppError::new(ErrorCode::PreconditionRequired)
+                    .with_detail("send the ETag of the version to change in If-Match")
+            })
+    }
+}
+
+// The optional 'If-None-Match' header of a read.
+#[derive(Debug, Clone)]
+pub struct IfNoneMatch(Option<Tags>);
+
+impl IfNoneMatch {
+    pub fn matches(&self, version: u64) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|tags| tags.matches(version, true))
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for IfNoneMatch {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, AppError> {
+        Ok(IfNoneMatch(Tags::from_headers(
+            &parts.headers,
+            header::IF_NONE_MATCH,
+        )))
+    }
+}
+
+// The answer to a read when the client already has the current version.
+pub fn not_modified(version: u64) -> Response {
+    (StatusCode::NOT_MODIFIED, [(header::ETAG, etag(version))]).into_response()
+}
+
+// The answer to a change of another version than the current one.
+pub fn precondition_failed(version: u64) -> AppError {
+    AppError::new(ErrorCode::PreconditionFailed).with_detail(format!(
+        "the current version is \"{version}\", read it again before changing it"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    // the version of the resource of 'app'
+    const VERSION: u64 = 3;
+
+    // A resource read and changed like a user.
+    fn app() -> Router {
+        Router::new().route(
+            "/",
+            get(|if_none_match: IfNoneMatch| async move {
+                match if_none_match.matches(VERSION) {
+                    true => not_modified(VERSION),
+                    false => ([(header::ETAG, etag(VERSION))], "resource").into_response(),
+                }
+            })
+            .patch(|if_match: IfMatch| async move {
+                match if_match.matches(VERSION) {
+                    true => Ok(StatusCode::NO_CONTENT),
+                    false => Err(precondition_failed(VERSION)),
+                }
+            }),
+        )
+    }
+
+    async fn send(method: &str, condition: Option<(HeaderName, &str)>) -> StatusCode {
+        let mut request = Request::builder().method(method).uri("/");
+        if let Some((name, value)) = condition {
+            request = request.header(name, value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        app().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn answers_a_read_of_the_current_version_with_not_modified() {
+        assert_eq!(send("GET", None).await, StatusCode::OK);
+        for tags in ["\"3\"", "\"1\", W/\"3\"", "*"] {
+            let status = send("GET", Some((header::IF_NONE_MATCH, tags))).await;
+            assert_eq!(status, StatusCode::NOT_MODIFIED, "{tags}");
+        }
+        let status = send("GET", Some((header::IF_NONE_MATCH, "\"2\""))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn applies_a_change_only_to_the_current_version() {
+        for tags in ["\"3\"", "\"1\", \"3\"", "*"] {
+            let status = send("PATCH", Some((header::IF_MATCH, tags))).await;
+            assert_eq!(status, StatusCode::NO_CONTENT, "{tags}");
+        }
+        // a weak tag does not match before a change
+        for tags in ["\"2\"", "W/\"3\"", "3"] {
+            let status = send("PATCH", Some((header::IF_MATCH, tags))).await;
+            assert_eq!(status, StatusCode::PRECONDITION_FAILED, "{tags}");
+        }
+        assert_eq!(send("PATCH", None).await, StatusCode::PRECONDITION_REQUIRED);
+        let status = send("PATCH", Some((header::IF_MATCH, " , "))).await;
+        assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+    }
+}
@@ -0,0 +1,339 @@
+// EVENTS
+//   GET /users/events  stream of the changes of the users, as Server-Sent Events
+// Every change made through '/users' is sent to the connected clients as an event :
+//   id: 7
+//   event: user.updated
+//   data: {"id":1,"active":false,"username":"sam","email":"sam.gamegie@shire.com","sign_in_count":1}
+// 'user.created' and 'user.updated' carry the user, 'user.deleted' only its identifier.
+// The last events are kept in memory ('events.backlog'). A client reconnecting with the
+// 'Last-Event-ID' header, as browsers do, first receives the events it missed, if they are still
+// kept. A client that missed events no longer kept, or too slow to receive them, is told so
+// before the events kept, or before its stream ends, and reloads '/users' :
+//   event: events.lost
+//   data: {"detail":"some events were not kept, reload the users"} A comment is sent when nothing happens for 'events.keep_alive_secs', so the proxies do not
+// close an idle stream. The streams end when the server shuts down.
+// Reading requires the 'users:read' scope.
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use futures_util::{stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+
+use crate::auth::{RequireScope, Scope};
+use crate::config::EventsConfig;
+use crate::error::{AppError, ErrorCode, Problem};
+use crate::state::AppState;
+
+const LAST_EVENT_ID: &str = "last-event-id";
+const LOST_EVENT: &str = "events.lost";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl UserEventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            UserEventKind::Created => "user.created",
+            UserEventKind::Updated => "user.updated",
+            UserEventKind::Deleted => "user.deleted",
+        }
+    }
+}
+
+// An event, its data serialized once for all the clients.
+#[derive(Debug)]
+struct UserEvent {
+    id: u64,
+    kind: UserEventKind,
+    data: String,
+}
+
+// The last events, numbered from 1 since the start of the server.
+struct Backlog {
+    last_id: u64,
+    events: VecDeque<Arc<UserEvent>>,
+    capacity: usize,
+}
+
+struct Inner {
+    // locked while an event is both kept and sent, so a new client never misses nor repeats one
+    backlog: Mutex<Backlog>,
+    sender: broadcast::Sender<Arc<UserEvent>>,
+}
+
+// What a client resuming after an event receives.
+struct Subscription {
+    missed: Vec<Arc<UserEvent>>,
+    lost: bool,
+    receiver: broadcast::Receiver<Arc<UserEvent>>,
+}
+
+// The changes of the users, and the clients following them.
+#[derive(Clone)]
+pub struct UserEvents {
+    inner: Arc<Inner>,
+    // 'true' once the server shuts down
+    closing: watch::Sender<bool>,
+    keep_alive: Duration,
+}
+
+impl UserEvents {
+    pub fn new(config: &EventsConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                backlog: Mutex::new(Backlog {
+                    last_id: 0,
+                    events: VecDeque::with_capacity(config.backlog),
+                    capacity: config.backlog,
+                }),
+                sender: broadcast::channel(config.backlog).0,
+            }),
+            closing: watch::channel(false).0,
+            keep_alive: config.keep_alive(),
+        }
+    }
+
+    // Keeps the event and sends it to the connected clients.
+    pub fn publish(&self, kind: UserEventKind, data: &impl Serialize) {
+        // serializing a user cannot fail
+        let data = serde_json::to_string(data).unwrap_or_default();
+        let mut backlog = self
+            .inner
+            .backlog
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        backlog.last_id += 1;
+        let event = Arc::new(UserEvent {
+            id: backlog.last_id,
+            kind,
+            data,
+        });
+        if backlog.events.len() == backlog.capacity {
+            backlog.events.pop_front();
+        }
+        backlog.events.push_back(event.clone());
+        // no client is connected
+        let _ = self.inner.sender.send(event);
+    }
+
+    // The events kept after 'last_id', whether some events after it are no longer kept, and the
+    // receiver of the next ones.
+    fn subscribe(&self, last_id: Option<u64>) -> Subscription {
+        let backlog = self
+            .inner
+            .backlog
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        // the identifier of the first event kept, or of the next one
+        let first_id = backlog
+            .events
+            .front()
+            .map_or(backlog.last_id + 1, |event| event.id);
+        let (missed, lost) = match last_id {
+            None => (Vec::new(), false),
+            Some(last_id) if last_id <= backlog.last_id => (
+                backlog
+                    .events
+                    .iter()
+                    .filter(|event| event.id > last_id)
+                    .cloned()
+                    .collect(),
+                last_id + 1 < first_id,
+            ),
+            // an identifier given before a restart of the server : every event kept is new, the
+            // ones published before the restart are lost
+            Some(_) => (backlog.events.iter().cloned().collect(), true),
+        };
+        Subscription {
+            missed,
+            lost,
+            receiver: self.inner.sender.subscribe(),
+        }
+    }
+
+    // Ends every stream, for the shutdown of the server.
+    pub fn close(&self) {
+        self.closing.send_replace(true);
+    }
+}
+
+#[cfg(test)]
+impl UserEvents {
+    // The kind and the data of the events kept, oldest first, for the tests.
+    pub fn kept(&self) -> Vec<(UserEventKind, serde_json::Value)> {
+        let backlog = self.inner.backlog.lock().unwrap();
+        backlog
+            .events
+            .iter()
+            .map(|event| (event.kind, serde_json::from_str(&event.data).unwrap()))
+            .collect()
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/users/events", get(user_events).require(Scope::UsersRead))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/events",
+    tag = "users",
+    security(("bearer" = ["users:read"])),
+    params(("Last-Event-ID" = Option<u64>, Header, description = "Identifier of the last event received, to receive the events missed since")),
+    responses(
+        (status = 200, description = "Stream of the events 'user.created', 'user.updated' and 'user.deleted', and 'events.lost' when events were missed and are no longer kept", content_type = "text/event-stream"),
+        (status = 400, description = "Invalid 'Last-Event-ID'", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn user_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let last_id = match headers.get(LAST_EVENT_ID) {
+        None => None,
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    AppError::new(ErrorCode::BadRequest)
+                        .with_detail("Last-Event-ID: not an event identifier")
+                })?,
+        ),
+    };
+    let events = state.events;
+    let Subscription {
+        missed,
+        lost,
+        receiver,
+    } = events.subscribe(last_id);
+    let closing = events.closing.subscribe();
+
+    // 'None' once the client was told it is too slow
+    let next = stream::unfold(Some((receiver, closing)), |following| async move {
+        let (mut receiver, mut closing) = following?;
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => Some((event_of(&event), Some((receiver, closing)))),
+                // ending the stream makes the client reconnect, then receive what is still
+                // kept
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(missed, "events client too slow, stream ended");
+                    Some((lost_event(), None))
+                }
+                Err(broadcast::error::RecvError::Closed) => None,
+            },
+            // the guard of the value is released before the select ends, to keep the
+            // future 'Send'
+            _ = async { closing.wait_for(|closing| *closing).await.map(|_| ()) } => None,
+        }
+    });
+    let stream = stream::iter(lost.then(lost_event))
+        .chain(stream::iter(missed).map(|event| event_of(&event)))
+        .chain(next)
+        .map(Ok);
+    Ok(Sse::new(stream).keep_alive(
+        KeepAlive::new()
+            .interval(events.keep_alive)
+            .text("keep-alive"),
+    ))
+}
+
+// the frame of an event, the same for every client
+fn event_of(event: &UserEvent) -> Event {
+    Event::default()
+        .id(event.id.to_string())
+        .event(event.kind.as_str())
+        .data(&event.data)
+}
+
+// the event telling a client it missed events that are no longer kept
+fn lost_event() -> Event {
+    Event::default()
+        .event(LOST_EVENT)
+        .data(r#"{"detail":"some events were not kept, reload the users"}"#)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn with_backlog(backlog: usize) -> UserEvents {
+        UserEvents::new(&EventsConfig {
+            backlog,
+            ..Default::default()
+        })
+    }
+
+    fn publish(events: &UserEvents, count: u64) {
+        for id in 0..count {
+            events.publish(UserEventKind::Deleted, &serde_json::json!({ "id": id }));
+        }
+    }
+
+    // the identifiers of the events missed, and whether some are lost
+    fn resume(events: &UserEvents, last_id: Option<u64>) -> (Vec<u64>, bool) {
+        let subscription = events.subscribe(last_id);
+        let missed = subscription.missed.iter().map(|event| event.id).collect();
+        (missed, subscription.lost)
+    }
+
+    #[test]
+    fn replays_the_events_missed_since_the_last_one_received() {
+        let events = with_backlog(8);
+        publish(&events, 3);
+
+        assert_eq!(resume(&events, None), (vec![], false));
+        assert_eq!(resume(&events, Some(1)), (vec![2, 3], false));
+        assert_eq!(resume(&events, Some(3)), (vec![], false));
+    }
+
+    #[test]
+    fn keeps_the_last_events_only_and_tells_when_others_are_lost() {
+        let events = with_backlog(2);
+        publish(&events, 5);
+
+        assert_eq!(events.kept().len(), 2);
+        assert_eq!(resume(&events, Some(3)), (vec![4, 5], false));
+        assert_eq!(resume(&events, Some(2)), (vec![4, 5], true));
+        // an identifier given before a restart
+        assert_eq!(resume(&events, Some(9)), (vec![4, 5], true));
+    }
+
+    #[tokio::test]
+    async fn tells_a_client_too_slow_that_it_lost_events() {
+        let mut config = Config::default();
+        config.events.backlog = 2;
+        let state = AppState::test(&config);
+        let events = state.events.clone();
+        let stream = user_events(State(state), HeaderMap::new());
+        let response = stream.await.unwrap().into_response();
+        // the client reads nothing while more events than the backlog are published
+        publish(&events, 3);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        let body = tokio::time::timeout(Duration::from_secs(5), body);
+        let body = body.await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&body).ends_with(&format!(
+            "event: {LOST_EVENT}\ndata: {{\"detail\":\"some events were not kept, reload the users\"}}\n\n"
+        )));
+    }
+}
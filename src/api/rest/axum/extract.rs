@@ -0,0 +1,83 @@
+// EXTRACTORS
+// Same extractors as axum, but their rejections are turned into an 'AppError', so an invalid
+// request is answered with a problem document like any other error.
+use axum::{
+    extract::{FromRequest, FromRequestParts, Request},
+    response::{IntoResponse, Response},
+};
+
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+use crate::error::AppError;
+
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(AppError))]
+pub struct Json<T>(pub T);
+
+impl<T: serde::Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+pub struct Path<T>(pub T);
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+pub struct Query<T>(pub T);
+
+// the integers of SQLite are signed : no row has a larger identifier
+pub const MAX_ID: u64 = i64::MAX as u64;
+
+// The identifier of a row in a path, such as '/users/{id}'. A larger one than 'MAX_ID' is refused
+// like any other invalid path, instead of failing in the database.
+#[derive(Debug, Clone, Copy)]
+pub struct Id(pub u64);
+
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u64::deserialize(deserializer)? {
+            id if id <= MAX_ID => Ok(Id(id)),
+            _ => Err(D::Error::custom(format!(
+                "identifiers are integers between 0 and {MAX_ID}"
+            ))),
+        }
+    }
+}
+
+// The body as UTF-8 text.
+pub struct Text(pub String);
+
+impl<S: Send + Sync> FromRequest<S> for Text {
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Text(String::from_request(request, state).await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, StatusCode},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_a_text_that_is_not_utf8_with_a_problem() {
+        let request = Request::new(Body::from(vec![b'i', b'n', 0xff]));
+        let Err(err) = Text::from_request(request, &()).await else {
+            panic!("the body is not UTF-8");
+        };
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// HEALTH
+//   GET /healthz  liveness : the process is running and answers requests
+//   GET /readyz   readiness : the server can do its job, every dependency is checked and the
+//                 result of each check is part of the answer. '503' when one of them fails.
+// The 'healthcheck' command of the binary probes these routes on the local server, so the
+// container image can check the health of the server without installing 'curl'.
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use rustls::pki_types::ServerName;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsConnector;
+use utoipa::ToSchema;
+
+use crate::extract::Json;
+use crate::state::AppState;
+
+// maximum duration of a single readiness check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, ToSchema)]
+pub struct Health {
+    status: &'static str,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    #[schema(example = "up")]
+    status: &'static str,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "The process is running", body = Health)),
+)]
+async fn healthz() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Every dependency is available", body = Readiness),
+        (status = 503, description = "A dependency is not available", body = Readiness),
+    ),
+)]
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let mut checks = BTreeMap::new();
+    checks.insert("database", check_database(&state).await);
+
+    let ready = checks.values().all(Check::is_up);
+    let (status, readiness) = match ready {
+        true => (StatusCode::OK, "ready"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    };
+    (
+        status,
+        Json(Readiness {
+            status: readiness,
+            checks,
+        }),
+    )
+}
+
+// The database answers a trivial query in time.
+async fn check_database(state: &AppState) -> Check {
+    let started = Instant::now();
+    let query = state.db.call(|conn| {
+        conn.query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    });
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, query).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some(format!("no answer after {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    Check {
+        status: if error.is_none() { "up" } else { "down" },
+        duration_ms: started.elapsed().as_millis(),
+        error,
+    }
+}
+
+// Sends 'GET <path>' to the server listening on 'addr' and returns the status code of the answer.
+// A plain HTTP/1.1 request is written by hand : it is enough for a probe and needs no client.
+// The request is sent over TLS when a connector is given.
+pub async fn probe(
+    addr: SocketAddr,
+    path: &str,
+    tls: Option<TlsConnector>,
+    timeout: Duration,
+) -> Result<u16, String> {
+    let addr = local(addr);
+    let request = async {
+        let stream = tokio::net::TcpStream::connect(addr)
+            .await
+            .map_err(|err| format!("cannot connect to {addr}: {err}"))?;
+        match tls {
+            None => send(stream, path).await,
+            Some(connector) => {
+                let stream = connector
+                    .connect(ServerName::IpAddress(addr.ip().into()), stream)
+                    .await
+                    .map_err(|err| format!("TLS handshake with {addr} failed: {err}"))?;
+                send(stream, path).await
+            }
+        }
+    };
+    tokio::time::timeout(timeout, request)
+        .await
+        .map_err(|_| format!("no answer after {}s", timeout.as_secs()))?
+}
+
+async fn send(mut stream: impl AsyncRead + AsyncWrite + Unpin, path: &str) -> Result<u16, String> {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|err| format!("cannot send the request: {err}"))?;
+    let mut response = Vec::new();
+    // a TLS server may close the connection without 'close_notify' : the answer is still usable
+    if let Err(err) = stream.read_to_end(&mut response).await {
+        if response.is_empty() {
+            return Err(format!("cannot read the answer: {err}"));
+        }
+    }
+    status_code(&response).ok_or_else(|| String::from("the answer is not HTTP"))
+}
+
+// A server listening on every interface ('0.0.0.0' or '::') is probed on the loopback.
+fn local(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+// reads the code of the status line : 'HTTP/1.1 200 OK'
+fn status_code(response: &[u8]) -> Option<u16> {
+    let line = response.split(|byte| *byte == b'\r').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.split(' ');
+    parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::config::Config;
+
+    async fn ready(state: &AppState) -> (StatusCode, serde_json::Value) {
+        let response = readyz(State(state.clone())).await.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        (
+            status,
+            serde_json::from_slice(&body.await.unwrap()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn answers_unavailable_when_the_database_does_not_answer() {
+        let state = AppState::test(&Config::default());
+        let (status, readiness) = ready(&state).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(readiness["checks"]["database"]["status"], "up");
+
+        // the only connection of the in-memory database is busy for longer than a check
+        let (taken, connection_taken) = tokio::sync::oneshot::channel();
+        let db = state.db.clone();
+        let busy = tokio::spawn(async move {
+            db.call(|_| {
+                let _ = taken.send(());
+                std::thread::sleep(CHECK_TIMEOUT + Duration::from_secs(1));
+                Ok(())
+            })
+            .await
+        });
+        connection_taken.await.unwrap();
+        let (status, readiness) = ready(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readiness["status"], "unavailable");
+        assert_eq!(readiness["checks"]["database"]["status"], "down");
+        assert_eq!(
+            readiness["checks"]["database"]["error"],
+            "no answer after 2s"
+        );
+        busy.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn reads_the_status_of_an_answer() {
+        assert_eq!(
+            status_code(b"HTTP/1.1 503 Service Unavailable\r\n\r\n"),
+            Some(503)
+        );
+        assert_eq!(status_code(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+        let any: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+        assert_eq!(local(any), "127.0.0.1:8080".parse().unwrap());
+    }
+}
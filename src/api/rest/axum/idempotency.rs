@@ -0,0 +1,499 @@
+// IDEMPOTENCY
+// A client retrying a request it got no answer for (timeout, lost connection) may apply it twice,
+// creating two users instead of one. With an 'Idempotency-Key' header, a unique value chosen by the
+// client for each operation (such as a UUID), the request is applied once :
+//   - the first request with the key runs, and its answer is stored with the fingerprint of the
+//     request (method, path, query and body)
+//   - a retry with the same key and the same request gets the stored answer, with the header
+//     'Idempotent-Replayed: true', without running the request again
+//   - the same key with another request is refused with '422 Unprocessable Entity'
+//   - a retry while the first request is still running is refused with '409 Conflict'
+// The keys belong to their client (API key or user) and expire after 'idempotency.ttl_secs'.
+// A '5xx' answer is not stored, so the request can be retried, nor a '412' or '428' : they only
+// depend on the 'If-Match' header, not part of the fingerprint, and the retry with the current
+// version has to run. Only the methods changing something
+// (POST, PUT, PATCH and DELETE) of an authenticated client are concerned : the key is ignored
+// otherwise.
+// The credentials are never stored : the 'Set-Cookie' headers are dropped, and only the status and
+// the 'Location' of the answers of the routes returning credentials (a new API key, tokens, a CSRF
+// token) are kept.
+use axum::{
+    body::{self, Body, Bytes},
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::LengthLimitError;
+use ring::digest::{Context, SHA256};
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+
+use crate::auth::{now, Principal};
+use crate::config::IdempotencyConfig;
+use crate::db::{Db, DbError};
+use crate::error::{AppError, ErrorCode};
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+// routes answering with credentials
+const CREDENTIAL_ROUTES: [&str; 4] = ["/api-keys", "/auth/login", "/auth/refresh", "/auth/session"];
+
+// The stored answers, by client and key.
+#[derive(Clone)]
+pub struct Idempotency {
+    db: Db,
+    enabled: bool,
+    ttl_secs: u64,
+}
+
+// The answer of the first request with a key.
+struct Stored {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl IntoResponse for Stored {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in self.headers {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::try_from(name), HeaderValue::try_from(value))
+            {
+                response.headers_mut().append(name, value);
+            }
+        }
+        response.headers_mut().insert(
+            HeaderName::from_static(IDEMPOTENT_REPLAYED),
+            HeaderValue::from_static("true"),
+        );
+        response
+    }
+}
+
+// What is known of a key when a request comes with it.
+enum Begin {
+    // first request with the key : it is now running
+    New,
+    // the first request is still running
+    Running,
+    // the key was used for another request
+    Mismatch,
+    Replay(Stored),
+}
+
+impl Idempotency {
+    pub fn new(db: Db, config: &IdempotencyConfig) -> Self {
+        Self {
+            db,
+            enabled: config.enabled,
+            ttl_secs: config.ttl_secs,
+        }
+    }
+
+    // Marks the key as running, unless it is already known.
+    async fn begin(
+        &self,
+        client: String,
+        key: String,
+        fingerprint: String,
+    ) -> Result<Begin, DbError> {
+        let ttl_secs = self.ttl_secs;
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let now = now();
+                // the expired keys are of no use anymore
+                tx.execute("DELETE FROM idempotency_keys WHERE expires_at < ?1", [now])?;
+                let known = tx
+                    .query_row(
+                        "SELECT fingerprint, status, headers, body FROM idempotency_keys
+                         WHERE client = ?1 AND key = ?2",
+                        params![client, key],
+                        |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                row.get::<_, Option<u16>>(1)?,
+                                row.get::<_, Option<String>>(2)?,
+                                row.get::<_, Option<Vec<u8>>>(3)?,
+                            ))
+                        },
+                    )
+                    .optional()?;
+                let begin = match known {
+                    None => {
+                        tx.execute(
+                            "INSERT INTO idempotency_keys (client, key, fingerprint, expires_at)
+                             VALUES (?1, ?2, ?3, ?4)",
+                            params![client, key, fingerprint, now + ttl_secs],
+                        )?;
+                        Begin::New
+                    }
+                    Some((known, ..)) if known != fingerprint => Begin::Mismatch,
+                    Some((_, Some(status), headers, body)) => Begin::Replay(Stored {
+                        status,
+                        headers: headers
+                            .and_then(|headers| serde_json::from_str(&headers).ok())
+                            .unwrap_or_default(),
+                        body: body.unwrap_or_default(),
+                    }),
+                    Some(_) => Begin::Running,
+                };
+                tx.commit()?;
+                Ok(begin)
+            })
+            .await
+    }
+
+    async fn complete(&self, client: String, key: String, stored: Stored) -> Result<(), DbError> {
+        // serializing pairs of strings cannot fail
+        let headers = serde_json::to_string(&stored.headers).unwrap_or_default();
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE idempotency_keys SET status = ?3, headers = ?4, body = ?5
+                     WHERE client = ?1 AND key = ?2",
+                    params![client, key, stored.status, headers, stored.body],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    // Forgets a key whose request failed, so it can be retried.
+    async fn forget(&self, client: String, key: String) -> Result<(), DbError> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM idempotency_keys WHERE client = ?1 AND key = ?2 AND status IS NULL",
+                    params![client, key],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+// A running request : its key is forgotten when it does not complete, even when the client goes
+// away and its request is dropped.
+struct Running {
+    idempotency: Idempotency,
+    client: String,
+    key: String,
+    completed: bool,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let idempotency = self.idempotency.clone();
+        let (client, key) = (self.client.clone(), self.key.clone());
+        tokio::spawn(async move {
+            if let Err(err) = idempotency.forget(client, key).await {
+                tracing::warn!("cannot forget an idempotency key: {err}");
+            }
+        });
+    }
+}
+
+// hexadecimal SHA-256 of what identifies the request
+fn fingerprint(method: &Method, path: &str, body: &Bytes) -> String {
+    let mut context = Context::new(&SHA256);
+    for part in [
+        method.as_str().as_bytes(),
+        b"\n",
+        path.as_bytes(),
+        b"\n",
+        body,
+    ] {
+        context.update(part);
+    }
+    context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+// Middleware applying the requests with an 'Idempotency-Key' once. It runs after 'authenticate',
+// so the client of the request is known, and after 'body_limit', so the body it reads is limited.
+pub async fn idempotency(
+    State(idempotency): State<Idempotency>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let changes = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    if !idempotency.enabled || !changes {
+        return next.run(request).await;
+    }
+    let client = match request.extensions().get::<Principal>() {
+        Some(Principal::ApiKey(key)) => format!("api_key:{}", key.id),
+        Some(Principal::User { user, .. }) => format!("user:{}", user.id),
+        None => return next.run(request).await,
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_owned(),
+        _ => {
+            return AppError::new(ErrorCode::BadRequest)
+                .with_detail(format!(
+                    "Idempotency-Key: must be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+                ))
+                .into_response()
+        }
+    };
+
+    // the body is read to be part of the fingerprint, then given back to the route
+    let (parts, body) = request.into_parts();
+    let body = match body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => {
+            // the error of 'Limited' is wrapped by the errors of the body
+            let mut source = std::error::Error::source(&err);
+            let mut too_large = false;
+            while let Some(err) = source {
+                too_large |= err.is::<LengthLimitError>();
+                source = err.source();
+            }
+            let code = match too_large {
+                true => ErrorCode::PayloadTooLarge,
+                false => ErrorCode::BadRequest,
+            };
+            return AppError::new(code)
+                .with_detail(err.to_string())
+                .into_response();
+        }
+    };
+    let path = parts.uri.path_and_query().map_or("", |path| path.as_str());
+    let fingerprint = fingerprint(&parts.method, path, &body);
+    let credentials = parts
+        .extensions
+        .get::<MatchedPath>()
+        .is_some_and(|route| CREDENTIAL_ROUTES.contains(&route.as_str()));
+
+    match idempotency
+        .begin(client.clone(), key.clone(), fingerprint)
+        .await
+    {
+        Err(err) => return AppError::from(err).into_response(),
+        Ok(Begin::New) => {}
+        Ok(Begin::Replay(stored)) => return stored.into_response(),
+        Ok(Begin::Running) => {
+            return AppError::new(ErrorCode::Conflict)
+                .with_detail("a request with this Idempotency-Key is still running")
+                .into_response()
+        }
+        Ok(Begin::Mismatch) => {
+            return AppError::new(ErrorCode::IdempotencyKeyReused)
+                .with_detail("this Idempotency-Key was already used for another request")
+                .into_response()
+        }
+    }
+    let mut running = Running {
+        idempotency: idempotency.clone(),
+        client: client.clone(),
+        key: key.clone(),
+        completed: false,
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let status = response.status();
+    if status.is_server_error()
+        || status == StatusCode::PRECONDITION_FAILED
+        || status == StatusCode::PRECONDITION_REQUIRED
+    {
+        // forgotten before the answer is sent, so a retry right after runs
+        match idempotency.forget(client, key).await {
+            Ok(()) => running.completed = true,
+            Err(err) => tracing::warn!("cannot forget an idempotency key: {err}"),
+        }
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => return AppError::internal(err).into_response(),
+    };
+    let stored = Stored {
+        status: parts.status.as_u16(),
+        headers: parts
+            .headers
+            .iter()
+            .filter(|(name, _)| match credentials {
+                true => *name == header::LOCATION,
+                false => *name != header::SET_COOKIE,
+            })
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect(),
+        body: match credentials {
+            true => Vec::new(),
+            false => body.to_vec(),
+        },
+    };
+    match idempotency.complete(client, key, stored).await {
+        Ok(()) => running.completed = true,
+        // the answer is still sent, a retry runs the request again
+        Err(err) => tracing::warn!("cannot store the answer of an idempotency key: {err}"),
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use axum::{
+        middleware,
+        routing::{patch, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::{DatabaseConfig, DatabaseMode};
+    use crate::etag::{self, IfMatch};
+
+    // A route counting its runs, answering with a cookie, and its router.
+    fn app(route: &'static str) -> (Router, Arc<AtomicU32>) {
+        let db = Db::open(&DatabaseConfig {
+            mode: DatabaseMode::Memory,
+            ..Default::default()
+        })
+        .unwrap();
+        let runs = Arc::new(AtomicU32::new(0));
+        let handler = {
+            let runs = runs.clone();
+            move |body: String| async move {
+                let run = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                (
+                    StatusCode::CREATED,
+                    [
+                        (header::LOCATION, String::from("/created/1")),
+                        (header::SET_COOKIE, String::from("session=secret")),
+                    ],
+                    format!("run {run}: {body}"),
+                )
+            }
+        };
+        let app = Router::new()
+            .route(route, post(handler))
+            .layer(middleware::from_fn_with_state(
+                Idempotency::new(db, &IdempotencyConfig::default()),
+                idempotency,
+            ));
+        (app, runs)
+    }
+
+    // A request of an API key with an idempotency key.
+    fn request(uri: &str, body: &str) -> Request {
+        let mut request = Request::post(uri)
+            .header(IDEMPOTENCY_KEY, "5f0c9d27")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let principal = Principal::ApiKey(crate::api_keys::ApiKey {
+            id: 1,
+            name: String::from("client"),
+            prefix: String::from("rak_1a2b3c4d"),
+            scopes: Vec::new(),
+            created_at: 0,
+            last_used_at: None,
+            revoked_at: None,
+        });
+        request.extensions_mut().insert(principal);
+        request
+    }
+
+    async fn send(app: &Router, request: Request) -> (Response, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = body::to_bytes(body, usize::MAX).await.unwrap();
+        let body = String::from_utf8_lossy(&body).into_owned();
+        (Response::from_parts(parts, Body::empty()), body)
+    }
+
+    #[tokio::test]
+    async fn replays_the_answer_and_refuses_the_key_for_another_body() {
+        let (app, runs) = app("/users");
+
+        let (first, body) = send(&app, request("/users", "sam")).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(body, "run 1: sam");
+        let (replay, body) = send(&app, request("/users", "sam")).await;
+        assert_eq!(replay.status(), StatusCode::CREATED);
+        assert_eq!(body, "run 1: sam");
+        assert_eq!(replay.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(replay.headers()[header::LOCATION], "/created/1");
+        assert!(!replay.headers().contains_key(header::SET_COOKIE));
+
+        let (other, _) = send(&app, request("/users", "frodo")).await;
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_store_the_credentials() {
+        let (app, runs) = app("/api-keys");
+
+        let (_, body) = send(&app, request("/api-keys", "ops")).await;
+        assert_eq!(body, "run 1: ops");
+        let (replay, body) = send(&app, request("/api-keys", "ops")).await;
+        assert_eq!(replay.status(), StatusCode::CREATED);
+        assert_eq!(replay.headers()[header::LOCATION], "/created/1");
+        assert_eq!(body, "");
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn runs_again_a_change_refused_for_its_version() {
+        let db = Db::open(&DatabaseConfig {
+            mode: DatabaseMode::Memory,
+            ..Default::default()
+        })
+        .unwrap();
+        let handler = |if_match: IfMatch| async move {
+            match if_match.matches(2) {
+                true => Ok(StatusCode::NO_CONTENT),
+                false => Err(etag::precondition_failed(2)),
+            }
+        };
+        let app =
+            Router::new()
+                .route("/users/1", patch(handler))
+                .layer(middleware::from_fn_with_state(
+                    Idempotency::new(db, &IdempotencyConfig::default()),
+                    idempotency,
+                ));
+        let change = |if_match: Option<&str>| {
+            let mut request = request("/users/1", "{}");
+            *request.method_mut() = Method::PATCH;
+            if let Some(if_match) = if_match {
+                let if_match = HeaderValue::from_str(if_match).unwrap();
+                request.headers_mut().insert(header::IF_MATCH, if_match);
+            }
+            request
+        };
+
+        let (response, _) = send(&app, change(None)).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+        let (response, _) = send(&app, change(Some("\"1\""))).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        // the retry with the current version runs, then is replayed
+        let (response, _) = send(&app, change(Some("\"2\""))).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!response.headers().contains_key(IDEMPOTENT_REPLAYED));
+        let (response, _) = send(&app, change(Some("\"1\""))).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[IDEMPOTENT_REPLAYED], "true");
+    }
+}
@@ -0,0 +1,155 @@
+// LOGGING
+// Structured logs with the 'tracing' crate, written on the standard output as 'pretty' text or
+// as one JSON object per line (see 'log.format' in the configuration).
+// Each request runs in a 'request' span holding its method, route, request ID, status and latency,
+// so every log written while processing the request carries these fields.
+// The filter can be changed while the server runs :
+//   GET /admin/log-filter  returns the current filter (scope 'logs:read')
+//   PUT /admin/log-filter  replaces it with the body of the request (scope 'logs:write'), for
+//                          example to debug a single route : 'info,[request{route=/users/.id.}]=debug'
+//                          (field values are regular expressions, and braces cannot be written
+//                          in a filter : '.id.' matches '{id}')
+use std::io::IsTerminal;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::{Request, Response},
+    routing::{get, put},
+    Router,
+};
+use tracing::{field, Span};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+use crate::auth::{RequireScope, Scope};
+use crate::config::{LogConfig, LogFormat};
+use crate::error::{AppError, ErrorCode, Problem};
+use crate::extract::Text;
+use crate::state::AppState;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Changes the filter of the logs of the running process.
+#[derive(Clone)]
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogHandle {
+    fn current(&self) -> Result<String, reload::Error> {
+        self.filter.with_current(|filter| filter.to_string())
+    }
+
+    fn replace(&self, directives: &str) -> Result<(), AppError> {
+        let filter = EnvFilter::try_new(directives).map_err(|err| {
+            AppError::new(ErrorCode::BadRequest).with_detail(format!("invalid filter: {err}"))
+        })?;
+        self.filter.reload(filter).map_err(AppError::internal)
+    }
+}
+
+// Installs the logger of the process. Must be called once, before any log.
+pub fn init(config: &LogConfig) -> LogHandle {
+    // the configuration is validated before : the directives are known to be valid
+    let filter = EnvFilter::try_new(config.directives()).unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let output = match config.format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .pretty()
+            .with_ansi(std::io::stdout().is_terminal())
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .init();
+    LogHandle { filter: handle }
+}
+
+// A handle on a filter that filters nothing, for the tests : the global subscriber is left alone.
+#[cfg(test)]
+pub fn detached() -> LogHandle {
+    let (_, handle) = reload::Layer::new(EnvFilter::new("info"));
+    LogHandle { filter: handle }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/admin/log-filter",
+            get(get_log_filter).require(Scope::LogsRead),
+        )
+        .route(
+            "/admin/log-filter",
+            put(put_log_filter).require(Scope::LogsWrite),
+        )
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/log-filter",
+    tag = "admin",
+    security(("bearer" = ["logs:read"])),
+    responses((status = 200, description = "The current filter of the logs", body = String)),
+)]
+async fn get_log_filter(State(state): State<AppState>) -> Result<String, AppError> {
+    state.logs.current().map_err(AppError::internal)
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/log-filter",
+    tag = "admin",
+    security(("bearer" = ["logs:write"])),
+    request_body(content = String, description = "Directives of 'tracing_subscriber::EnvFilter'"),
+    responses(
+        (status = 200, description = "The new filter of the logs", body = String),
+        (status = 400, description = "Invalid filter", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn put_log_filter(
+    State(state): State<AppState>,
+    Text(directives): Text,
+) -> Result<String, AppError> {
+    state.logs.replace(directives.trim())?;
+    tracing::warn!(filter = directives.trim(), "log filter changed");
+    state.logs.current().map_err(AppError::internal)
+}
+
+// Span of a request, used by the 'TraceLayer' of the router.
+// 'status' and 'latency_ms' are only known once the answer is ready, see 'record_response'.
+pub fn request_span(request: &Request<Body>) -> Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or("unmatched");
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        route,
+        path = request.uri().path(),
+        request_id,
+        status = field::Empty,
+        latency_ms = field::Empty,
+    )
+}
+
+pub fn record_response(response: &Response<Body>, latency: Duration, span: &Span) {
+    span.record("status", response.status().as_u16());
+    span.record("latency_ms", latency.as_millis() as u64);
+    tracing::info!("request answered");
+}
@@ -0,0 +1,127 @@
+// MAIL
+// The mails sent to the users, such as the links of '/auth/password/forgot'. They are handed to a
+// 'MailSender', chosen with 'mail.sender' :
+//   spool  writes each mail to a '.eml' file of 'mail.spool_dir' instead of sending it, for
+//          development and tests : the mails are read there, or by any mail client
+// Another way of sending (SMTP, the API of a provider) implements 'MailSender', and is added to
+// 'MailSenderKind' and 'Mailer::new'. The senders are blocking, they run on the blocking threads.
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::auth::{now, random_bytes};
+use crate::config::{MailConfig, MailSenderKind};
+
+// A plain text mail.
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait MailSender: Send + Sync {
+    fn send(&self, from: &str, mail: &Mail) -> io::Result<()>;
+}
+
+// Writes the mails to a directory.
+struct Spool {
+    dir: PathBuf,
+}
+
+impl MailSender for Spool {
+    fn send(&self, from: &str, mail: &Mail) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // sorted by date, the random part tells apart the mails of the same second
+        let random = random_bytes::<4>()
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            });
+        let name = format!("{}-{random}", now());
+        // written under another name first, so a reader of the directory never sees half a mail
+        let partial = self.dir.join(format!("{name}.tmp"));
+        fs::write(&partial, message(from, mail))?;
+        fs::rename(partial, self.dir.join(format!("{name}.eml")))
+    }
+}
+
+// The mail in the format of RFC 5322, with CRLF line endings.
+fn message(from: &str, mail: &Mail) -> String {
+    let mut message = format!(
+        "From: {from}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        mail.to, mail.subject
+    );
+    for line in mail.body.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+// The sender of the configuration.
+#[derive(Clone)]
+pub struct Mailer {
+    sender: Arc<dyn MailSender>,
+    from: String,
+}
+
+impl Mailer {
+    pub fn new(config: &MailConfig) -> Self {
+        let sender: Arc<dyn MailSender> = match config.sender {
+            MailSenderKind::Spool => Arc::new(Spool {
+                dir: config.spool_dir.clone(),
+            }),
+        };
+        Self {
+            sender,
+            from: config.from.clone(),
+        }
+    }
+
+    pub async fn send(&self, mail: Mail) -> io::Result<()> {
+        let mailer = self.clone();
+        tokio::task::spawn_blocking(move || mailer.sender.send(&mailer.from, &mail))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_each_mail_to_its_own_file_of_the_spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool_dir = dir.path().join("mail");
+        let mailer = Mailer::new(&MailConfig {
+            spool_dir: spool_dir.clone(),
+            ..Default::default()
+        });
+        let mail = Mail {
+            to: String::from("sam@example.com"),
+            subject: String::from("Hello"),
+            body: String::from("first line\nsecond line\n"),
+        };
+
+        mailer.send(mail).await.unwrap();
+        // the directory is created, and the file written under another name is renamed
+        let files: Vec<_> = fs::read_dir(&spool_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        assert_eq!(
+            fs::read_to_string(&files[0]).unwrap(),
+            "From: rest-api-axum <no-reply@localhost>\r\nTo: sam@example.com\r\n\
+             Subject: Hello\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\r\nfirst line\r\nsecond line\r\n"
+        );
+    }
+}
@@ -1,14 +1,412 @@
-use axum::{
-    routing::get,
-    Router,
+use std::process::ExitCode;
+use std::time::Duration;
+
+use axum::{extract::DefaultBodyLimit, http::StatusCode, middleware, routing::get, Router};
+use clap::Parser;
+use tower::{limit::ConcurrencyLimitLayer, util::option_layer};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
 };
 
+mod api_keys;
+mod audit;
+mod auth;
+mod compression;
+mod config;
+mod cookies;
+mod db;
+mod error;
+mod etag;
+mod events;
+mod extract;
+mod health;
+mod idempotency;
+mod logging;
+mod mail;
+mod messages;
+// the 'Message' enum of the enumerations tutorial, exchanged by 'messages.rs'
+#[path = "../../../tuto/enumerations/message.rs"]
+mod message;
+mod metrics;
+mod openapi;
+mod pagination;
+mod passwords;
+mod ratelimit;
+mod roles;
+mod security;
+mod server;
+mod state;
+mod tls;
+mod users;
+mod validation;
+
+use api_keys::{ApiKeyStore, CreateApiKey};
+use audit::AuditLog;
+use auth::{Auth, Scope};
+use config::{Cli, Command, Config, DatabaseMode, Environment};
+use db::Db;
+use events::UserEvents;
+use idempotency::Idempotency;
+use messages::Hub;
+use metrics::Metrics;
+use ratelimit::RateLimiter;
+use security::{BodyLimits, SecurityHeaders};
+use state::AppState;
+use tls::Certificates;
+
 #[tokio::main]
-async fn main() {
-    // build our application with a single route
-    let app = Router::new().route("/", get(|| async { "Hello, World!" }));
-
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
\ No newline at end of file
+async fn main() -> ExitCode {
+    // the configuration is checked before anything else : a wrong setting stops the server
+    // with a readable message instead of a panic
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("configuration error: {err}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match cli.command {
+        Some(Command::Healthcheck { path, timeout_secs }) => {
+            healthcheck(&config, &path, Duration::from_secs(timeout_secs)).await
+        }
+        Some(Command::CreateApiKey { name, scopes }) => create_api_key(&config, name, scopes).await,
+        Some(Command::VerifyAudit) => verify_audit(&config),
+        Some(Command::Serve) | None => serve(config).await,
+    }
+}
+
+async fn serve(config: Config) -> ExitCode {
+    let logs = logging::init(&config.log);
+    error::expose_internal_errors(config.server.environment == Environment::Development);
+    for name in &config.ignored_env {
+        tracing::warn!("the environment variable {name} is not a setting, it is ignored");
+    }
+
+    // the schema of the database is created or upgraded before serving any request
+    let db = match Db::open(&config.database) {
+        Ok(db) => db,
+        Err(err) => {
+            tracing::error!("cannot open the database: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // the log continues the chain of its last entry, a log that cannot be read stops the server
+    let audit = if config.audit.enabled {
+        match AuditLog::open(&config.audit) {
+            Ok(audit) => Some(audit),
+            Err(err) => {
+                tracing::error!("cannot open the audit log: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    let metrics = match Metrics::new() {
+        Ok(metrics) => metrics,
+        Err(err) => {
+            tracing::error!("cannot register the metrics: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // the certificate is loaded once here, then reloaded in the background when its files change
+    let tls = if config.tls.enabled {
+        let certificates = match Certificates::load(&config.tls) {
+            Ok(certificates) => certificates,
+            Err(err) => {
+                tracing::error!("cannot load the TLS certificate: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let acceptor = match certificates.acceptor() {
+            Ok(acceptor) => acceptor,
+            Err(err) => {
+                tracing::error!("cannot configure TLS: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        tokio::spawn(certificates.clone().watch(config.tls.reload_interval()));
+        Some(acceptor)
+    } else {
+        None
+    };
+
+    if config.auth.secret.is_empty() {
+        tracing::warn!("auth.secret is not set: the tokens will not be valid after a restart");
+    }
+    let auth = Auth::new(&config.auth, db.clone());
+
+    // the quotas of the clients that went idle are forgotten in the background
+    let limiter = config
+        .rate_limit
+        .enabled
+        .then(|| RateLimiter::new(&config.rate_limit));
+    if let Some(limiter) = &limiter {
+        tokio::spawn(limiter.clone().evict(config.rate_limit.evict_interval()));
+    }
+
+    let signal = server::broadcast_signal();
+
+    // the WebSocket sessions and the event streams outlive their request : they are closed as
+    // soon as the shutdown starts
+    let hub = Hub::new(&config.websocket);
+    let events = UserEvents::new(&config.events);
+    tokio::spawn({
+        let (hub, events, signal) = (hub.clone(), events.clone(), signal.clone());
+        async move {
+            server::stopped(signal).await;
+            hub.close();
+            events.close();
+        }
+    });
+
+    // build our application with its routes and the state shared by the requests
+    let app = router(
+        &config,
+        AppState::new(&config, db, auth, events, hub, logs, metrics).with_audit(audit),
+        limiter,
+    );
+
+    // run our app with hyper, listening on the configured address
+    let addr = config.addr();
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("cannot listen on {addr}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Ok(local) = listener.local_addr() else {
+        tracing::error!("cannot read the address of the listener");
+        return ExitCode::FAILURE;
+    };
+    tracing::info!(addr = %local, tls = tls.is_some(), "listening");
+
+    // the plain HTTP listener only redirects to HTTPS
+    if let Some(addr) = config.redirect_addr() {
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!("cannot listen on {addr}: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        tracing::info!(%addr, "redirecting HTTP to HTTPS");
+        tokio::spawn(server::serve(
+            listener,
+            tls::redirect(local.port()),
+            None,
+            server::stopped(signal.clone()),
+            config.shutdown_timeout(),
+        ));
+    }
+
+    // the server runs until SIGTERM or SIGINT, then lets the requests in progress finish
+    let report = server::serve(
+        listener,
+        app,
+        tls,
+        server::stopped(signal),
+        config.shutdown_timeout(),
+    )
+    .await;
+    tracing::info!(
+        signal = report.signal,
+        accepted = report.accepted,
+        drained = report.draining - report.aborted,
+        aborted = report.aborted,
+        elapsed_ms = report.elapsed.as_millis() as u64,
+        "server stopped"
+    );
+    ExitCode::SUCCESS
+}
+
+// Probes the server started with the same configuration, for the 'HEALTHCHECK' of the container.
+async fn healthcheck(config: &Config, path: &str, timeout: Duration) -> ExitCode {
+    let tls = match config.tls.enabled.then(tls::probe_connector).transpose() {
+        Ok(tls) => tls,
+        Err(err) => {
+            eprintln!("unhealthy: cannot configure TLS: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match health::probe(config.addr(), path, tls, timeout).await {
+        Ok(status) if (200..300).contains(&status) => ExitCode::SUCCESS,
+        Ok(status) => {
+            eprintln!("unhealthy: {path} answered {status}");
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("unhealthy: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Creates the first API key, before any client can call '/api-keys'.
+async fn create_api_key(config: &Config, name: String, scopes: Vec<Scope>) -> ExitCode {
+    if config.database.mode == DatabaseMode::Memory {
+        eprintln!("database.mode is 'memory' : the key would be lost when this command exits");
+        return ExitCode::FAILURE;
+    }
+    let db = match Db::open(&config.database) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("cannot open the database: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match ApiKeyStore::new(db)
+        .create(CreateApiKey { name, scopes })
+        .await
+    {
+        Ok(created) => {
+            eprintln!(
+                "API key {} created, it cannot be read again :",
+                created.api_key.prefix
+            );
+            println!("{}", created.key);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("cannot create the API key: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Checks the chain of the audit log, see 'audit.rs'.
+fn verify_audit(config: &Config) -> ExitCode {
+    match audit::verify(&config.audit.path) {
+        Ok((entries, hash)) => {
+            println!("{entries} entries, the chain is intact, last hash {hash}");
+            ExitCode::SUCCESS
+        }
+        Err(problem) => {
+            eprintln!("the audit log was tampered with: {problem}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// The routes of the application, with the timeouts and limits of the configuration.
+// Layers wrap the routes declared before them : the last one added is the first one called.
+fn router(config: &Config, state: AppState, limiter: Option<RateLimiter>) -> Router {
+    Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .merge(api_keys::routes())
+        .merge(audit::routes())
+        .merge(auth::routes())
+        .merge(cookies::routes())
+        .merge(events::routes())
+        .merge(health::routes())
+        .merge(logging::routes())
+        .merge(messages::routes())
+        .merge(metrics::routes())
+        .merge(openapi::routes())
+        .merge(passwords::routes())
+        .merge(roles::routes())
+        .merge(users::routes())
+        .fallback(error::not_found)
+        .method_not_allowed_fallback(error::method_not_allowed)
+        // after the body limit and the authentication : see 'idempotency.rs'
+        .layer(middleware::from_fn_with_state(
+            Idempotency::new(state.db.clone(), &config.idempotency),
+            idempotency::idempotency,
+        ))
+        // added to every route, so the limit of the route is known
+        .layer(middleware::from_fn_with_state(
+            BodyLimits::new(&config.limits),
+            security::body_limit,
+        ))
+        // counted against the quota of the client, once it is known
+        .layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit,
+        ))
+        // the client is known before the route is called, see 'require' in 'auth.rs'
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        // counted against the quota of the address, even when the credentials are refused
+        .layer(middleware::from_fn_with_state(limiter, ratelimit::limit_ip))
+        // before every layer refusing requests, so the refused requests are recorded too
+        .layer(middleware::from_fn_with_state(
+            state.audit.clone(),
+            audit::audit,
+        ))
+        // added to every route, so the middleware knows the route matched by the request
+        .layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::track,
+        ))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(logging::request_span)
+                .on_response(logging::record_response),
+        )
+        .with_state(state)
+        // the request ID is set before the span of the request is created, and sent back to the
+        // client : an ID given by the client in 'X-Request-Id' is kept
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        // the bodies are limited by 'body_limit' instead, route by route
+        .layer(DefaultBodyLimit::disable())
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            config.request_timeout(),
+        ))
+        .layer(ConcurrencyLimitLayer::new(
+            config.limits.max_concurrent_requests,
+        ))
+        // the bodies are decompressed before the limits of 'body_limit' apply
+        .layer(compression::decompression(&config.compression))
+        .layer(middleware::from_fn_with_state(
+            compression::accepted(&config.compression),
+            compression::check_encoding,
+        ))
+        .layer(compression::compression(&config.compression))
+        // outside of the limits, so the preflight requests and the refused requests are answered
+        // with these headers too
+        .layer(option_layer(security::cors(&config.cors)))
+        .layer(middleware::from_fn_with_state(
+            SecurityHeaders::new(&config.security_headers, config.tls.enabled),
+            security::security_headers,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn fails_the_healthcheck_of_a_server_not_ready_or_not_running() {
+        let app = Router::new()
+            .route("/healthz", get(|| async { StatusCode::OK }))
+            .route("/readyz", get(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.server.port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let timeout = Duration::from_secs(5);
+
+        let code = healthcheck(&config, "/healthz", timeout).await;
+        assert_eq!(code, ExitCode::SUCCESS);
+        let code = healthcheck(&config, "/readyz", timeout).await;
+        assert_eq!(code, ExitCode::FAILURE);
+        server.abort();
+        let _ = server.await;
+        let code = healthcheck(&config, "/healthz", timeout).await;
+        assert_eq!(code, ExitCode::FAILURE);
+    }
+}
@@ -0,0 +1,296 @@
+// MESSAGES
+//   GET /ws  WebSocket session exchanging the 'Message' enum of the enumerations tutorial
+//            (src/tuto/enumerations/message.rs), requires the scope 'messages:write'
+// Each message is a JSON text frame, its variant in 'type' and its fields in 'data' :
+//   {"type": "quit"}
+//   {"type": "write", "data": "hello"}
+//   {"type": "move", "data": {"x": 1, "y": 2}}
+//   {"type": "change_color", "data": [255, 0, 0]}
+// A message received from a client is sent to every connected client, the sender included,
+// through the hub. 'quit' is not sent to anyone : it closes the session of its sender (close code
+// 1000). A frame that is not a message closes the session too (close code 1003 or 1007), so do
+// more messages than 'websocket.message_rate' (close code 1008). A message over
+// 'websocket.max_message_bytes' drops the connection, and the sessions are closed with the code
+// 1001 when the server shuts down.
+use std::borrow::Cow;
+use std::time::Instant;
+
+use axum::{
+    extract::{
+        ws::{
+            self, rejection::WebSocketUpgradeRejection, CloseFrame, Utf8Bytes, WebSocket,
+            WebSocketUpgrade,
+        },
+        State,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use tokio::sync::{broadcast, watch};
+
+use crate::auth::{RequireScope, Scope};
+use crate::config::WebSocketConfig;
+use crate::error::{AppError, ErrorCode, Problem};
+use crate::message::Message;
+use crate::ratelimit::Gcra;
+use crate::state::AppState;
+
+// close codes of RFC 6455
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID: u16 = 1007;
+const CLOSE_POLICY: u16 = 1008;
+// a close reason is limited to 123 bytes
+const MAX_REASON_BYTES: usize = 123;
+
+impl Message {
+    // Delivers the message to every connected client, returns their number.
+    pub fn send(&self, hub: &Hub) -> usize {
+        // serializing an enum of strings and integers cannot fail
+        let text = serde_json::to_string(self).unwrap_or_default();
+        hub.messages.send(text.into()).unwrap_or(0)
+    }
+}
+
+// The connected clients.
+#[derive(Clone)]
+pub struct Hub {
+    // each session holds a receiver, the messages are serialized once for all of them
+    messages: broadcast::Sender<Utf8Bytes>,
+    // 'true' once the server shuts down
+    closing: watch::Sender<bool>,
+    max_message_bytes: usize,
+    // each session has its own quota of messages
+    message_rate: Gcra,
+}
+
+impl Hub {
+    pub fn new(config: &WebSocketConfig) -> Self {
+        Self {
+            messages: broadcast::channel(config.buffer).0,
+            closing: watch::channel(false).0,
+            max_message_bytes: config.max_message_bytes,
+            message_rate: Gcra::new(&config.message_rate),
+        }
+    }
+
+    // Closes every session, for the shutdown of the server.
+    pub fn close(&self) {
+        self.closing.send_replace(true);
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/ws", get(ws).require(Scope::MessagesWrite))
+}
+
+impl From<WebSocketUpgradeRejection> for AppError {
+    fn from(rejection: WebSocketUpgradeRejection) -> Self {
+        AppError::new(ErrorCode::BadRequest).with_detail(rejection.body_text())
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "messages",
+    security(("bearer" = ["messages:write"])),
+    responses(
+        (status = 101, description = "WebSocket session exchanging messages as JSON text frames, such as {\"type\": \"move\", \"data\": {\"x\": 1, \"y\": 2}}"),
+        (status = 400, description = "Not a WebSocket handshake", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn ws(
+    State(state): State<AppState>,
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, AppError> {
+    let hub = state.hub;
+    Ok(upgrade?
+        .max_message_size(hub.max_message_bytes)
+        .on_upgrade(move |socket| session(socket, hub)))
+}
+
+// Runs the session of a client until it quits, disconnects or the server shuts down.
+async fn session(mut socket: WebSocket, hub: Hub) {
+    let mut messages = hub.messages.subscribe();
+    let mut closing = hub.closing.subscribe();
+    let mut quota = Instant::now();
+    tracing::info!(
+        clients = hub.messages.receiver_count(),
+        "WebSocket client connected"
+    );
+    let close = loop {
+        tokio::select! {
+            frame = socket.recv() => match frame {
+                Some(Ok(ws::Message::Text(_))) if !hub.message_rate.allow(&mut quota) => {
+                    break Some((CLOSE_POLICY, Cow::from("too many messages")));
+                }
+                Some(Ok(ws::Message::Text(text))) => match serde_json::from_str::<Message>(&text) {
+                    // closes the session of the sender
+                    Ok(Message::Quit) => break Some((CLOSE_NORMAL, Cow::from("quit"))),
+                    Ok(message) => {
+                        message.send(&hub);
+                    }
+                    Err(err) => break Some((CLOSE_INVALID, format!("invalid message: {err}").into())),
+                },
+                Some(Ok(ws::Message::Binary(_))) => {
+                    break Some((CLOSE_UNSUPPORTED, Cow::from("messages are JSON text frames")));
+                }
+                // the pings are answered by axum
+                Some(Ok(ws::Message::Ping(_) | ws::Message::Pong(_))) => {}
+                // the client closed the session or the connection is lost
+                Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break None,
+            },
+            message = messages.recv() => match message {
+                Ok(text) => {
+                    if socket.send(ws::Message::Text(text)).await.is_err() {
+                        break None;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(missed, "WebSocket client too slow, messages skipped");
+                }
+                Err(broadcast::error::RecvError::Closed) => break None,
+            },
+            // the guard of the value is released before the select ends, to keep the future 'Send'
+            _ = async { closing.wait_for(|closing| *closing).await.map(|_| ()) } => {
+                break Some((CLOSE_GOING_AWAY, Cow::from("the server is shutting down")));
+            }
+        }
+    };
+    if let Some((code, reason)) = close {
+        let reason = truncate(&reason, MAX_REASON_BYTES);
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        let _ = socket.send(ws::Message::Close(Some(frame))).await;
+    }
+    // the receiver of this session is still counted
+    tracing::info!(
+        clients = hub.messages.receiver_count() - 1,
+        "WebSocket client disconnected"
+    );
+}
+
+// the longest start of the text fitting in 'max' bytes, without cutting a character
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{protocol::frame::coding::CloseCode, Message as Frame},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    use super::*;
+    use crate::config::{Config, Quota};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    // Serves the sessions on a free port, without the authentication, and returns its address
+    // and its hub.
+    async fn serve(config: &Config) -> (String, Hub) {
+        let state = AppState::test(config);
+        let hub = state.hub.clone();
+        let app = Router::new().route("/ws", get(ws)).with_state(state);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("ws://{address}/ws"), hub)
+    }
+
+    async fn connect(url: &str) -> Client {
+        connect_async(url).await.unwrap().0
+    }
+
+    async fn send(client: &mut Client, message: &str) {
+        client.send(Frame::text(message)).await.unwrap();
+    }
+
+    // the code of the close frame sent by the server, the messages received before are skipped
+    async fn close_code(client: &mut Client) -> CloseCode {
+        while let Some(frame) = client.next().await {
+            if let Frame::Close(Some(frame)) = frame.unwrap() {
+                return frame.code;
+            }
+        }
+        panic!("the session ended without a close frame");
+    }
+
+    #[tokio::test]
+    async fn sends_a_message_to_every_session() {
+        let (url, hub) = serve(&Config::default()).await;
+        let mut alice = connect(&url).await;
+        let mut bob = connect(&url).await;
+        // the sessions subscribe to the hub once the handshake is answered
+        while hub.messages.receiver_count() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        send(&mut alice, r#"{"type": "write", "data": "hello"}"#).await;
+        send(&mut alice, r#"{"type": "move", "data": {"x": 1, "y": 2}}"#).await;
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let frame = bob.next().await.unwrap().unwrap();
+            received.push(serde_json::from_str::<Message>(frame.to_text().unwrap()).unwrap());
+        }
+        assert_eq!(
+            received,
+            [
+                Message::Write(String::from("hello")),
+                Message::Move { x: 1, y: 2 }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_the_session_that_quits() {
+        let (url, _) = serve(&Config::default()).await;
+        let mut client = connect(&url).await;
+
+        send(&mut client, r#"{"type": "quit"}"#).await;
+        assert_eq!(close_code(&mut client).await, CloseCode::Normal);
+    }
+
+    #[tokio::test]
+    async fn closes_the_session_sending_a_frame_that_is_not_a_message() {
+        let (url, _) = serve(&Config::default()).await;
+        let mut client = connect(&url).await;
+        send(&mut client, r#"{"type": "jump"}"#).await;
+        assert_eq!(close_code(&mut client).await, CloseCode::Invalid);
+
+        let mut client = connect(&url).await;
+        let frame = Frame::binary(br#"{"type": "quit"}"#.to_vec());
+        client.send(frame).await.unwrap();
+        assert_eq!(close_code(&mut client).await, CloseCode::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn closes_the_session_sending_too_many_messages() {
+        let mut config = Config::default();
+        config.websocket.message_rate = Quota {
+            requests: 1,
+            period_secs: 60,
+            burst: Some(2),
+        };
+        let (url, _) = serve(&config).await;
+        let mut client = connect(&url).await;
+
+        for _ in 0..3 {
+            send(&mut client, r#"{"type": "write", "data": "hello"}"#).await;
+        }
+        assert_eq!(close_code(&mut client).await, CloseCode::Policy);
+    }
+}
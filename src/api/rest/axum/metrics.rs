@@ -0,0 +1,209 @@
+// METRICS
+//   GET /metrics  metrics in the Prometheus text exposition format
+// Every request going through the 'track' middleware is recorded, labelled by method and route.
+// The route is the path declared in the router ('/users/{id}'), not the path of the request
+// ('/users/42'), so the number of series does not grow with the identifiers. The requests that do
+// not match any route share the 'unmatched' label.
+// The metrics of the process itself (CPU, memory, file descriptors) are added on Linux.
+use std::time::Instant;
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::error::AppError;
+use crate::state::AppState;
+
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    in_flight: IntGaugeVec,
+    response_size: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests answered"),
+            &["method", "route", "status"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Duration of the HTTP requests, until the headers of the answer are sent",
+            ),
+            &["method", "route"],
+        )?;
+        let in_flight = IntGaugeVec::new(
+            Opts::new(
+                "http_requests_in_flight",
+                "Number of HTTP requests being processed",
+            ),
+            &["method", "route"],
+        )?;
+        let response_size = HistogramVec::new(
+            // from 64 bytes to 4 MiB
+            HistogramOpts::new(
+                "http_response_size_bytes",
+                "Size of the bodies of the HTTP answers, when known in advance",
+            )
+            .buckets(exponential_buckets(64.0, 4.0, 9)?),
+            &["method", "route"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(response_size.clone()))?;
+        #[cfg(target_os = "linux")]
+        registry.register(Box::new(
+            prometheus::process_collector::ProcessCollector::for_self(),
+        ))?;
+        Ok(Self {
+            registry,
+            requests,
+            duration,
+            in_flight,
+            response_size,
+        })
+    }
+
+    // all the metrics, in the text exposition format
+    fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "metrics",
+    responses((
+        status = 200,
+        description = "Metrics in the Prometheus text exposition format",
+        body = String,
+        content_type = "text/plain; version=0.0.4",
+    )),
+)]
+async fn metrics(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let body = state.metrics.render().map_err(AppError::internal)?;
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
+
+// Counts a request in flight as long as it lives. The count is also decreased when the request is
+// cancelled, for example when the client goes away before the answer.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Middleware recording the metrics of each request.
+pub async fn track(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let labels = [method.as_str(), route.as_str()];
+
+    let in_flight = InFlight::new(metrics.in_flight.with_label_values(&labels));
+    let started = Instant::now();
+    let response = next.run(request).await;
+    drop(in_flight);
+
+    metrics
+        .duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    // streamed bodies (events, downloads) have no size known in advance and are not observed
+    if let Some(size) = Body::size_hint(response.body()).exact() {
+        metrics
+            .response_size
+            .with_label_values(&labels)
+            .observe(size as f64);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, middleware};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn labels_the_requests_by_route_and_exposes_them() {
+        let state = AppState::test(&Config::default());
+        let app = Router::new()
+            .route("/users/{id}", get(|| async { "sam" }))
+            .merge(routes())
+            .layer(middleware::from_fn_with_state(state.metrics.clone(), track))
+            .with_state(state);
+        let get = |uri: &str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request)
+        };
+
+        for uri in ["/users/1", "/users/2", "/nothing/here"] {
+            get(uri).await.unwrap();
+        }
+        let response = get("/metrics").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            prometheus::TEXT_FORMAT
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        let metrics = String::from_utf8(body.await.unwrap().to_vec()).unwrap();
+        let lines: Vec<&str> = metrics.lines().collect();
+        for line in [
+            "# TYPE http_requests_total counter",
+            r#"http_requests_total{method="GET",route="/users/{id}",status="200"} 2"#,
+            r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#,
+            r#"http_response_size_bytes_count{method="GET",route="/users/{id}"} 2"#,
+            // the request reading the metrics is still in flight
+            r#"http_requests_in_flight{method="GET",route="/metrics"} 1"#,
+            r#"http_requests_in_flight{method="GET",route="/users/{id}"} 0"#,
+        ] {
+            assert!(lines.contains(&line), "{line} not in\n{metrics}");
+        }
+        assert!(!metrics.contains("/users/1"));
+    }
+}
@@ -0,0 +1,205 @@
+// OPENAPI
+//   GET /openapi.json  OpenAPI 3.1 document of the API
+//   GET /docs          Swagger UI explorer of the document
+// The document is generated by 'utoipa' from the '#[utoipa::path]' attributes of the handlers and
+// the 'ToSchema' models. A new handler must be added to 'paths' below to be part of it.
+// The Swagger UI files are embedded in the binary ('vendored' feature of 'utoipa-swagger-ui'),
+// so the explorer works without any access to a CDN.
+use axum::Router;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::state::AppState;
+use crate::{
+    api_keys, audit, auth, cookies, events, health, logging, messages, metrics, passwords, roles,
+    users,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "rest-api-axum", description = "REST API example using Axum"),
+    modifiers(&Security),
+    paths(
+        api_keys::list_api_keys,
+        api_keys::create_api_key,
+        api_keys::revoke_api_key,
+        auth::login,
+        auth::refresh,
+        auth::logout,
+        auth::me,
+        cookies::create_session,
+        cookies::delete_session,
+        passwords::change_password,
+        passwords::forgot_password,
+        passwords::reset_password,
+        health::healthz,
+        health::readyz,
+        metrics::metrics,
+        logging::get_log_filter,
+        logging::put_log_filter,
+        messages::ws,
+        roles::list_roles,
+        roles::create_role,
+        roles::replace_role,
+        roles::delete_role,
+        roles::list_user_roles,
+        roles::assign_role,
+        roles::unassign_role,
+        users::list_users,
+        users::create_user,
+        users::get_user,
+        users::replace_user,
+        users::update_user,
+        users::delete_user,
+        events::user_events,
+        audit::list_audit,
+    ),
+    tags(
+        (name = "auth", description = "Sign in and tokens"),
+        (name = "api-keys", description = "Keys of the services calling the API"),
+        (name = "users", description = "Users of the structures tutorial"),
+        (name = "roles", description = "Roles and permissions of the users"),
+        (name = "audit", description = "Log of the changes, who made them and when"),
+        (name = "health", description = "Liveness and readiness of the server"),
+        (name = "metrics", description = "Prometheus metrics"),
+        (name = "admin", description = "Administration of the running server"),
+        (name = "messages", description = "Messages of the enumerations tutorial, over WebSocket"),
+    )
+)]
+struct ApiDoc;
+
+// Declares the 'bearer' scheme used by the 'security' of the protected handlers : an access token
+// of a user or an API key.
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    SwaggerUi::new("/docs")
+        .url("/openapi.json", ApiDoc::openapi())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+
+    // The paths declared by the 'routes' function of each module merged in 'router', read from
+    // their sources : a route added without documentation is found too.
+    fn declared_paths() -> BTreeSet<String> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/api/rest/axum");
+        let read = |module: &str| std::fs::read_to_string(format!("{dir}/{module}.rs")).unwrap();
+        let main = read("main");
+        let router = &main[main.find("fn router(").unwrap()..];
+        let modules = router
+            .split(".merge(")
+            .skip(1)
+            .filter_map(|merged| merged.split_once("::routes()"))
+            .map(|(module, _)| module.to_owned());
+        let mut paths = BTreeSet::new();
+        for module in modules {
+            let source = read(&module);
+            let Some(start) = source.find("pub fn routes()") else {
+                continue;
+            };
+            let routes = &source[start..];
+            let routes = &routes[..routes.find("\n}\n").unwrap()];
+            for route in routes.split(".route(").skip(1) {
+                let path = route.split('"').nth(1).unwrap();
+                paths.insert(path.to_owned());
+            }
+        }
+        paths
+    }
+
+    // the '$ref' of the document and of its members
+    fn references<'a>(value: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::Object(members) => {
+                if let Some(serde_json::Value::String(reference)) = members.get("$ref") {
+                    found.push(reference);
+                }
+                members.values().for_each(|value| references(value, found));
+            }
+            serde_json::Value::Array(values) => {
+                values.iter().for_each(|value| references(value, found));
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn documents_every_route_of_the_router() {
+        let json = ApiDoc::openapi().to_json().unwrap();
+        let document: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(document["openapi"], "3.1.0");
+        let mut found = Vec::new();
+        references(&document, &mut found);
+        for reference in found {
+            let name = reference.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                document["components"]["schemas"].get(name).is_some(),
+                "{reference}"
+            );
+        }
+
+        let paths = document["paths"].as_object().unwrap();
+        let documented: BTreeSet<String> = paths.keys().cloned().collect();
+        assert_eq!(documented, declared_paths());
+    }
+
+    #[tokio::test]
+    async fn routes_every_documented_operation() {
+        let config = Config::default();
+        let app = crate::router(&config, AppState::test(&config), None);
+        let document = ApiDoc::openapi();
+
+        for (path, item) in &document.paths.paths {
+            let uri = path.replace("{id}", "1").replace("{name}", "viewer");
+            let methods = [
+                (Method::GET, &item.get),
+                (Method::POST, &item.post),
+                (Method::PUT, &item.put),
+                (Method::PATCH, &item.patch),
+                (Method::DELETE, &item.delete),
+            ];
+            for (method, operation) in methods {
+                if operation.is_none() {
+                    continue;
+                }
+                let request = Request::builder()
+                    .method(method.clone())
+                    .uri(&uri)
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app.clone().oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+                let body = String::from_utf8_lossy(&body.await.unwrap()).into_owned();
+                // the answers of the fallbacks
+                assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{method} {path}");
+                assert!(!body.contains("no route matches"), "{method} {path}");
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// PAGINATION
+// The listings are split in pages with cursors (keyset pagination) rather than offsets : the
+// cursor holds the sort key and the identifier of the last item of the page, and the next page
+// starts right after them. An item inserted or deleted meanwhile does not shift the next pages,
+// so no item is sent twice or skipped.
+// The cursor is opaque for the clients : the JSON of the position, encoded in base64url. It also
+// holds the sort and the filters of the listing, so the next pages are consistent with the first.
+// The pages are linked in the 'Link' header (RFC 8288) :
+//   Link: </users?limit=50&cursor=eyJ...>; rel="next", </users?limit=50>; rel="first"
+use axum::http::{header, HeaderMap, HeaderValue};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{AppError, ErrorCode};
+
+// items of a page when the client does not give 'limit'
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 200;
+
+pub fn parse_limit(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+        _ => Err(format!("must be an integer between 1 and {MAX_LIMIT}")),
+    }
+}
+
+pub fn encode_cursor(cursor: &impl Serialize) -> String {
+    // serializing the position of a page cannot fail : it only holds strings and integers
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor).unwrap_or_default())
+}
+
+pub fn decode_cursor<T: DeserializeOwned>(cursor: &str) -> Result<T, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| {
+            AppError::new(ErrorCode::InvalidQuery)
+                .with_field("cursor", "not a cursor given by a previous page")
+        })
+}
+
+// The 'Link' header of a page. 'first' holds the parameters of the first page, 'next' the cursor
+// of the next page when there is one.
+pub fn links(
+    path: &str,
+    limit: usize,
+    first: Vec<(String, String)>,
+    next: Option<String>,
+) -> HeaderMap {
+    let limit = (String::from("limit"), limit.to_string());
+    let url = |params: Vec<(String, String)>| {
+        // encoding pairs of strings cannot fail
+        format!(
+            "{path}?{}",
+            serde_urlencoded::to_string(params).unwrap_or_default()
+        )
+    };
+    let mut links = Vec::new();
+    if let Some(next) = next {
+        let params = vec![limit.clone(), (String::from("cursor"), next)];
+        links.push(format!("<{}>; rel=\"next\"", url(params)));
+    }
+    let mut params = vec![limit];
+    params.extend(first);
+    links.push(format!("<{}>; rel=\"first\"", url(params)));
+
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&links.join(", ")) {
+        headers.insert(header::LINK, value);
+    }
+    headers
+}
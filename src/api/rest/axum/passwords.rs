@@ -0,0 +1,642 @@
+// PASSWORDS
+//   POST /auth/password         changes the password of the signed in user
+//   POST /auth/password/forgot  mails a link to reset a forgotten password
+//   POST /auth/password/reset   sets a new password with the token of that link
+// The passwords are hashed with Argon2id and only the hash is stored, as a PHC string :
+//   $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+// The cost ('passwords.memory_kib', 'iterations' and 'parallelism') is part of the hash, so
+// changing it does not lock anyone out : the password of a user is hashed again with the new cost
+// at its next login. Hashing is slow on purpose, it runs on the blocking threads.
+// A reset token is random, mailed to the address of the user (see 'mail.rs') and stored hashed,
+// as the API keys. It can be used once, before 'passwords.reset_ttl_secs'. '/auth/password/forgot'
+// answers the same whether the address is known or not, so it does not tell who has an account.
+// A new password revokes the sessions of the user : the other ones after a change, all of them
+// after a reset.
+use std::fmt;
+use std::sync::Arc;
+
+use argon2::{
+    password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::post, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::api_keys;
+use crate::audit::AuditChange;
+use crate::auth::{now, random_bytes, AuthUser};
+use crate::config::PasswordsConfig;
+use crate::db::{Db, DbError};
+use crate::error::{AppError, Problem};
+use crate::extract::Json;
+use crate::mail::Mail;
+use crate::state::AppState;
+use crate::users::{user_from_row, User, USER_COLUMNS};
+use crate::validation::Violations;
+
+// longer passwords only make the hashing slower
+const MAX_PASSWORD_LEN: usize = 128;
+
+// A password sent by a client, never printed.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(***)")
+    }
+}
+
+// Body of 'POST /auth/password'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ChangePassword {
+    #[schema(value_type = String, format = Password)]
+    pub current_password: Password,
+    #[schema(value_type = String, format = Password)]
+    pub new_password: Password,
+}
+
+// Body of 'POST /auth/password/forgot'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+// Body of 'POST /auth/password/reset'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ResetPassword {
+    // token of the link mailed by '/auth/password/forgot'
+    pub token: String,
+    #[schema(value_type = String, format = Password)]
+    pub new_password: Password,
+}
+
+// Whether a password matches a hash.
+#[derive(Debug)]
+pub enum Verified {
+    Wrong,
+    // the new hash of the password, when the hash has another cost than the configuration
+    Right { rehash: Option<String> },
+}
+
+// Hashes the passwords, and stores them and their reset tokens.
+#[derive(Clone)]
+pub struct Passwords {
+    inner: Arc<Inner>,
+    db: Db,
+}
+
+struct Inner {
+    params: Params,
+    min_length: usize,
+    reset_ttl_secs: u64,
+    reset_url: String,
+    // hash checked when a user has no password, so its login takes as long as the others
+    dummy: String,
+}
+
+impl Inner {
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    fn hash(&self, password: &Password) -> password_hash::Result<String> {
+        let salt = SaltString::encode_b64(&random_bytes::<16>())?;
+        Ok(self
+            .argon2()
+            .hash_password(password.0.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    fn verify(&self, password: &Password, hash: &str) -> password_hash::Result<bool> {
+        let hash = PasswordHash::new(hash)?;
+        match self.argon2().verify_password(password.0.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // whether the hash was made with another algorithm or cost than the configured ones
+    fn outdated(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let current = &self.params;
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || Params::try_from(&hash).map_or(true, |params| {
+                (params.m_cost(), params.t_cost(), params.p_cost())
+                    != (current.m_cost(), current.t_cost(), current.p_cost())
+            })
+    }
+}
+
+impl Passwords {
+    // The cost is checked by 'Config::validate'.
+    pub fn new(config: &PasswordsConfig, db: Db) -> Self {
+        let mut inner = Inner {
+            params: config.params().unwrap_or_default(),
+            min_length: config.min_length,
+            reset_ttl_secs: config.reset_ttl_secs,
+            reset_url: config.reset_url.clone(),
+            dummy: String::new(),
+        };
+        let password = Password(URL_SAFE_NO_PAD.encode(random_bytes::<16>()));
+        inner.dummy = inner.hash(&password).unwrap_or_default();
+        Self {
+            inner: Arc::new(inner),
+            db,
+        }
+    }
+
+    pub fn check(&self, password: &Password) -> Result<(), String> {
+        let length = password.0.chars().count();
+        if (self.inner.min_length..=MAX_PASSWORD_LEN).contains(&length) {
+            Ok(())
+        } else {
+            Err(format!(
+                "must be {} to {MAX_PASSWORD_LEN} characters",
+                self.inner.min_length
+            ))
+        }
+    }
+
+    pub async fn hash(&self, password: Password) -> Result<String, AppError> {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || inner.hash(&password))
+            .await
+            .map_err(AppError::internal)?
+            .map_err(AppError::internal)
+    }
+
+    // Checks the password against the hash of a user. Without a hash (unknown user, user without
+    // a password) the answer is always wrong, but takes the same time.
+    pub async fn verify(
+        &self,
+        password: Password,
+        hash: Option<String>,
+    ) -> Result<Verified, AppError> {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let Some(hash) = hash else {
+                inner.verify(&password, &inner.dummy)?;
+                return Ok(Verified::Wrong);
+            };
+            if !inner.verify(&password, &hash)? {
+                return Ok(Verified::Wrong);
+            }
+            let rehash = match inner.outdated(&hash) {
+                true => Some(inner.hash(&password)?),
+                false => None,
+            };
+            Ok(Verified::Right { rehash })
+        })
+        .await
+        .map_err(AppError::internal)?
+        .map_err(|err: password_hash::Error| AppError::internal(err))
+    }
+
+    // hash of the password of the user, if it has one
+    async fn get(&self, user: u64) -> Result<Option<String>, DbError> {
+        self.db
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT password_hash FROM users WHERE id = ?1",
+                        [user],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .flatten())
+            })
+            .await
+    }
+
+    // Replaces the password of the user and revokes its sessions, except the one given. The reset
+    // tokens of the user are of no use anymore.
+    async fn set(&self, user: u64, hash: String, keep: Option<String>) -> Result<(), DbError> {
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                set_password(&tx, user, &hash, keep.as_deref())?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    // A new reset token for the active user with this address, if there is one.
+    async fn create_reset(&self, email: String) -> Result<Option<(User, String)>, DbError> {
+        let token = URL_SAFE_NO_PAD.encode(random_bytes::<32>());
+        let expires_at = now() + self.inner.reset_ttl_secs;
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                // the expired tokens are of no use anymore
+                tx.execute("DELETE FROM password_resets WHERE expires_at < ?1", [now()])?;
+                let user = tx
+                    .query_row(
+                        &format!(
+                            "SELECT {USER_COLUMNS} FROM users
+                             WHERE email = ?1 COLLATE NOCASE AND active = 1
+                             ORDER BY id LIMIT 1"
+                        ),
+                        [email],
+                        user_from_row,
+                    )
+                    .optional()?;
+                let Some(user) = user else {
+                    return Ok(None);
+                };
+                tx.execute(
+                    "INSERT INTO password_resets (hash, user_id, expires_at) VALUES (?1, ?2, ?3)",
+                    params![api_keys::hash(&token), user.id, expires_at],
+                )?;
+                tx.commit()?;
+                Ok(Some((user, token)))
+            })
+            .await
+    }
+
+    // the user of a valid reset token
+    async fn reset_user(&self, token: &str) -> Result<Option<u64>, DbError> {
+        let hash = api_keys::hash(token);
+        self.db
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT user_id FROM password_resets WHERE hash = ?1 AND expires_at >= ?2",
+                        params![hash, now()],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    // Uses the reset token to replace the password of its user, unless it was used or expired
+    // meanwhile. Every session of the user is revoked.
+    async fn reset(&self, token: &str, password_hash: String) -> Result<Option<u64>, DbError> {
+        let hash = api_keys::hash(token);
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let user: Option<u64> = tx
+                    .query_row(
+                        "DELETE FROM password_resets WHERE hash = ?1 AND expires_at >= ?2
+                         RETURNING user_id",
+                        params![hash, now()],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let Some(user) = user else {
+                    return Ok(None);
+                };
+                set_password(&tx, user, &password_hash, None)?;
+                tx.commit()?;
+                tracing::info!(user, "password reset");
+                Ok(Some(user))
+            })
+            .await
+    }
+}
+
+// Mails a reset link to the user with this address. Failures are only logged : the client is
+// answered before, whatever happens.
+async fn send_reset(state: AppState, email: String) {
+    let (user, token) = match state.passwords.create_reset(email).await {
+        Ok(Some(reset)) => reset,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::warn!("cannot create a password reset: {err}");
+            return;
+        }
+    };
+    let link = state.passwords.inner.reset_url.replace("{token}", &token);
+    let mail = Mail {
+        to: user.email,
+        subject: String::from("Reset your password"),
+        body: format!(
+            "Hello {},\n\n\
+             To choose a new password, open this link within {} minutes:\n{link}\n\n\
+             If you did not ask for it, ignore this mail: your password is unchanged.\n",
+            user.username,
+            state.passwords.inner.reset_ttl_secs.div_ceil(60)
+        ),
+    };
+    match state.mailer.send(mail).await {
+        Ok(()) => tracing::info!(user = user.id, "password reset mailed"),
+        Err(err) => tracing::warn!(user = user.id, "cannot mail a password reset: {err}"),
+    }
+}
+
+fn set_password(
+    conn: &rusqlite::Connection,
+    user: u64,
+    hash: &str,
+    keep: Option<&str>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE users SET password_hash = ?2 WHERE id = ?1",
+        params![user, hash],
+    )?;
+    conn.execute(
+        "UPDATE sessions SET revoked = 1 WHERE user_id = ?1 AND id IS NOT ?2",
+        params![user, keep],
+    )?;
+    conn.execute("DELETE FROM password_resets WHERE user_id = ?1", [user])?;
+    Ok(())
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/password", post(change_password))
+        .route("/auth/password/forgot", post(forgot_password))
+        .route("/auth/password/reset", post(reset_password))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/password",
+    tag = "auth",
+    security(("bearer" = [])),
+    request_body = ChangePassword,
+    responses(
+        (status = 204, description = "The password is changed, the other sessions of the user are revoked"),
+        (status = 401, description = "Invalid access token", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Wrong current password or invalid new password", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn change_password(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(body): Json<ChangePassword>,
+) -> Result<impl IntoResponse, AppError> {
+    let passwords = state.passwords;
+    let mut violations = Violations::default();
+    // a user without a password resets it instead
+    let current = passwords.get(auth.user.id).await?;
+    if let Verified::Wrong = passwords.verify(body.current_password, current).await? {
+        violations.add("current_password", "is not the password of the user");
+    }
+    violations.check("new_password", passwords.check(&body.new_password));
+    if !violations.is_empty() {
+        return Err(violations.into());
+    }
+    let hash = passwords.hash(body.new_password).await?;
+    passwords
+        .set(auth.user.id, hash, Some(auth.session))
+        .await?;
+    tracing::info!(user = auth.user.id, "password changed");
+    // the audit log records the change, never the hashes
+    let change = AuditChange::new("user.password_changed", format!("user:{}", auth.user.id));
+    Ok((change, StatusCode::NO_CONTENT))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/password/forgot",
+    tag = "auth",
+    request_body = ForgotPassword,
+    responses(
+        (status = 202, description = "A reset link is mailed, if an active user has this address"),
+    ),
+)]
+async fn forgot_password(
+    State(state): State<AppState>,
+    Json(body): Json<ForgotPassword>,
+) -> StatusCode {
+    // answered at once, so the time taken does not tell whether a mail is sent
+    tokio::spawn(send_reset(state, body.email));
+    StatusCode::ACCEPTED
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/password/reset",
+    tag = "auth",
+    request_body = ResetPassword,
+    responses(
+        (status = 204, description = "The password is changed, every session of the user is revoked"),
+        (status = 422, description = "Invalid, used or expired token, or invalid new password", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn reset_password(
+    State(state): State<AppState>,
+    Json(body): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    let passwords = state.passwords;
+    let invalid_token = "is invalid, already used or expired";
+    let mut violations = Violations::default();
+    violations.check("new_password", passwords.check(&body.new_password));
+    // checked before hashing, so an invalid token costs no hash
+    if passwords.reset_user(&body.token).await?.is_none() {
+        violations.add("token", invalid_token);
+    }
+    if !violations.is_empty() {
+        return Err(violations.into());
+    }
+    let hash = passwords.hash(body.new_password).await?;
+    let Some(user) = passwords.reset(&body.token, hash).await? else {
+        let mut violations = Violations::default();
+        violations.add("token", invalid_token);
+        return Err(violations.into());
+    };
+    let change = AuditChange::new("user.password_reset", format!("user:{user}"));
+    Ok((change, StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::users::CreateUser;
+
+    // A config with the cheapest cost of Argon2id, its mails written to 'spool_dir'.
+    fn cheap(spool_dir: &Path) -> Config {
+        let mut config = Config::default();
+        config.passwords.memory_kib = 8;
+        config.passwords.iterations = 1;
+        config.mail.spool_dir = spool_dir.to_path_buf();
+        config
+    }
+
+    fn password(password: &str) -> Password {
+        Password(password.to_string())
+    }
+
+    async fn create_user(state: &AppState) -> User {
+        let body = CreateUser {
+            username: String::from("sam"),
+            email: String::from("sam@example.com"),
+            active: None,
+            sign_in_count: None,
+            password: None,
+        };
+        let user = state.users.create(body, None, Violations::default());
+        user.await.unwrap().unwrap()
+    }
+
+    // the sessions of the user, and whether they are revoked
+    async fn sessions(state: &AppState, user: u64) -> Vec<(String, bool)> {
+        let sessions = state.db.call(move |conn| {
+            let mut statement =
+                conn.prepare("SELECT id, revoked FROM sessions WHERE user_id = ?1 ORDER BY id")?;
+            let sessions = statement.query_map([user], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            Ok(sessions.collect::<rusqlite::Result<Vec<_>>>()?)
+        });
+        sessions.await.unwrap()
+    }
+
+    async fn open_sessions(state: &AppState, user: u64) {
+        let opened = state.db.call(move |conn| {
+            for session in ["a", "b"] {
+                conn.execute(
+                    "INSERT INTO sessions (id, user_id, refresh_jti, expires_at)
+                     VALUES (?1, ?2, '', ?3)",
+                    params![session, user, now() + 60],
+                )?;
+            }
+            Ok(())
+        });
+        opened.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn hashes_with_argon2id_and_rehashes_when_the_cost_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = cheap(dir.path());
+        let db = AppState::test(&config).db;
+        let passwords = Passwords::new(&config.passwords, db.clone());
+
+        let hash = passwords.hash(password("correct horse")).await.unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=8,t=1,p=1$"));
+        let verified = passwords.verify(password("correct horse"), Some(hash.clone()));
+        assert!(matches!(
+            verified.await.unwrap(),
+            Verified::Right { rehash: None }
+        ));
+        let verified = passwords.verify(password("wrong horse"), Some(hash.clone()));
+        assert!(matches!(verified.await.unwrap(), Verified::Wrong));
+        // a user without a password cannot sign in
+        let verified = passwords.verify(password("correct horse"), None);
+        assert!(matches!(verified.await.unwrap(), Verified::Wrong));
+
+        let mut config = config;
+        config.passwords.iterations = 2;
+        let costlier = Passwords::new(&config.passwords, db);
+        let verified = costlier.verify(password("correct horse"), Some(hash));
+        let Verified::Right {
+            rehash: Some(rehash),
+        } = verified.await.unwrap()
+        else {
+            panic!("the password is not hashed again");
+        };
+        assert!(rehash.starts_with("$argon2id$v=19$m=8,t=2,p=1$"));
+    }
+
+    #[tokio::test]
+    async fn uses_a_reset_token_once_before_it_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::test(&cheap(dir.path()));
+        let passwords = state.passwords.clone();
+        let user = create_user(&state).await;
+
+        // the address is not case sensitive
+        let created = passwords.create_reset(String::from("SAM@example.com"));
+        let (_, token) = created.await.unwrap().unwrap();
+        assert_eq!(passwords.reset_user(&token).await.unwrap(), Some(user.id));
+        let reset = passwords.reset(&token, String::from("hash")).await.unwrap();
+        assert_eq!(reset, Some(user.id));
+        assert_eq!(passwords.reset_user(&token).await.unwrap(), None);
+        let reset = passwords.reset(&token, String::from("hash")).await.unwrap();
+        assert_eq!(reset, None);
+
+        let created = passwords.create_reset(String::from("sam@example.com"));
+        let (_, token) = created.await.unwrap().unwrap();
+        let expired = state.db.call(|conn| {
+            conn.execute("UPDATE password_resets SET expires_at = ?1", [now() - 1])?;
+            Ok(())
+        });
+        expired.await.unwrap();
+        assert_eq!(passwords.reset_user(&token).await.unwrap(), None);
+        let reset = passwords.reset(&token, String::from("hash")).await.unwrap();
+        assert_eq!(reset, None);
+    }
+
+    #[tokio::test]
+    async fn answers_the_same_to_a_known_and_an_unknown_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::test(&cheap(dir.path()));
+        create_user(&state).await;
+        let forgot = |email: &str| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/auth/password/forgot")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "email": email }).to_string(),
+                ))
+                .unwrap();
+            routes().with_state(state.clone()).oneshot(request)
+        };
+
+        let response = forgot("frodo@example.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = forgot("sam@example.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        // the mail is sent after the answer
+        let mails = loop {
+            let mails: Vec<_> = fs::read_dir(dir.path()).map_or(Vec::new(), |entries| {
+                entries
+                    .map(|entry| entry.unwrap().path())
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "eml"))
+                    .collect()
+            });
+            if !mails.is_empty() {
+                break mails;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(mails.len(), 1);
+        let mail = fs::read_to_string(&mails[0]).unwrap();
+        assert!(mail.contains("To: sam@example.com\r\n"));
+        assert!(mail.contains("reset-password?token="));
+    }
+
+    #[tokio::test]
+    async fn revokes_the_sessions_when_the_password_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::test(&cheap(dir.path()));
+        let passwords = state.passwords.clone();
+        let user = create_user(&state).await;
+        open_sessions(&state, user.id).await;
+
+        // a change keeps the session it was made with
+        let changed = passwords.set(user.id, String::from("hash"), Some(String::from("a")));
+        changed.await.unwrap();
+        let expected = [(String::from("a"), false), (String::from("b"), true)];
+        assert_eq!(sessions(&state, user.id).await, expected);
+
+        // a reset revokes them all
+        let created = passwords.create_reset(String::from("sam@example.com"));
+        let (_, token) = created.await.unwrap().unwrap();
+        passwords.reset(&token, String::from("hash")).await.unwrap();
+        let expected = [(String::from("a"), true), (String::from("b"), true)];
+        assert_eq!(sessions(&state, user.id).await, expected);
+    }
+}
@@ -0,0 +1,323 @@
+// RATE LIMIT
+// Each client has a quota of requests ('rate_limit' in the configuration). Every request counts
+// against the quota of its IP address, before the authentication, so the requests refused for
+// their credentials are counted too. The requests of an API key or of a signed in user also count
+// against the quota of that client, wherever they come from.
+// The quotas use the generic cell rate algorithm (GCRA), a token bucket needing a single instant per
+// client : the 'theoretical arrival time' (TAT) at which its bucket is full again. Each request
+// pushes the TAT by 'period / requests', and a request pushing it further than 'burst' requests
+// ahead of now is refused with '429 Too Many Requests'.
+// A route with its own quota has its own bucket, the other routes share the default bucket of the
+// client. Every answer tells the client where it stands (draft-ietf-httpapi-ratelimit-headers) :
+//   RateLimit-Limit      requests allowed at once (the burst)
+//   RateLimit-Remaining  requests still allowed now
+//   RateLimit-Reset      seconds until the full quota is available again
+//   RateLimit-Policy     the quota, such as '600;w=60'
+//   Retry-After          seconds before a refused request can be sent again
+// The state lives in memory, so each instance of the server has its own quotas.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::auth::Principal;
+use crate::config::{Quota, RateLimitConfig};
+use crate::error::{AppError, ErrorCode};
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    default: Gcra,
+    routes: HashMap<String, Gcra>,
+    // TAT of each bucket
+    buckets: Mutex<HashMap<Bucket, Instant>>,
+}
+
+// A quota, ready for the computations. Also limits the messages of a WebSocket session, see
+// 'messages.rs'.
+#[derive(Clone)]
+pub struct Gcra {
+    // time between two requests at the sustained rate
+    interval: Duration,
+    // how far ahead of now the TAT can go : 'burst' intervals
+    tolerance: Duration,
+    burst: u32,
+    policy: HeaderValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    ApiKey(u64),
+    User(u64),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Bucket {
+    client: Client,
+    // the route, when it has its own quota
+    route: Option<String>,
+}
+
+// Result of a request against its quota.
+struct Decision {
+    limit: u32,
+    remaining: u32,
+    reset: Duration,
+    // only when the request is refused
+    retry_after: Option<Duration>,
+}
+
+impl Gcra {
+    pub fn new(quota: &Quota) -> Self {
+        // the configuration is validated before : the interval is not zero
+        let interval = quota.interval();
+        let policy = format!("{};w={}", quota.requests, quota.period_secs);
+        Self {
+            interval,
+            tolerance: interval.saturating_mul(quota.burst()),
+            burst: quota.burst(),
+            // digits and ASCII only
+            policy: HeaderValue::from_str(&policy).unwrap_or(HeaderValue::from_static("")),
+        }
+    }
+
+    // Counts a request at 'now' in the bucket whose TAT is 'tat', and updates it if the request is
+    // allowed.
+    fn check(&self, tat: &mut Instant, now: Instant) -> Decision {
+        let next = (*tat).max(now) + self.interval;
+        let ahead = next - now;
+        if ahead > self.tolerance {
+            return Decision {
+                limit: self.burst,
+                remaining: 0,
+                reset: tat.saturating_duration_since(now),
+                retry_after: Some(ahead - self.tolerance),
+            };
+        }
+        *tat = next;
+        Decision {
+            limit: self.burst,
+            remaining: ((self.tolerance - ahead).as_nanos() / self.interval.as_nanos()) as u32,
+            reset: ahead,
+            retry_after: None,
+        }
+    }
+
+    // Counts an event now in the bucket whose TAT is 'tat', returns whether it is allowed.
+    pub fn allow(&self, tat: &mut Instant) -> bool {
+        self.check(tat, Instant::now()).retry_after.is_none()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                default: Gcra::new(&config.default),
+                routes: config
+                    .routes
+                    .iter()
+                    .map(|(route, quota)| (route.clone(), Gcra::new(quota)))
+                    .collect(),
+                buckets: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    fn check(&self, client: Client, route: Option<&str>) -> (Decision, HeaderValue) {
+        let (gcra, route) = match route.and_then(|route| self.inner.routes.get_key_value(route)) {
+            Some((route, gcra)) => (gcra, Some(route.clone())),
+            None => (&self.inner.default, None),
+        };
+        let now = Instant::now();
+        let mut buckets = self
+            .inner
+            .buckets
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let tat = buckets.entry(Bucket { client, route }).or_insert(now);
+        (gcra.check(tat, now), gcra.policy.clone())
+    }
+
+    // Refuses the request over the quota of the client, else lets it through.
+    async fn run(&self, client: Client, request: Request, next: Next) -> Response {
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str);
+        let (decision, policy) = self.check(client.clone(), route);
+
+        let mut response = match decision.retry_after {
+            None => next.run(request).await,
+            Some(retry_after) => {
+                tracing::debug!(?client, "rate limit exceeded");
+                let mut response = AppError::new(ErrorCode::TooManyRequests)
+                    .with_detail(format!(
+                        "the quota of requests is used, retry in {} seconds",
+                        seconds(retry_after)
+                    ))
+                    .into_response();
+                response
+                    .headers_mut()
+                    .insert(axum::http::header::RETRY_AFTER, seconds(retry_after).into());
+                response
+            }
+        };
+        // the quota of the API key or of the user comes first, the one of the address is shared
+        if !response.headers().contains_key(RATELIMIT_LIMIT) {
+            add_headers(response.headers_mut(), &decision, policy);
+        }
+        response
+    }
+
+    // Drops, at each interval, the buckets of the clients that have their full quota again : they
+    // are the same as no bucket at all. Runs until the process exits.
+    pub async fn evict(self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let now = Instant::now();
+            let mut buckets = self
+                .inner
+                .buckets
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+            let before = buckets.len();
+            buckets.retain(|_, tat| *tat > now);
+            tracing::debug!(
+                evicted = before - buckets.len(),
+                remaining = buckets.len(),
+                "rate limit buckets evicted"
+            );
+        }
+    }
+}
+
+// Middleware counting each request against the quota of its IP address. It runs before
+// 'authenticate', so every request is counted, even the ones refused for their credentials.
+pub async fn limit_ip(
+    State(limiter): State<Option<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = limiter else {
+        return next.run(request).await;
+    };
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => limiter.run(Client::Ip(addr.ip()), request, next).await,
+        // no address when the router is not served by 'server.rs'
+        None => next.run(request).await,
+    }
+}
+
+// Middleware counting each request of an API key or of a signed in user against the quota of that
+// client. It runs after 'authenticate', so the client of the request is known.
+pub async fn limit(
+    State(limiter): State<Option<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = match request.extensions().get::<Principal>() {
+        Some(Principal::ApiKey(key)) => Client::ApiKey(key.id),
+        Some(Principal::User { user, .. }) => Client::User(user.id),
+        None => return next.run(request).await,
+    };
+    match limiter {
+        Some(limiter) => limiter.run(client, request, next).await,
+        None => next.run(request).await,
+    }
+}
+
+fn add_headers(headers: &mut HeaderMap, decision: &Decision, policy: HeaderValue) {
+    headers.insert(RATELIMIT_LIMIT, decision.limit.into());
+    headers.insert(RATELIMIT_REMAINING, decision.remaining.into());
+    headers.insert(RATELIMIT_RESET, seconds(decision.reset).into());
+    headers.insert(RATELIMIT_POLICY, policy);
+}
+
+// whole seconds, rounded up so the client never retries too early
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn quota(requests: u32, period_secs: u64, burst: Option<u32>) -> Quota {
+        Quota {
+            requests,
+            period_secs,
+            burst,
+        }
+    }
+
+    #[test]
+    fn allows_the_burst_then_one_request_per_interval() {
+        // a burst of 2, then a request every 10 seconds
+        let gcra = Gcra::new(&quota(6, 60, Some(2)));
+        let start = Instant::now();
+        let mut tat = start;
+
+        let decision = gcra.check(&mut tat, start);
+        assert_eq!((decision.remaining, decision.retry_after), (1, None));
+        let decision = gcra.check(&mut tat, start);
+        assert_eq!((decision.remaining, decision.retry_after), (0, None));
+        let decision = gcra.check(&mut tat, start + Duration::from_secs(4));
+        assert_eq!(decision.retry_after, Some(Duration::from_secs(6)));
+        // a refused request does not use the quota
+        let decision = gcra.check(&mut tat, start + Duration::from_secs(10));
+        assert_eq!((decision.remaining, decision.retry_after), (0, None));
+    }
+
+    #[tokio::test]
+    async fn refuses_the_requests_over_the_quota_of_the_address_with_retry_after() {
+        let config = RateLimitConfig {
+            default: quota(2, 60, None),
+            ..Default::default()
+        };
+        let app = Router::new()
+            .route("/", get(|| async { "Hello, World!" }))
+            .layer(middleware::from_fn_with_state(
+                Some(RateLimiter::new(&config)),
+                limit_ip,
+            ));
+        let send = || {
+            let mut request = Request::new(Body::empty());
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000));
+            request.extensions_mut().insert(ConnectInfo(addr));
+            app.clone().oneshot(request)
+        };
+
+        for remaining in ["1", "0"] {
+            let response = send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[RATELIMIT_REMAINING], remaining);
+        }
+        let response = send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], "30");
+        assert_eq!(response.headers()[RATELIMIT_POLICY], "2;w=60");
+    }
+}
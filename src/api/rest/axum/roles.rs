@@ -0,0 +1,648 @@
+// ROLES
+//   GET    /roles                     lists the roles
+//   POST   /roles                     creates a custom role
+//   PUT    /roles/{name}              replaces the permissions of a custom role
+//   DELETE /roles/{name}              deletes a custom role, and removes it from its users
+//   GET    /users/{id}/roles          lists the roles of a user
+//   PUT    /users/{id}/roles/{name}   gives a role to a user
+//   DELETE /users/{id}/roles/{name}   takes a role back from a user
+// A role is a named set of permissions, the same scopes as the API keys ('users:read', ...).
+// A signed in user has the permissions of all its roles, and none without a role.
+// Three roles are built in and cannot be changed :
+//   admin   every permission, including the ones added by later versions
+//   editor  reads and changes the users
+//   viewer  reads the users
+// A client can only create, give, take back or delete a role with permissions it has itself.
+// The last active user with the role 'admin' keeps it, so the permissions can always be managed:
+// it can neither lose the role, be deactivated nor be deleted.
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{get, post, put},
+    Router,
+};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{format_scopes, parse_scopes, Principal, RequireScope, Scope};
+use crate::db::{Db, DbError};
+use crate::error::{AppError, ErrorCode, Problem};
+use crate::extract::{Id, Json, Path};
+use crate::state::AppState;
+
+// maximum length of the name of a role
+const MAX_NAME_LEN: usize = 32;
+
+// Permissions of the built in roles. They live in the code, so 'admin' gets the new permissions.
+fn builtin_scopes(name: &str) -> Option<Vec<Scope>> {
+    match name {
+        "admin" => Some(Scope::ALL.to_vec()),
+        "editor" => Some(vec![Scope::UsersRead, Scope::UsersWrite]),
+        "viewer" => Some(vec![Scope::UsersRead]),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Role {
+    #[schema(example = "editor")]
+    pub name: String,
+    pub builtin: bool,
+    pub scopes: Vec<Scope>,
+}
+
+// Body of 'POST /roles'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateRole {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+// Body of 'PUT /roles/{name}'.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ReplaceRole {
+    pub scopes: Vec<Scope>,
+}
+
+// Storage of the roles and of their assignment to the users.
+#[derive(Clone)]
+pub struct RoleStore {
+    db: Db,
+}
+
+// What happened to a change of a role.
+pub enum Change {
+    Done,
+    NotFound,
+    Builtin,
+}
+
+// What happened to the removal of a role from a user.
+pub enum Removal {
+    Done,
+    NotAssigned,
+    LastAdmin,
+}
+
+fn role_from_row(row: &Row<'_>) -> rusqlite::Result<Role> {
+    let name: String = row.get(0)?;
+    let scopes: String = row.get(1)?;
+    Ok(Role {
+        scopes: builtin_scopes(&name).unwrap_or_else(|| parse_scopes(&scopes)),
+        builtin: builtin_scopes(&name).is_some(),
+        name,
+    })
+}
+
+fn select_role(conn: &Connection, name: &str) -> rusqlite::Result<Option<Role>> {
+    conn.query_row(
+        "SELECT name, scopes FROM roles WHERE name = ?1",
+        [name],
+        role_from_row,
+    )
+    .optional()
+}
+
+// Number of the active users with the role 'admin'.
+pub fn active_admins(conn: &Connection) -> rusqlite::Result<u64> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM user_roles JOIN users ON users.id = user_roles.user_id
+         WHERE user_roles.role = 'admin' AND users.active",
+        [],
+        |row| row.get(0),
+    )
+}
+
+// Answer to a change that would leave no active user with the role 'admin'.
+pub fn last_admin(user: u64) -> AppError {
+    AppError::new(ErrorCode::Conflict).with_detail(format!(
+        "user {user} is the last active one with the role 'admin', give it to another user first"
+    ))
+}
+
+// The permissions of a user, given by all its roles.
+pub fn user_scopes(conn: &Connection, user: u64) -> rusqlite::Result<Vec<Scope>> {
+    let mut statement = conn.prepare(
+        "SELECT roles.name, roles.scopes FROM roles
+         JOIN user_roles ON user_roles.role = roles.name
+         WHERE user_roles.user_id = ?1",
+    )?;
+    let mut scopes = Vec::new();
+    for role in statement.query_map([user], role_from_row)? {
+        scopes.extend(role?.scopes);
+    }
+    scopes.sort_by_key(|scope| scope.as_str());
+    scopes.dedup();
+    Ok(scopes)
+}
+
+impl RoleStore {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    pub async fn list(&self) -> Result<Vec<Role>, DbError> {
+        self.db
+            .call(|conn| {
+                let mut statement = conn.prepare("SELECT name, scopes FROM roles ORDER BY name")?;
+                let roles = statement
+                    .query_map([], role_from_row)?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(roles)
+            })
+            .await
+    }
+
+    pub async fn get(&self, name: String) -> Result<Option<Role>, DbError> {
+        self.db
+            .call(move |conn| Ok(select_role(conn, &name)?))
+            .await
+    }
+
+    // Returns 'None' when a role with this name already exists.
+    pub async fn create(&self, body: CreateRole) -> Result<Option<Role>, DbError> {
+        self.db
+            .call(move |conn| {
+                let created = conn.execute(
+                    "INSERT INTO roles (name, scopes) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                    params![body.name, format_scopes(&body.scopes)],
+                )?;
+                if created == 0 {
+                    return Ok(None);
+                }
+                Ok(select_role(conn, &body.name)?)
+            })
+            .await
+    }
+
+    pub async fn replace(&self, name: String, scopes: Vec<Scope>) -> Result<Change, DbError> {
+        if builtin_scopes(&name).is_some() {
+            return Ok(Change::Builtin);
+        }
+        self.db
+            .call(move |conn| {
+                let updated = conn.execute(
+                    "UPDATE roles SET scopes = ?2 WHERE name = ?1",
+                    params![name, format_scopes(&scopes)],
+                )?;
+                Ok(if updated > 0 {
+                    Change::Done
+                } else {
+                    Change::NotFound
+                })
+            })
+            .await
+    }
+
+    // The users having the role lose it.
+    pub async fn delete(&self, name: String) -> Result<Change, DbError> {
+        if builtin_scopes(&name).is_some() {
+            return Ok(Change::Builtin);
+        }
+        self.db
+            .call(move |conn| {
+                let deleted = conn.execute("DELETE FROM roles WHERE name = ?1", [name])?;
+                Ok(if deleted > 0 {
+                    Change::Done
+                } else {
+                    Change::NotFound
+                })
+            })
+            .await
+    }
+
+    // The roles of a user, 'None' when the user does not exist.
+    pub async fn of_user(&self, user: u64) -> Result<Option<Vec<Role>>, DbError> {
+        self.db
+            .call(move |conn| {
+                let exists = conn
+                    .query_row("SELECT 1 FROM users WHERE id = ?1", [user], |_| Ok(()))
+                    .optional()?;
+                if exists.is_none() {
+                    return Ok(None);
+                }
+                let mut statement = conn.prepare(
+                    "SELECT roles.name, roles.scopes FROM roles
+                     JOIN user_roles ON user_roles.role = roles.name
+                     WHERE user_roles.user_id = ?1 ORDER BY roles.name",
+                )?;
+                let roles = statement
+                    .query_map([user], role_from_row)?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(Some(roles))
+            })
+            .await
+    }
+
+    // Returns whether the user exists. Giving a role twice changes nothing.
+    pub async fn assign(&self, user: u64, role: String) -> Result<bool, DbError> {
+        self.db
+            .call(move |conn| {
+                let exists = conn
+                    .query_row("SELECT 1 FROM users WHERE id = ?1", [user], |_| Ok(()))
+                    .optional()?;
+                if exists.is_none() {
+                    return Ok(false);
+                }
+                conn.execute(
+                    "INSERT INTO user_roles (user_id, role) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                    params![user, role],
+                )?;
+                Ok(true)
+            })
+            .await
+    }
+
+    // Takes the role back from the user, unless it is the last active one with the role 'admin'.
+    pub async fn unassign(&self, user: u64, role: String) -> Result<Removal, DbError> {
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let admins = active_admins(&tx)?;
+                let deleted = tx.execute(
+                    "DELETE FROM user_roles WHERE user_id = ?1 AND role = ?2",
+                    params![user, role],
+                )?;
+                if deleted == 0 {
+                    return Ok(Removal::NotAssigned);
+                }
+                // dropping the transaction rolls the deletion back
+                if admins > 0 && active_admins(&tx)? == 0 {
+                    return Ok(Removal::LastAdmin);
+                }
+                tx.commit()?;
+                Ok(Removal::Done)
+            })
+            .await
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/roles", get(list_roles).require(Scope::RolesRead))
+        .route("/roles", post(create_role).require(Scope::RolesWrite))
+        .route(
+            "/roles/{name}",
+            put(replace_role)
+                .delete(delete_role)
+                .require(Scope::RolesWrite),
+        )
+        .route(
+            "/users/{id}/roles",
+            get(list_user_roles).require(Scope::RolesRead),
+        )
+        .route(
+            "/users/{id}/roles/{name}",
+            put(assign_role)
+                .delete(unassign_role)
+                .require(Scope::RolesWrite),
+        )
+}
+
+fn role_not_found(name: &str) -> AppError {
+    AppError::not_found(format!("role '{name}' does not exist"))
+}
+
+fn builtin(name: &str) -> AppError {
+    AppError::forbidden(format!("the built in role '{name}' cannot be changed"))
+}
+
+// a client cannot grant, nor take back, more than what it has
+fn check_granted(principal: &Principal, scopes: &[Scope]) -> Result<(), AppError> {
+    match scopes.iter().find(|scope| !principal.has_scope(**scope)) {
+        Some(scope) => Err(AppError::forbidden(format!(
+            "the permission '{scope}' of the role is not granted to the caller"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn check_name(name: &str) -> Result<(), AppError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::new(ErrorCode::InvalidBody).with_field(
+            "name",
+            format!("must be 1 to {MAX_NAME_LEN} characters among a-z, 0-9, '-' and '_'"),
+        ))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/roles",
+    tag = "roles",
+    security(("bearer" = ["roles:read"])),
+    responses((status = 200, description = "Every role", body = [Role])),
+)]
+async fn list_roles(State(state): State<AppState>) -> Result<Json<Vec<Role>>, AppError> {
+    Ok(Json(state.roles.list().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/roles",
+    tag = "roles",
+    security(("bearer" = ["roles:write"])),
+    request_body = CreateRole,
+    responses(
+        (status = 201, description = "The created role", body = Role),
+        (status = 403, description = "A permission of the role is not granted to the caller", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The role already exists", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn create_role(
+    State(state): State<AppState>,
+    principal: Principal,
+    Json(body): Json<CreateRole>,
+) -> Result<impl IntoResponse, AppError> {
+    check_name(&body.name)?;
+    check_granted(&principal, &body.scopes)?;
+    let name = body.name.clone();
+    let role = state.roles.create(body).await?.ok_or_else(|| {
+        AppError::new(ErrorCode::Conflict).with_detail(format!("role '{name}' already exists"))
+    })?;
+    let location = format!("/roles/{}", role.name);
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(role),
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/roles/{name}",
+    tag = "roles",
+    security(("bearer" = ["roles:write"])),
+    params(("name" = String, Path, description = "Name of the role")),
+    request_body = ReplaceRole,
+    responses(
+        (status = 200, description = "The role with its new permissions", body = Role),
+        (status = 403, description = "Built in role, or permission not granted to the caller", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such role", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn replace_role(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(name): Path<String>,
+    Json(body): Json<ReplaceRole>,
+) -> Result<Json<Role>, AppError> {
+    check_granted(&principal, &body.scopes)?;
+    match state.roles.replace(name.clone(), body.scopes).await? {
+        Change::Done => {}
+        Change::NotFound => return Err(role_not_found(&name)),
+        Change::Builtin => return Err(builtin(&name)),
+    }
+    let role = state.roles.get(name.clone()).await?;
+    role.map(Json).ok_or_else(|| role_not_found(&name))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/roles/{name}",
+    tag = "roles",
+    security(("bearer" = ["roles:write"])),
+    params(("name" = String, Path, description = "Name of the role")),
+    responses(
+        (status = 204, description = "The role is deleted"),
+        (status = 403, description = "Built in role, or permission not granted to the caller", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such role", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn delete_role(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let role = state
+        .roles
+        .get(name.clone())
+        .await?
+        .ok_or_else(|| role_not_found(&name))?;
+    check_granted(&principal, &role.scopes)?;
+    match state.roles.delete(name.clone()).await? {
+        Change::Done => Ok(StatusCode::NO_CONTENT),
+        Change::NotFound => Err(role_not_found(&name)),
+        Change::Builtin => Err(builtin(&name)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{id}/roles",
+    tag = "roles",
+    security(("bearer" = ["roles:read"])),
+    params(("id" = u64, Path, description = "Identifier of the user")),
+    responses(
+        (status = 200, description = "The roles of the user", body = [Role]),
+        (status = 404, description = "No such user", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn list_user_roles(
+    State(state): State<AppState>,
+    Path(Id(id)): Path<Id>,
+) -> Result<Json<Vec<Role>>, AppError> {
+    let roles = state.roles.of_user(id).await?;
+    roles
+        .map(Json)
+        .ok_or_else(|| AppError::not_found(format!("user {id} does not exist")))
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{id}/roles/{name}",
+    tag = "roles",
+    security(("bearer" = ["roles:write"])),
+    params(
+        ("id" = u64, Path, description = "Identifier of the user"),
+        ("name" = String, Path, description = "Name of the role"),
+    ),
+    responses(
+        (status = 204, description = "The user has the role"),
+        (status = 403, description = "A permission of the role is not granted to the caller", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such user or role", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn assign_role(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path((Id(id), name)): Path<(Id, String)>,
+) -> Result<StatusCode, AppError> {
+    let role = state
+        .roles
+        .get(name.clone())
+        .await?
+        .ok_or_else(|| role_not_found(&name))?;
+    check_granted(&principal, &role.scopes)?;
+    if !state.roles.assign(id, name.clone()).await? {
+        return Err(AppError::not_found(format!("user {id} does not exist")));
+    }
+    tracing::info!(user = id, role = name, "role assigned");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/roles/{name}",
+    tag = "roles",
+    security(("bearer" = ["roles:write"])),
+    params(
+        ("id" = u64, Path, description = "Identifier of the user"),
+        ("name" = String, Path, description = "Name of the role"),
+    ),
+    responses(
+        (status = 204, description = "The user no longer has the role"),
+        (status = 403, description = "A permission of the role is not granted to the caller", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such role, or the user does not have it", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The user is the last administrator", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn unassign_role(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path((Id(id), name)): Path<(Id, String)>,
+) -> Result<StatusCode, AppError> {
+    let role = state
+        .roles
+        .get(name.clone())
+        .await?
+        .ok_or_else(|| role_not_found(&name))?;
+    check_granted(&principal, &role.scopes)?;
+    match state.roles.unassign(id, name.clone()).await? {
+        Removal::Done => {}
+        Removal::NotAssigned => {
+            return Err(AppError::not_found(format!(
+                "user {id} does not have the role '{name}'"
+            )))
+        }
+        Removal::LastAdmin => return Err(last_admin(id)),
+    }
+    tracing::info!(user = id, role = name, "role unassigned");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::etag::IfMatch;
+    use crate::users::{CreateUser, Outcome, User};
+    use crate::validation::Violations;
+
+    async fn create_user(state: &AppState, username: &str) -> u64 {
+        let body = CreateUser {
+            username: username.to_string(),
+            email: format!("{username}@example.com"),
+            active: None,
+            sign_in_count: None,
+            password: None,
+        };
+        let user = state.users.create(body, None, Violations::default());
+        user.await.unwrap().unwrap().id
+    }
+
+    #[tokio::test]
+    async fn keeps_the_role_admin_of_its_last_user() {
+        let state = AppState::test(&Config::default());
+        let (alice, bob) = (
+            create_user(&state, "alice").await,
+            create_user(&state, "bob").await,
+        );
+        let admin = String::from("admin");
+        for user in [alice, bob] {
+            assert!(state.roles.assign(user, admin.clone()).await.unwrap());
+        }
+
+        let removal = state.roles.unassign(alice, admin.clone()).await.unwrap();
+        assert!(matches!(removal, Removal::Done));
+        let removal = state.roles.unassign(bob, admin.clone()).await.unwrap();
+        assert!(matches!(removal, Removal::LastAdmin));
+        let roles = state.roles.of_user(bob).await.unwrap().unwrap();
+        assert_eq!(roles[0].name, "admin");
+        let removal = state.roles.unassign(alice, admin).await.unwrap();
+        assert!(matches!(removal, Removal::NotAssigned));
+    }
+
+    #[tokio::test]
+    async fn keeps_its_last_active_admin_from_being_deactivated() {
+        let state = AppState::test(&Config::default());
+        let (alice, bob) = (
+            create_user(&state, "alice").await,
+            create_user(&state, "bob").await,
+        );
+        for user in [alice, bob] {
+            assert!(state
+                .roles
+                .assign(user, String::from("admin"))
+                .await
+                .unwrap());
+        }
+        let deactivate = |user: &mut User| user.active = false;
+
+        let outcome = state
+            .users
+            .modify(alice, IfMatch::any(), Violations::default(), deactivate);
+        assert!(matches!(outcome.await.unwrap(), Outcome::Done(_)));
+        let outcome = state
+            .users
+            .modify(bob, IfMatch::any(), Violations::default(), deactivate);
+        assert!(matches!(outcome.await.unwrap(), Outcome::LastAdmin));
+        assert!(state.users.get(bob).await.unwrap().unwrap().active);
+        // an inactive admin is not counted : bob cannot lose the role either
+        let removal = state
+            .roles
+            .unassign(bob, String::from("admin"))
+            .await
+            .unwrap();
+        assert!(matches!(removal, Removal::LastAdmin));
+    }
+
+    #[tokio::test]
+    async fn keeps_its_last_active_admin_from_being_deleted() {
+        let state = AppState::test(&Config::default());
+        let (alice, bob) = (
+            create_user(&state, "alice").await,
+            create_user(&state, "bob").await,
+        );
+        for user in [alice, bob] {
+            assert!(state
+                .roles
+                .assign(user, String::from("admin"))
+                .await
+                .unwrap());
+        }
+
+        let outcome = state.users.delete(alice, IfMatch::any()).await.unwrap();
+        assert!(matches!(outcome, Outcome::Done(_)));
+        let outcome = state.users.delete(bob, IfMatch::any()).await.unwrap();
+        assert!(matches!(outcome, Outcome::LastAdmin));
+        let roles = state.roles.of_user(bob).await.unwrap().unwrap();
+        assert_eq!(roles[0].name, "admin");
+    }
+
+    #[test]
+    fn refuses_to_take_back_a_permission_the_caller_does_not_have() {
+        let principal = Principal::ApiKey(crate::api_keys::ApiKey {
+            id: 1,
+            name: String::from("roles"),
+            prefix: String::from("rak_1a2b3c4d"),
+            scopes: vec![Scope::RolesWrite, Scope::UsersRead],
+            created_at: 0,
+            last_used_at: None,
+            revoked_at: None,
+        });
+        assert!(check_granted(&principal, &builtin_scopes("viewer").unwrap()).is_ok());
+        assert!(check_granted(&principal, &builtin_scopes("editor").unwrap()).is_err());
+    }
+}
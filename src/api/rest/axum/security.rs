@@ -0,0 +1,365 @@
+// SECURITY
+// Protections of the HTTP layer, all driven by the configuration :
+//   - CORS ('cors') : the browsers only let the front-ends of the allowed origins call the API.
+//     The preflight requests ('OPTIONS' with 'Access-Control-Request-Method') are answered before
+//     reaching the routes.
+//   - security headers ('security_headers') : added to every answer that does not already have
+//     them, so a handler can still set its own :
+//       Strict-Transport-Security  HTTPS only, for the next connections (only sent with TLS)
+//       Content-Security-Policy    what a page served by the API may load
+//       X-Content-Type-Options     the browser trusts the 'Content-Type' of the answer
+//       X-Frame-Options            the pages cannot be framed by other sites
+//       Referrer-Policy            what the browser tells the next site about this one
+//   - body limits ('limits') : a body larger than the limit of its route is refused with
+//     '413 Payload Too Large', at once when its 'Content-Length' is known, else once the limit is
+//     reached while it is read.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::Limited;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::{CorsConfig, LimitsConfig, SecurityHeadersConfig};
+use crate::error::{AppError, ErrorCode};
+
+// The CORS layer, none when no origin is allowed.
+pub fn cors(config: &CorsConfig) -> Option<CorsLayer> {
+    if config.allowed_origins.is_empty() {
+        return None;
+    }
+    // every value is checked by the validation of the configuration
+    let origin = if config.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+    let methods: Vec<Method> = config
+        .allowed_methods
+        .iter()
+        .filter_map(|method| method.parse().ok())
+        .collect();
+    let names = |names: &[String]| -> Vec<HeaderName> {
+        names.iter().filter_map(|name| name.parse().ok()).collect()
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods(methods)
+            .allow_headers(names(&config.allowed_headers))
+            .expose_headers(names(&config.exposed_headers))
+            .allow_credentials(config.allow_credentials)
+            .max_age(config.max_age()),
+    )
+}
+
+// The security headers of every answer.
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig, tls: bool) -> Self {
+        let mut headers = Vec::new();
+        if config.enabled {
+            headers.push((
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ));
+            // a browser ignores it over plain HTTP
+            if tls && config.hsts_max_age_secs > 0 {
+                let mut hsts = format!("max-age={}", config.hsts_max_age_secs);
+                if config.hsts_include_subdomains {
+                    hsts.push_str("; includeSubDomains");
+                }
+                headers.extend(
+                    HeaderValue::from_str(&hsts)
+                        .ok()
+                        .map(|value| (header::STRICT_TRANSPORT_SECURITY, value)),
+                );
+            }
+            let values = [
+                (
+                    header::CONTENT_SECURITY_POLICY,
+                    &config.content_security_policy,
+                ),
+                (header::X_FRAME_OPTIONS, &config.frame_options),
+                (header::REFERRER_POLICY, &config.referrer_policy),
+            ];
+            for (name, value) in values {
+                if let Ok(value) = HeaderValue::from_str(value) {
+                    if !value.is_empty() {
+                        headers.push((name, value));
+                    }
+                }
+            }
+        }
+        Self {
+            headers: Arc::new(headers),
+        }
+    }
+}
+
+// Middleware adding the security headers missing from the answer.
+pub async fn security_headers(
+    State(security): State<SecurityHeaders>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    for (name, value) in security.headers.iter() {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+// The maximum size of the bodies, for each route.
+#[derive(Clone)]
+pub struct BodyLimits {
+    inner: Arc<(usize, HashMap<String, usize>)>,
+}
+
+impl BodyLimits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        let routes = config
+            .routes
+            .iter()
+            .map(|(route, max)| (route.clone(), *max))
+            .collect();
+        Self {
+            inner: Arc::new((config.max_body_bytes, routes)),
+        }
+    }
+
+    fn of(&self, route: Option<&str>) -> usize {
+        let (default, routes) = &*self.inner;
+        route
+            .and_then(|route| routes.get(route))
+            .copied()
+            .unwrap_or(*default)
+    }
+}
+
+// Middleware limiting the size of the body to the limit of its route. The body extractors such as
+// 'Json' refuse a body over the limit with a 'payload_too_large' problem.
+pub async fn body_limit(
+    State(limits): State<BodyLimits>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    let max = limits.of(route);
+    let length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > max as u64) {
+        return AppError::new(ErrorCode::PayloadTooLarge)
+            .with_detail(format!("the body is larger than {max} bytes"))
+            .into_response();
+    }
+    let request = request.map(|body| Body::new(Limited::new(body, max)));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Bytes,
+        http::{HeaderMap, StatusCode},
+        middleware,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::extract::Json;
+
+    // A route with the default limit and one with its own limit.
+    fn limited() -> Router {
+        let config = LimitsConfig {
+            max_body_bytes: 16,
+            routes: [(String::from("/large"), 64)].into(),
+            ..LimitsConfig::default()
+        };
+        let echo = |Json(body): Json<serde_json::Value>| async move { Json(body) };
+        Router::new()
+            .route("/", post(echo))
+            .route("/large", post(echo))
+            .layer(middleware::from_fn_with_state(
+                BodyLimits::new(&config),
+                body_limit,
+            ))
+    }
+
+    // A JSON body of about 'size' bytes.
+    fn json_of(size: usize) -> String {
+        format!("\"{}\"", "a".repeat(size - 2))
+    }
+
+    async fn post_to(path: &str, body: Body, length: Option<usize>) -> StatusCode {
+        let mut request = Request::post(path).header(header::CONTENT_TYPE, "application/json");
+        if let Some(length) = length {
+            request = request.header(header::CONTENT_LENGTH, length);
+        }
+        let request = request.body(body).unwrap();
+        limited().oneshot(request).await.unwrap().status()
+    }
+
+    // A body sent in chunks, without a 'Content-Length'.
+    fn streamed(body: String) -> Body {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = body
+            .into_bytes()
+            .chunks(4)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Body::from_stream(futures_util::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn refuses_a_body_larger_than_its_content_length_allows() {
+        assert_eq!(
+            post_to("/", Body::from(json_of(16)), Some(16)).await,
+            StatusCode::OK
+        );
+        // refused before the body is read : the body sent does not even matter
+        assert_eq!(
+            post_to("/", Body::empty(), Some(17)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_a_streamed_body_once_it_reaches_the_limit() {
+        assert_eq!(
+            post_to("/", streamed(json_of(16)), None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            post_to("/", streamed(json_of(17)), None).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_the_limit_of_the_route_instead_of_the_default_one() {
+        assert_eq!(
+            post_to("/large", Body::from(json_of(64)), Some(64)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            post_to("/large", streamed(json_of(64)), None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            post_to("/large", Body::from(json_of(65)), Some(65)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            post_to("/large", streamed(json_of(65)), None).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    // The headers of an answer of a route setting its own 'X-Frame-Options'.
+    async fn headers_of(tls: bool) -> HeaderMap {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { [(header::X_FRAME_OPTIONS, "SAMEORIGIN")] }),
+            )
+            .layer(middleware::from_fn_with_state(
+                SecurityHeaders::new(&SecurityHeadersConfig::default(), tls),
+                security_headers,
+            ));
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap().headers().clone()
+    }
+
+    #[tokio::test]
+    async fn sends_hsts_only_with_tls() {
+        let headers = headers_of(true).await;
+        assert_eq!(
+            headers[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=31536000; includeSubDomains"
+        );
+        let headers = headers_of(false).await;
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::REFERRER_POLICY], "no-referrer");
+    }
+
+    #[tokio::test]
+    async fn keeps_a_header_set_by_the_handler() {
+        let headers = headers_of(false).await;
+        let frame_options: Vec<_> = headers.get_all(header::X_FRAME_OPTIONS).iter().collect();
+        assert_eq!(frame_options, ["SAMEORIGIN"]);
+    }
+
+    #[tokio::test]
+    async fn answers_the_preflight_of_the_allowed_origins_only() {
+        let config = CorsConfig {
+            allowed_origins: vec![String::from("https://app.example.com")],
+            ..CorsConfig::default()
+        };
+        let app = Router::new()
+            .route("/", post(|| async { "reached" }))
+            .layer(cors(&config).unwrap());
+        let preflight = |origin: &str| {
+            Request::options("/")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(preflight("https://app.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        let methods = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("POST"));
+        let allowed = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(allowed.contains("content-type"));
+
+        // the browser refuses the call without an 'Access-Control-Allow-Origin' for its origin
+        let response = app
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,328 @@
+// SERVER
+// 'axum::serve' stops instantly when the process receives a signal, dropping the requests in
+// progress. This module runs its own accept loop instead, in order to shut down gracefully :
+//   1. on SIGTERM or SIGINT, the listener is closed, so new connections are refused
+//   2. the open connections finish their current request and are closed (draining)
+//   3. the connections still open after the drain deadline are aborted
+//   4. a report of the shutdown is returned to the caller
+// With a TLS acceptor, the handshake of each connection runs in the task of the connection, so a
+// slow client does not block the accept loop.
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use tokio::{net::TcpListener, sync::watch, task::JoinSet};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+// maximum duration of a TLS handshake, the connection is closed after it
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What happened during the shutdown, logged once the server is stopped.
+#[derive(Debug)]
+pub struct ShutdownReport {
+    // the signal that started the shutdown
+    pub signal: &'static str,
+    // connections accepted since the start of the server
+    pub accepted: u64,
+    // connections still open when the signal was received
+    pub draining: usize,
+    // connections aborted because they were still open after the drain deadline
+    pub aborted: usize,
+    // duration of the drain
+    pub elapsed: Duration,
+}
+
+// Serves the application on the listener until 'shutdown' completes, then drains the open
+// connections for at most 'drain_deadline'. The connections are encrypted when 'tls' is given.
+// 'shutdown' resolves to the name of what stopped the server, see 'shutdown_signal'.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = &'static str>,
+    drain_deadline: Duration,
+) -> ShutdownReport {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    let mut connections = JoinSet::new();
+    let mut accepted = 0;
+
+    tokio::pin!(shutdown);
+    let signal =
+        loop {
+            let (stream, remote) = tokio::select! {
+                signal = &mut shutdown => break signal,
+                accept = listener.accept() => match accept {
+                    Ok(accept) => accept,
+                    Err(err) => {
+                        // usually a lack of file descriptors : wait a bit instead of spinning
+                        tracing::warn!("cannot accept a connection: {err}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+            };
+            accepted += 1;
+
+            // each request knows the address of the client, for handlers using 'ConnectInfo'
+            let app = app.clone();
+            let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(remote));
+                app.clone().oneshot(request)
+            });
+            let watcher = graceful.watcher();
+            match &tls {
+                None => {
+                    let connection = builder
+                        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                        .into_owned();
+                    connections.spawn(async move {
+                        let _ = watcher.watch(connection).await;
+                    });
+                }
+                Some(acceptor) => {
+                    let (acceptor, builder) = (acceptor.clone(), builder.clone());
+                    connections.spawn(async move {
+                        let stream =
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                                .await
+                            {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(err)) => {
+                                    tracing::debug!(%remote, "TLS handshake failed: {err}");
+                                    return;
+                                }
+                                Err(_) => {
+                                    tracing::debug!(%remote, "TLS handshake timed out");
+                                    return;
+                                }
+                            };
+                        let connection =
+                            builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                        let _ = watcher.watch(connection).await;
+                    });
+                }
+            }
+
+            // forget about the connections already closed
+            while connections.try_join_next().is_some() {}
+        };
+
+    // from now on, new connections are refused by the system
+    drop(listener);
+    while connections.try_join_next().is_some() {}
+    let draining = connections.len();
+    let started = Instant::now();
+
+    // the open connections are asked to close once their current request is answered
+    let aborted = match tokio::time::timeout(drain_deadline, graceful.shutdown()).await {
+        Ok(()) => 0,
+        Err(_) => {
+            while connections.try_join_next().is_some() {}
+            let remaining = connections.len();
+            connections.abort_all();
+            remaining
+        }
+    };
+
+    ShutdownReport {
+        signal,
+        accepted,
+        draining,
+        aborted,
+        elapsed: started.elapsed(),
+    }
+}
+
+// Sends the signal received by the process to every server, so they all stop together.
+pub fn broadcast_signal() -> watch::Receiver<Option<&'static str>> {
+    let (sender, receiver) = watch::channel(None);
+    tokio::spawn(async move {
+        let _ = sender.send(Some(shutdown_signal().await));
+    });
+    receiver
+}
+
+// Completes with the signal sent by 'broadcast_signal'.
+pub async fn stopped(mut signal: watch::Receiver<Option<&'static str>>) -> &'static str {
+    let received = signal
+        .wait_for(Option::is_some)
+        .await
+        .map(|signal| signal.unwrap_or_default());
+    match received {
+        Ok(signal) => signal,
+        // the sender is gone without any signal : nothing can stop the server anymore
+        Err(_) => std::future::pending().await,
+    }
+}
+
+// Completes when the process receives SIGINT (Ctrl+C) or SIGTERM (sent by container runtimes to
+// stop a container), and returns the name of the signal.
+pub async fn shutdown_signal() -> &'static str {
+    let ctrl_c = async {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => "SIGINT",
+            Err(err) => {
+                tracing::error!("cannot listen to SIGINT: {err}");
+                std::future::pending().await
+            }
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+                "SIGTERM"
+            }
+            Err(err) => {
+                tracing::error!("cannot listen to SIGTERM: {err}");
+                std::future::pending().await
+            }
+        }
+    };
+    // there is no SIGTERM outside of unix systems
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<&'static str>();
+
+    tokio::select! {
+        signal = ctrl_c => signal,
+        signal = terminate => signal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::pin::pin;
+    use std::process::Command;
+    use std::task::Poll;
+
+    use axum::routing::get;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::{oneshot, Mutex},
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    // the signals are sent to the whole process : the tests sending them run one at a time
+    static SIGNALS: Mutex<()> = Mutex::const_new(());
+
+    // Starts a server stopped by the signals of the process, answering '/slow' after 'delay'.
+    // Returns once the server listens to the signals.
+    async fn spawn(
+        delay: Duration,
+        drain_deadline: Duration,
+    ) -> (SocketAddr, JoinHandle<ShutdownReport>) {
+        let app = Router::new().route(
+            "/slow",
+            get(move || async move {
+                tokio::time::sleep(delay).await;
+                "done"
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (listening, ready) = oneshot::channel();
+        // the listeners of the signals are registered by the first poll
+        let shutdown = async move {
+            let mut signal = pin!(shutdown_signal());
+            if let Poll::Ready(signal) = futures_util::poll!(signal.as_mut()) {
+                return signal;
+            }
+            let _ = listening.send(());
+            signal.await
+        };
+        let server = tokio::spawn(serve(listener, app, None, shutdown, drain_deadline));
+        ready.await.unwrap();
+        (addr, server)
+    }
+
+    // Sends a request to '/slow' and returns the connection, to read the answer later.
+    async fn request_slow(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        stream
+    }
+
+    fn kill(signal: &str) {
+        let status = Command::new("kill")
+            .args(["-s", signal, &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    // the answer until the server closes the connection, empty when it was aborted
+    async fn read_answer(mut stream: TcpStream) -> String {
+        let mut answer = Vec::new();
+        let _ = stream.read_to_end(&mut answer).await;
+        String::from_utf8_lossy(&answer).into_owned()
+    }
+
+    #[tokio::test]
+    async fn drains_the_requests_in_progress_on_sigterm() {
+        let _signals = SIGNALS.lock().await;
+        let (addr, server) = spawn(Duration::from_millis(600), Duration::from_secs(5)).await;
+        let stream = request_slow(addr).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        kill("TERM");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the request is still running, but the new connections are refused
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        let answer = read_answer(stream).await;
+        assert!(answer.starts_with("HTTP/1.1 200 OK"), "{answer}");
+        assert!(answer.ends_with("done"), "{answer}");
+        let report = server.await.unwrap();
+        assert_eq!(report.signal, "SIGTERM");
+        assert_eq!(report.accepted, 1);
+        assert_eq!(report.draining, 1);
+        assert_eq!(report.aborted, 0);
+    }
+
+    #[tokio::test]
+    async fn aborts_the_requests_still_running_after_the_drain_deadline_on_sigint() {
+        let _signals = SIGNALS.lock().await;
+        let (addr, server) = spawn(Duration::from_secs(30), Duration::from_millis(200)).await;
+        let stream = request_slow(addr).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        kill("INT");
+        let report = server.await.unwrap();
+        assert_eq!(report.signal, "SIGINT");
+        assert_eq!(report.draining, 1);
+        assert_eq!(report.aborted, 1);
+        assert!(report.elapsed >= Duration::from_millis(200));
+        assert!(report.elapsed < Duration::from_secs(30));
+        assert_eq!(read_answer(stream).await, "");
+    }
+
+    #[tokio::test]
+    async fn stops_at_once_without_connections() {
+        let _signals = SIGNALS.lock().await;
+        let (_, server) = spawn(Duration::ZERO, Duration::from_secs(5)).await;
+
+        kill("TERM");
+        let report = server.await.unwrap();
+        assert_eq!(report.signal, "SIGTERM");
+        assert_eq!(report.accepted, 0);
+        assert_eq!(report.draining, 0);
+        assert_eq!(report.aborted, 0);
+    }
+}
@@ -0,0 +1,90 @@
+// STATE
+// Everything shared by the handlers. It is cloned for each request, so every field is cheap to
+// clone (usually an 'Arc').
+use crate::api_keys::ApiKeyStore;
+use crate::audit::AuditLog;
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::cookies::CookieSessions;
+use crate::db::Db;
+use crate::events::UserEvents;
+use crate::logging::LogHandle;
+use crate::mail::Mailer;
+use crate::messages::Hub;
+use crate::metrics::Metrics;
+use crate::passwords::Passwords;
+use crate::roles::RoleStore;
+use crate::users::UserStore;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub api_keys: ApiKeyStore,
+    // None when 'audit.enabled' is false
+    pub audit: Option<AuditLog>,
+    pub auth: Auth,
+    pub cookies: CookieSessions,
+    pub db: Db,
+    pub events: UserEvents,
+    pub hub: Hub,
+    pub logs: LogHandle,
+    pub mailer: Mailer,
+    pub metrics: Metrics,
+    pub passwords: Passwords,
+    pub roles: RoleStore,
+    pub users: UserStore,
+}
+
+impl AppState {
+    pub fn new(
+        config: &Config,
+        db: Db,
+        auth: Auth,
+        events: UserEvents,
+        hub: Hub,
+        logs: LogHandle,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            api_keys: ApiKeyStore::new(db.clone()),
+            audit: None,
+            auth,
+            cookies: CookieSessions::new(&config.cookies, db.clone()),
+            mailer: Mailer::new(&config.mail),
+            passwords: Passwords::new(&config.passwords, db.clone()),
+            roles: RoleStore::new(db.clone()),
+            users: UserStore::new(db.clone(), events.clone()),
+            db,
+            events,
+            hub,
+            logs,
+            metrics,
+        }
+    }
+
+    // records the changes in the audit log, see 'audit.rs'
+    pub fn with_audit(mut self, audit: Option<AuditLog>) -> Self {
+        self.audit = audit;
+        self
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    // A state over an in-memory database, for the tests.
+    pub fn test(config: &Config) -> Self {
+        let db = Db::open(&crate::config::DatabaseConfig {
+            mode: crate::config::DatabaseMode::Memory,
+            ..Default::default()
+        })
+        .expect("cannot open the database");
+        Self::new(
+            config,
+            db.clone(),
+            Auth::new(&config.auth, db),
+            UserEvents::new(&config.events),
+            Hub::new(&config.websocket),
+            crate::logging::detached(),
+            Metrics::new().expect("cannot register the metrics"),
+        )
+    }
+}
@@ -0,0 +1,361 @@
+// TLS
+// When 'tls.enabled' is set, the server speaks HTTPS on 'server.port' with 'rustls', using the PEM
+// certificate and key of the configuration. HTTP/2 and HTTP/1.1 are both offered (ALPN).
+// The files are checked every 'tls.reload_secs' : when they change, the new certificate is used
+// by the next connections, without restarting the server. The open connections keep the
+// certificate they were established with. A certificate that cannot be loaded (for example the
+// new certificate is written but not its key yet) is ignored and the previous one is kept.
+// With 'tls.redirect_port', a plain HTTP listener redirects every request to HTTPS.
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    extract::Request,
+    http::{header, uri::Authority},
+    response::Redirect,
+    Router,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::TlsConfig;
+use crate::error::{AppError, ErrorCode};
+
+#[derive(Debug)]
+pub enum TlsError {
+    // a file cannot be read, or holds no valid PEM section of the expected kind
+    Pem {
+        path: PathBuf,
+        source: rustls::pki_types::pem::Error,
+    },
+    // the certificate file holds no certificate
+    NoCertificate(PathBuf),
+    // the key is not supported, or does not match the certificate
+    Key(rustls::Error),
+    // the provider supports none of the protocol versions
+    Protocols(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Pem { path, source } => {
+                write!(f, "cannot read '{}': {source}", path.display())
+            }
+            TlsError::NoCertificate(path) => {
+                write!(f, "no certificate found in '{}'", path.display())
+            }
+            TlsError::Key(err) => write!(f, "invalid private key: {err}"),
+            TlsError::Protocols(err) => write!(f, "unsupported TLS versions: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Pem { source, .. } => Some(source),
+            TlsError::Key(err) | TlsError::Protocols(err) => Some(err),
+            TlsError::NoCertificate(_) => None,
+        }
+    }
+}
+
+// The certificate of the server, shared by the TLS configuration and the task reloading it.
+#[derive(Clone)]
+pub struct Certificates {
+    resolver: Arc<Resolver>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+// Gives the current certificate to each new TLS connection.
+#[derive(Debug)]
+struct Resolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let current = self.current.read().unwrap_or_else(PoisonError::into_inner);
+        Some(current.clone())
+    }
+}
+
+impl Certificates {
+    pub fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let key = load_key(&config.cert_path, &config.key_path)?;
+        Ok(Self {
+            resolver: Arc::new(Resolver {
+                current: RwLock::new(key),
+            }),
+            cert_path: config.cert_path.clone(),
+            key_path: config.key_path.clone(),
+        })
+    }
+
+    // The TLS side of the server, always using the current certificate.
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Protocols)?
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    // Reloads the certificate each time one of the files changes. Runs until the process exits.
+    pub async fn watch(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick completes immediately
+        ticker.tick().await;
+        let mut last = self.modified();
+        loop {
+            ticker.tick().await;
+            let modified = self.modified();
+            if modified == last {
+                continue;
+            }
+            last = modified;
+            match load_key(&self.cert_path, &self.key_path) {
+                Ok(key) => {
+                    *self
+                        .resolver
+                        .current
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner) = key;
+                    tracing::info!(path = %self.cert_path.display(), "TLS certificate reloaded");
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "cannot reload the TLS certificate, the previous one is kept: {err}"
+                    )
+                }
+            }
+        }
+    }
+
+    // last modification of the files, 'None' for a file that cannot be read
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        let modified = |path: &Path| {
+            std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+        };
+        [modified(&self.cert_path), modified(&self.key_path)]
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>, TlsError> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|source| TlsError::Pem {
+            path: cert_path.to_path_buf(),
+            source,
+        })?;
+    if chain.is_empty() {
+        return Err(TlsError::NoCertificate(cert_path.to_path_buf()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|source| TlsError::Pem {
+        path: key_path.to_path_buf(),
+        source,
+    })?;
+    let key = CertifiedKey::from_der(chain, key, &provider()).map_err(TlsError::Key)?;
+    Ok(Arc::new(key))
+}
+
+// Application of the HTTP listener : every request is redirected to the same path over HTTPS.
+pub fn redirect(https_port: u16) -> Router {
+    Router::new().fallback(move |request: Request| async move { to_https(&request, https_port) })
+}
+
+fn to_https(request: &Request, https_port: u16) -> Result<Redirect, AppError> {
+    // HTTP/1.1 clients send the 'Host' header, HTTP/2 clients the authority of the URI
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .or_else(|| request.uri().authority().cloned())
+        .ok_or_else(|| {
+            AppError::new(ErrorCode::BadRequest).with_detail("the 'Host' header is missing")
+        })?;
+    let port = match https_port {
+        443 => String::new(),
+        port => format!(":{port}"),
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    Ok(Redirect::permanent(&format!(
+        "https://{}{port}{path}",
+        host.host()
+    )))
+}
+
+// Client side of the 'healthcheck' command. The local server is trusted whatever its certificate :
+// the probe only checks that the server answers, and the certificate is usually issued for a
+// public name, not for the loopback address.
+pub fn probe_connector() -> Result<TlsConnector, TlsError> {
+    let provider = provider();
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::Protocols)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// Accepts any certificate, but still checks the signatures of the handshake.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::routing::get;
+    use tempfile::TempDir;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{health, server};
+
+    // A new self-signed certificate for 'localhost', written to the files of the configuration.
+    // Returns the certificate.
+    fn write_certificate(config: &TlsConfig) -> Vec<u8> {
+        let generated =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        std::fs::write(&config.key_path, generated.signing_key.serialize_pem()).unwrap();
+        std::fs::write(&config.cert_path, generated.cert.pem()).unwrap();
+        generated.cert.der().to_vec()
+    }
+
+    // The certificate the server shows to a new connection.
+    async fn served_certificate(addr: SocketAddr) -> Vec<u8> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = probe_connector()
+            .unwrap()
+            .connect(ServerName::IpAddress(addr.ip().into()), stream)
+            .await
+            .unwrap();
+        let (_, connection) = stream.get_ref();
+        connection.peer_certificates().unwrap()[0].to_vec()
+    }
+
+    #[tokio::test]
+    async fn serves_https_and_reloads_the_certificate_when_its_files_change() {
+        let dir = TempDir::new().unwrap();
+        let config = TlsConfig {
+            enabled: true,
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+            ..TlsConfig::default()
+        };
+        let first = write_certificate(&config);
+        let certificates = Certificates::load(&config).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/healthz", get(|| async { "ok" }));
+        let server = tokio::spawn(server::serve(
+            listener,
+            app,
+            Some(certificates.acceptor().unwrap()),
+            std::future::pending(),
+            Duration::ZERO,
+        ));
+        let watcher = tokio::spawn(certificates.watch(Duration::from_millis(20)));
+
+        let status = health::probe(
+            addr,
+            "/healthz",
+            Some(probe_connector().unwrap()),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(status, Ok(200));
+        assert_eq!(served_certificate(addr).await, first);
+
+        let second = write_certificate(&config);
+        let mut served = served_certificate(addr).await;
+        for _ in 0..100 {
+            if served == second {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            served = served_certificate(addr).await;
+        }
+        assert_eq!(served, second, "the new certificate is not served");
+
+        // a certificate that cannot be loaded is ignored
+        std::fs::write(&config.cert_path, "not a certificate").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(served_certificate(addr).await, second);
+
+        watcher.abort();
+        server.abort();
+    }
+}
@@ -0,0 +1,1232 @@
+// USERS
+// REST resource built on the 'User' structure of the structures tutorial
+// (src/tuto/structures/main.rs) :
+//   GET    /users       lists the users, a page at a time, sorted and filtered (see 'ListUsers')
+//   POST   /users       creates a user, the server assigns its identifier
+//   GET    /users/{id}  reads a user
+//   PUT    /users/{id}  replaces every field of a user
+//   PATCH  /users/{id}  replaces only the fields given in the body
+//   DELETE /users/{id}  deletes a user
+// The changes are also streamed to the clients of '/users/events' (see 'events.rs').
+// Reading requires the 'users:read' scope, any change the 'users:write' scope. A change also
+// requires the 'ETag' of the user in 'If-Match' (see 'etag.rs').
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Router,
+};
+use rusqlite::{
+    params, params_from_iter, types::ToSqlOutput, Connection, OptionalExtension, Row, ToSql,
+    TransactionBehavior,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::audit::AuditChange;
+use crate::auth::{RequireScope, Scope};
+use crate::db::{Db, DbError};
+use crate::error::{AppError, ErrorCode, Problem};
+use crate::etag::{self, IfMatch, IfNoneMatch};
+use crate::events::{UserEventKind, UserEvents};
+use crate::extract::{Id, Json, Path, Query, MAX_ID};
+use crate::pagination;
+use crate::passwords::Password;
+use crate::roles;
+use crate::state::AppState;
+use crate::validation::{self, Violations};
+
+// Same fields as the tutorial, plus the identifier assigned by the server.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct User {
+    pub id: u64,
+    pub active: bool,
+    pub username: String,
+    pub email: String,
+    pub sign_in_count: u64,
+    // sent in the 'ETag' header, not in the body, see 'etag.rs'
+    #[serde(skip)]
+    pub version: u64,
+}
+
+// Body of 'POST /users'.
+// 'active' and 'sign_in_count' are optional : when missing, the values of 'build_user' are used.
+// A user created without 'password' cannot sign in until it resets its password.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateUser {
+    pub username: String,
+    pub email: String,
+    pub active: Option<bool>,
+    pub sign_in_count: Option<u64>,
+    #[schema(value_type = Option<String>, format = Password)]
+    pub password: Option<Password>,
+}
+
+// Body of 'PUT /users/{id}', every field is required.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ReplaceUser {
+    pub username: String,
+    pub email: String,
+    pub active: bool,
+    pub sign_in_count: u64,
+}
+
+// Body of 'PATCH /users/{id}', every field is optional.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateUser {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub active: Option<bool>,
+    pub sign_in_count: Option<u64>,
+}
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+// the integers of SQLite are signed
+pub const MAX_SIGN_IN_COUNT: u64 = i64::MAX as u64;
+
+fn check_username(username: &str) -> Result<(), String> {
+    let valid = (MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || ".-_".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "must be {MIN_USERNAME_LEN} to {MAX_USERNAME_LEN} characters among a-z, 0-9, '.', '_' and '-'"
+        ))
+    }
+}
+
+fn check_sign_in_count(sign_in_count: u64) -> Result<(), String> {
+    if sign_in_count <= MAX_SIGN_IN_COUNT {
+        Ok(())
+    } else {
+        Err(format!("must be at most {MAX_SIGN_IN_COUNT}"))
+    }
+}
+
+// The syntax of the fields given in a body, the fields not given are not checked. Their
+// uniqueness is checked when the user is written, see 'check_unique'.
+fn check_user(
+    username: Option<&str>,
+    email: Option<&str>,
+    sign_in_count: Option<u64>,
+) -> Violations {
+    let mut violations = Violations::default();
+    if let Some(username) = username {
+        violations.check("username", check_username(username));
+    }
+    if let Some(email) = email {
+        violations.check("email", validation::check_email(email));
+    }
+    if let Some(sign_in_count) = sign_in_count {
+        violations.check("sign_in_count", check_sign_in_count(sign_in_count));
+    }
+    violations
+}
+
+impl CreateUser {
+    fn check(&self) -> Violations {
+        check_user(Some(&self.username), Some(&self.email), self.sign_in_count)
+    }
+}
+
+impl ReplaceUser {
+    fn check(&self) -> Violations {
+        check_user(
+            Some(&self.username),
+            Some(&self.email),
+            Some(self.sign_in_count),
+        )
+    }
+}
+
+impl UpdateUser {
+    fn check(&self) -> Violations {
+        check_user(
+            self.username.as_deref(),
+            self.email.as_deref(),
+            self.sign_in_count,
+        )
+    }
+}
+
+// Same as the tutorial : a new user is active and has signed in once.
+pub fn build_user(id: u64, username: String, email: String) -> User {
+    User {
+        id,
+        active: true,
+        username,
+        email,
+        sign_in_count: 1,
+        version: 1,
+    }
+}
+
+impl User {
+    fn replace(&mut self, body: ReplaceUser) {
+        self.username = body.username;
+        self.email = body.email;
+        self.active = body.active;
+        self.sign_in_count = body.sign_in_count;
+    }
+
+    fn update(&mut self, body: UpdateUser) {
+        if let Some(username) = body.username {
+            self.username = username;
+        }
+        if let Some(email) = body.email {
+            self.email = email;
+        }
+        if let Some(active) = body.active {
+            self.active = active;
+        }
+        if let Some(sign_in_count) = body.sign_in_count {
+            self.sign_in_count = sign_in_count;
+        }
+    }
+}
+
+// A field of the users that the listing can be sorted on or filtered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    Id,
+    Active,
+    Username,
+    Email,
+    SignInCount,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "id" => Some(Field::Id),
+            "active" => Some(Field::Active),
+            "username" => Some(Field::Username),
+            "email" => Some(Field::Email),
+            "sign_in_count" => Some(Field::SignInCount),
+            _ => None,
+        }
+    }
+
+    // also the name of the field in the JSON and in the query string
+    fn column(self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Active => "active",
+            Field::Username => "username",
+            Field::Email => "email",
+            Field::SignInCount => "sign_in_count",
+        }
+    }
+
+    fn of(self, user: &User) -> Scalar {
+        match self {
+            Field::Id => Scalar::Int(user.id),
+            Field::Active => Scalar::Bool(user.active),
+            Field::Username => Scalar::Text(user.username.clone()),
+            Field::Email => Scalar::Text(user.email.clone()),
+            Field::SignInCount => Scalar::Int(user.sign_in_count),
+        }
+    }
+}
+
+// The value of a field, in a filter or in a cursor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Scalar {
+    Bool(bool),
+    Int(u64),
+    Text(String),
+}
+
+impl ToSql for Scalar {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            Scalar::Bool(value) => value.to_sql(),
+            Scalar::Int(value) => value.to_sql(),
+            Scalar::Text(value) => value.to_sql(),
+        }
+    }
+}
+
+// 'sort=username' sorts by ascending username, 'sort=-username' by descending username. The users
+// with the same value are sorted by identifier, so the order is always the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Sort {
+    field: Field,
+    descending: bool,
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Self {
+            field: Field::Id,
+            descending: false,
+        }
+    }
+}
+
+impl Sort {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (descending, name) = match value.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, value),
+        };
+        match Field::parse(name) {
+            Some(field) if field != Field::Active => Ok(Self { field, descending }),
+            _ => Err(String::from(
+                "must be one of id, username, email or sign_in_count, prefixed by '-' for a descending sort",
+            )),
+        }
+    }
+
+    fn to_param(self) -> String {
+        let prefix = if self.descending { "-" } else { "" };
+        format!("{prefix}{}", self.field.column())
+    }
+}
+
+// Comparison of a filter : 'sign_in_count[gte]=5'. Without operator, the values must be equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Operator {
+    Eq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl Operator {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "eq" => Some(Operator::Eq),
+            "gt" => Some(Operator::Gt),
+            "gte" => Some(Operator::Gte),
+            "lt" => Some(Operator::Lt),
+            "lte" => Some(Operator::Lte),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Operator::Eq => "eq",
+            Operator::Gt => "gt",
+            Operator::Gte => "gte",
+            Operator::Lt => "lt",
+            Operator::Lte => "lte",
+        }
+    }
+
+    fn sql(self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Gt => ">",
+            Operator::Gte => ">=",
+            Operator::Lt => "<",
+            Operator::Lte => "<=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Filter {
+    field: Field,
+    operator: Operator,
+    value: Scalar,
+}
+
+impl Filter {
+    // 'name' is the parameter of the query string, such as 'active' or 'sign_in_count[gte]'
+    fn parse(name: &str, value: &str) -> Result<Self, String> {
+        let (field, operator) = match name.strip_suffix(']').and_then(|name| name.split_once('[')) {
+            Some((field, operator)) => (field, Some(operator)),
+            None => (name, None),
+        };
+        let field = match Field::parse(field) {
+            Some(field) if field != Field::Id => field,
+            _ => return Err(String::from("unknown parameter")),
+        };
+        let operator = match operator {
+            None => Operator::Eq,
+            Some(operator) => Operator::parse(operator).ok_or_else(|| {
+                String::from("unknown operator, use one of eq, gt, gte, lt or lte")
+            })?,
+        };
+        if operator != Operator::Eq && field != Field::SignInCount {
+            return Err(format!("{} can only be compared with eq", field.column()));
+        }
+        let value = match field {
+            Field::Active => Scalar::Bool(
+                value
+                    .parse()
+                    .map_err(|_| String::from("must be true or false"))?,
+            ),
+            Field::SignInCount => Scalar::Int(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|count| *count <= MAX_SIGN_IN_COUNT)
+                    .ok_or_else(|| {
+                        format!("must be an integer between 0 and {MAX_SIGN_IN_COUNT}")
+                    })?,
+            ),
+            _ => Scalar::Text(value.to_owned()),
+        };
+        Ok(Self {
+            field,
+            operator,
+            value,
+        })
+    }
+
+    fn to_param(&self) -> (String, String) {
+        let name = match self.operator {
+            Operator::Eq => self.field.column().to_owned(),
+            operator => format!("{}[{}]", self.field.column(), operator.as_str()),
+        };
+        let value = match &self.value {
+            Scalar::Bool(value) => value.to_string(),
+            Scalar::Int(value) => value.to_string(),
+            Scalar::Text(value) => value.clone(),
+        };
+        (name, value)
+    }
+}
+
+// The sort and the filters of a listing, kept in its cursors.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserQuery {
+    sort: Sort,
+    filters: Vec<Filter>,
+}
+
+impl UserQuery {
+    // the parameters of the query string giving the same listing
+    fn to_params(&self) -> Vec<(String, String)> {
+        let mut params = Vec::new();
+        if self.sort != Sort::default() {
+            params.push((String::from("sort"), self.sort.to_param()));
+        }
+        params.extend(self.filters.iter().map(Filter::to_param));
+        params
+    }
+}
+
+// Position of the next page : the sort key and the identifier of the last user of the page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserCursor {
+    query: UserQuery,
+    key: Scalar,
+    id: u64,
+}
+
+// Parameters of 'GET /users'.
+struct ListUsers {
+    query: UserQuery,
+    after: Option<(Scalar, u64)>,
+    limit: usize,
+}
+
+impl ListUsers {
+    // Every invalid parameter is reported at once, each one as an error of its field.
+    fn parse(params: Vec<(String, String)>) -> Result<Self, AppError> {
+        let mut errors = Vec::new();
+        let mut limit = pagination::DEFAULT_LIMIT;
+        let mut cursor = None;
+        let mut sort = None;
+        let mut filters = Vec::new();
+        for (name, value) in params {
+            let parsed = match name.as_str() {
+                "limit" => pagination::parse_limit(&value).map(|value| limit = value),
+                "cursor" => {
+                    cursor = Some(value);
+                    Ok(())
+                }
+                "sort" => Sort::parse(&value).map(|value| sort = Some(value)),
+                _ => Filter::parse(&name, &value).map(|filter| filters.push(filter)),
+            };
+            if let Err(message) = parsed {
+                errors.push((name, message));
+            }
+        }
+        if !errors.is_empty() {
+            let error = AppError::new(ErrorCode::InvalidQuery)
+                .with_detail("the query string has invalid parameters");
+            return Err(errors.into_iter().fold(error, |error, (name, message)| {
+                error.with_field(name, message)
+            }));
+        }
+
+        // the sort and the filters may be repeated with the cursor, but not changed
+        let given = sort.is_some() || !filters.is_empty();
+        let query = UserQuery {
+            sort: sort.unwrap_or_default(),
+            filters,
+        };
+        let Some(cursor) = cursor else {
+            return Ok(Self {
+                query,
+                after: None,
+                limit,
+            });
+        };
+        let cursor: UserCursor = pagination::decode_cursor(&cursor)?;
+        // a cursor forged with integers the database cannot hold
+        if cursor.id > MAX_ID || matches!(cursor.key, Scalar::Int(key) if key > MAX_ID) {
+            return Err(AppError::new(ErrorCode::InvalidQuery)
+                .with_field("cursor", "not a cursor given by a previous page"));
+        }
+        if given && cursor.query != query {
+            return Err(AppError::new(ErrorCode::InvalidQuery).with_field(
+                "cursor",
+                "the cursor was given for another sort or other filters",
+            ));
+        }
+        Ok(Self {
+            query: cursor.query,
+            after: Some((cursor.key, cursor.id)),
+            limit,
+        })
+    }
+}
+
+// What became of a change of a user.
+pub enum Outcome<T> {
+    Done(T),
+    NotFound,
+    // the user is at another version than the one of 'If-Match', given here
+    Stale(u64),
+    // the fields of the change are invalid, or used by another user
+    Invalid(Violations),
+    // the change would leave no active user with the role 'admin'
+    LastAdmin,
+}
+
+// Checks that no other user has the username or the email of the user. Only the valid fields
+// changed since 'before' are checked, so a user that already shared a field can still change the
+// others.
+// Upper and lower case letters are the same : 'Sam@Shire.com' is 'sam@shire.com'.
+fn check_unique(
+    conn: &Connection,
+    user: &User,
+    before: Option<&User>,
+    violations: &mut Violations,
+) -> rusqlite::Result<()> {
+    let fields = [
+        (
+            "username",
+            &user.username,
+            before.map(|before| &before.username),
+        ),
+        ("email", &user.email, before.map(|before| &before.email)),
+    ];
+    for (column, value, before) in fields {
+        if before == Some(value) || violations.has(column) {
+            continue;
+        }
+        let used: bool = conn.query_row(
+            &format!(
+                "SELECT EXISTS (SELECT 1 FROM users WHERE {column} = ?1 COLLATE NOCASE AND id != ?2)"
+            ),
+            params![value, user.id],
+            |row| row.get(0),
+        )?;
+        if used {
+            violations.add(column, "is already used by another user");
+        }
+    }
+    Ok(())
+}
+
+// Storage of the users in the database. Every change is published to the events of '/users/events'.
+#[derive(Clone)]
+pub struct UserStore {
+    db: Db,
+    events: UserEvents,
+}
+
+pub const USER_COLUMNS: &str = "id, active, username, email, sign_in_count, version";
+
+pub fn user_from_row(row: &Row<'_>) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        active: row.get(1)?,
+        username: row.get(2)?,
+        email: row.get(3)?,
+        sign_in_count: row.get(4)?,
+        version: row.get(5)?,
+    })
+}
+
+pub fn select_user(conn: &Connection, id: u64) -> rusqlite::Result<Option<User>> {
+    conn.query_row(
+        &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"),
+        [id],
+        user_from_row,
+    )
+    .optional()
+}
+
+impl UserStore {
+    pub fn new(db: Db, events: UserEvents) -> Self {
+        Self { db, events }
+    }
+
+    // The first 'limit' users matching the filters, in the order of the sort, after the position
+    // of the sort key and identifier given by 'after'.
+    pub async fn list(
+        &self,
+        query: UserQuery,
+        after: Option<(Scalar, u64)>,
+        limit: usize,
+    ) -> Result<Vec<User>, DbError> {
+        self.db
+            .call(move |conn| {
+                let mut conditions = Vec::new();
+                let mut values = Vec::new();
+                for filter in query.filters {
+                    conditions.push(format!(
+                        "{} {} ?",
+                        filter.field.column(),
+                        filter.operator.sql()
+                    ));
+                    values.push(filter.value);
+                }
+                let column = query.sort.field.column();
+                let (order, next) = match query.sort.descending {
+                    false => ("ASC", ">"),
+                    true => ("DESC", "<"),
+                };
+                if let Some((key, id)) = after {
+                    conditions.push(format!("({column}, id) {next} (?, ?)"));
+                    values.extend([key, Scalar::Int(id)]);
+                }
+                let conditions = match conditions.is_empty() {
+                    true => String::new(),
+                    false => format!("WHERE {}", conditions.join(" AND ")),
+                };
+                let mut statement = conn.prepare(&format!(
+                    "SELECT {USER_COLUMNS} FROM users {conditions}
+                     ORDER BY {column} {order}, id {order} LIMIT {limit}"
+                ))?;
+                let users = statement
+                    .query_map(params_from_iter(values), user_from_row)?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(users)
+            })
+            .await
+    }
+
+    pub async fn get(&self, id: u64) -> Result<Option<User>, DbError> {
+        self.db.call(move |conn| Ok(select_user(conn, id)?)).await
+    }
+
+    // Creates the user with the hash of its password, unless its fields have violations : the ones
+    // found in the body are given, the ones of uniqueness are added.
+    pub async fn create(
+        &self,
+        body: CreateUser,
+        password_hash: Option<String>,
+        mut violations: Violations,
+    ) -> Result<Result<User, Violations>, DbError> {
+        let user = self
+            .db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                // the identifier is assigned by the database on insert
+                let defaults = build_user(0, body.username, body.email);
+                let mut user = User {
+                    active: body.active.unwrap_or(defaults.active),
+                    sign_in_count: body.sign_in_count.unwrap_or(defaults.sign_in_count),
+                    ..defaults
+                };
+                check_unique(&tx, &user, None, &mut violations)?;
+                if !violations.is_empty() {
+                    return Ok(Err(violations));
+                }
+                tx.execute(
+                    "INSERT INTO users (active, username, email, sign_in_count, password_hash)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        user.active,
+                        user.username,
+                        user.email,
+                        user.sign_in_count,
+                        password_hash
+                    ],
+                )?;
+                user.id = tx.last_insert_rowid() as u64;
+                tx.commit()?;
+                Ok(Ok(user))
+            })
+            .await?;
+        if let Ok(user) = &user {
+            self.events.publish(UserEventKind::Created, user);
+        }
+        Ok(user)
+    }
+
+    // Applies the change to the user, if it exists, its version matches and the changed fields
+    // have no violations (the ones found in the body are given), and returns its states before and
+    // after the change.
+    // The read and the write happen in the same transaction, so concurrent changes of the same
+    // user are applied one after the other, and the second one sees the version of the first.
+    pub async fn modify(
+        &self,
+        id: u64,
+        if_match: IfMatch,
+        mut violations: Violations,
+        change: impl FnOnce(&mut User) + Send + 'static,
+    ) -> Result<Outcome<(User, User)>, DbError> {
+        let outcome = self
+            .db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let Some(mut user) = select_user(&tx, id)? else {
+                    return Ok(Outcome::NotFound);
+                };
+                if !if_match.matches(user.version) {
+                    return Ok(Outcome::Stale(user.version));
+                }
+                let before = user.clone();
+                change(&mut user);
+                check_unique(&tx, &user, Some(&before), &mut violations)?;
+                if !violations.is_empty() {
+                    return Ok(Outcome::Invalid(violations));
+                }
+                let admins = roles::active_admins(&tx)?;
+                user.version += 1;
+                tx.execute(
+                    "UPDATE users SET active = ?2, username = ?3, email = ?4, sign_in_count = ?5,
+                     version = ?6 WHERE id = ?1",
+                    params![
+                        user.id,
+                        user.active,
+                        user.username,
+                        user.email,
+                        user.sign_in_count,
+                        user.version
+                    ],
+                )?;
+                // dropping the transaction rolls the change back
+                if admins > 0 && roles::active_admins(&tx)? == 0 {
+                    return Ok(Outcome::LastAdmin);
+                }
+                tx.commit()?;
+                Ok(Outcome::Done((before, user)))
+            })
+            .await?;
+        if let Outcome::Done((_, user)) = &outcome {
+            self.events.publish(UserEventKind::Updated, user);
+        }
+        Ok(outcome)
+    }
+
+    // Deletes the user, if it exists and its version matches, and returns its last state.
+    pub async fn delete(&self, id: u64, if_match: IfMatch) -> Result<Outcome<User>, DbError> {
+        let outcome = self
+            .db
+            .call(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let Some(user) = select_user(&tx, id)? else {
+                    return Ok(Outcome::NotFound);
+                };
+                if !if_match.matches(user.version) {
+                    return Ok(Outcome::Stale(user.version));
+                }
+                let admins = roles::active_admins(&tx)?;
+                tx.execute("DELETE FROM users WHERE id = ?1", [id])?;
+                if admins > 0 && roles::active_admins(&tx)? == 0 {
+                    return Ok(Outcome::LastAdmin);
+                }
+                tx.commit()?;
+                Ok(Outcome::Done(user))
+            })
+            .await?;
+        if let Outcome::Done(_) = outcome {
+            self.events
+                .publish(UserEventKind::Deleted, &serde_json::json!({ "id": id }));
+        }
+        Ok(outcome)
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/users", get(list_users).require(Scope::UsersRead))
+        .route("/users", post(create_user).require(Scope::UsersWrite))
+        .route("/users/{id}", get(get_user).require(Scope::UsersRead))
+        .route(
+            "/users/{id}",
+            put(replace_user)
+                .patch(update_user)
+                .delete(delete_user)
+                .require(Scope::UsersWrite),
+        )
+}
+
+fn user_not_found(id: u64) -> AppError {
+    AppError::not_found(format!("user {id} does not exist"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    security(("bearer" = ["users:read"])),
+    params(
+        ("limit" = Option<usize>, Query, description = "Users of the page, 50 by default, 200 at most"),
+        ("cursor" = Option<String>, Query, description = "Position of the page, from the 'Link' header of the previous page"),
+        ("sort" = Option<String>, Query, description = "id, username, email or sign_in_count, prefixed by '-' for a descending sort"),
+        ("active" = Option<bool>, Query, description = "Only the active, or inactive, users"),
+        ("username" = Option<String>, Query, description = "Only the user with this username"),
+        ("email" = Option<String>, Query, description = "Only the users with this email"),
+        ("sign_in_count" = Option<u64>, Query, description = "Only the users with this count, also 'sign_in_count[gt]', '[gte]', '[lt]' or '[lte]' to compare it"),
+    ),
+    responses(
+        (status = 200, description = "A page of users, the next and first pages are in the 'Link' header", body = [User]),
+        (status = 400, description = "Invalid query parameter", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn list_users(
+    State(state): State<AppState>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<impl IntoResponse, AppError> {
+    let ListUsers {
+        query,
+        after,
+        limit,
+    } = ListUsers::parse(params)?;
+    // one more user tells whether there is a next page
+    let mut users = state.users.list(query.clone(), after, limit + 1).await?;
+    let next = if users.len() > limit {
+        users.truncate(limit);
+        users.last().map(|last| {
+            pagination::encode_cursor(&UserCursor {
+                key: query.sort.field.of(last),
+                id: last.id,
+                query: query.clone(),
+            })
+        })
+    } else {
+        None
+    };
+    let links = pagination::links("/users", limit, query.to_params(), next);
+    Ok((links, Json(users)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users",
+    tag = "users",
+    security(("bearer" = ["users:write"])),
+    request_body = CreateUser,
+    responses(
+        (status = 201, description = "The created user, its URL is in 'Location'", body = User,
+            headers(("ETag" = String, description = "Version of the user"))),
+        (status = 422, description = "Invalid body", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn create_user(
+    State(state): State<AppState>,
+    Json(body): Json<CreateUser>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut violations = body.check();
+    let password = body.password.clone();
+    if let Some(password) = &password {
+        violations.check("password", state.passwords.check(password));
+    }
+    // an invalid body costs no hash
+    let password_hash = match password {
+        Some(password) if violations.is_empty() => Some(state.passwords.hash(password).await?),
+        _ => None,
+    };
+    let user = state
+        .users
+        .create(body, password_hash, violations)
+        .await??;
+    let location = format!("/users/{}", user.id);
+    Ok((
+        StatusCode::CREATED,
+        AuditChange::new("user.created", format!("user:{}", user.id)).after(&user),
+        [(header::LOCATION, location)],
+        [(header::ETAG, etag::etag(user.version))],
+        Json(user),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer" = ["users:read"])),
+    params(
+        ("id" = u64, Path, description = "Identifier of the user"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the version the client already has"),
+    ),
+    responses(
+        (status = 200, description = "The user", body = User,
+            headers(("ETag" = String, description = "Version of the user"))),
+        (status = 304, description = "The client already has the current version"),
+        (status = 404, description = "No such user", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn get_user(
+    State(state): State<AppState>,
+    Path(Id(id)): Path<Id>,
+    if_none_match: IfNoneMatch,
+) -> Result<Response, AppError> {
+    let user = state
+        .users
+        .get(id)
+        .await?
+        .ok_or_else(|| user_not_found(id))?;
+    if if_none_match.matches(user.version) {
+        return Ok(etag::not_modified(user.version));
+    }
+    Ok(with_etag(user))
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer" = ["users:write"])),
+    params(
+        ("id" = u64, Path, description = "Identifier of the user"),
+        ("If-Match" = String, Header, description = "ETag of the version to replace"),
+    ),
+    request_body = ReplaceUser,
+    responses(
+        (status = 200, description = "The replaced user", body = User,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 404, description = "No such user", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The user is the last administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The user changed since this version", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid body", body = Problem, content_type = "application/problem+json"),
+        (status = 428, description = "No 'If-Match' header", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn replace_user(
+    State(state): State<AppState>,
+    Path(Id(id)): Path<Id>,
+    if_match: IfMatch,
+    Json(body): Json<ReplaceUser>,
+) -> Result<Response, AppError> {
+    let violations = body.check();
+    let outcome = state
+        .users
+        .modify(id, if_match, violations, |user| user.replace(body))
+        .await?;
+    outcome_of(id, outcome).map(|(before, user)| changed(before, user))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer" = ["users:write"])),
+    params(
+        ("id" = u64, Path, description = "Identifier of the user"),
+        ("If-Match" = String, Header, description = "ETag of the version to update"),
+    ),
+    request_body = UpdateUser,
+    responses(
+        (status = 200, description = "The updated user", body = User,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 404, description = "No such user", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The user is the last administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The user changed since this version", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid body", body = Problem, content_type = "application/problem+json"),
+        (status = 428, description = "No 'If-Match' header", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn update_user(
+    State(state): State<AppState>,
+    Path(Id(id)): Path<Id>,
+    if_match: IfMatch,
+    Json(body): Json<UpdateUser>,
+) -> Result<Response, AppError> {
+    let violations = body.check();
+    let outcome = state
+        .users
+        .modify(id, if_match, violations, |user| user.update(body))
+        .await?;
+    outcome_of(id, outcome).map(|(before, user)| changed(before, user))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{id}",
+    tag = "users",
+    security(("bearer" = ["users:write"])),
+    params(
+        ("id" = u64, Path, description = "Identifier of the user"),
+        ("If-Match" = String, Header, description = "ETag of the version to delete"),
+    ),
+    responses(
+        (status = 204, description = "The user is deleted"),
+        (status = 404, description = "No such user", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The user is the last administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The user changed since this version", body = Problem, content_type = "application/problem+json"),
+        (status = 428, description = "No 'If-Match' header", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn delete_user(
+    State(state): State<AppState>,
+    Path(Id(id)): Path<Id>,
+    if_match: IfMatch,
+) -> Result<Response, AppError> {
+    let outcome = state.users.delete(id, if_match).await?;
+    outcome_of(id, outcome).map(|user| {
+        let change = AuditChange::new("user.deleted", format!("user:{id}")).before(&user);
+        (change, StatusCode::NO_CONTENT).into_response()
+    })
+}
+
+fn outcome_of<T>(id: u64, outcome: Outcome<T>) -> Result<T, AppError> {
+    match outcome {
+        Outcome::Done(value) => Ok(value),
+        Outcome::NotFound => Err(user_not_found(id)),
+        Outcome::Stale(version) => Err(etag::precondition_failed(version)),
+        Outcome::Invalid(violations) => Err(violations.into()),
+        Outcome::LastAdmin => Err(roles::last_admin(id)),
+    }
+}
+
+// the user in the body, its version in 'ETag'
+fn with_etag(user: User) -> Response {
+    ([(header::ETAG, etag::etag(user.version))], Json(user)).into_response()
+}
+
+// the changed user, with its states before and after for the audit log
+fn changed(before: User, user: User) -> Response {
+    let change = AuditChange::new("user.updated", format!("user:{}", user.id))
+        .before(&before)
+        .after(&user);
+    (change, with_etag(user)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+
+    async fn create(state: &AppState, username: &str, active: bool, sign_in_count: u64) -> u64 {
+        let body = CreateUser {
+            username: username.to_string(),
+            email: format!("{username}@example.com"),
+            active: Some(active),
+            sign_in_count: Some(sign_in_count),
+            password: None,
+        };
+        let user = state.users.create(body, None, Violations::default());
+        user.await.unwrap().unwrap().id
+    }
+
+    // The status, the 'Link' header and the body of a read, without the authentication.
+    async fn read(state: &AppState, uri: &str) -> (StatusCode, String, serde_json::Value) {
+        let app = Router::new()
+            .route("/users", get(list_users))
+            .route("/users/{id}", get(get_user))
+            .with_state(state.clone());
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let link = response
+            .headers()
+            .get(header::LINK)
+            .map_or(String::new(), |link| link.to_str().unwrap().to_owned());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        (
+            status,
+            link,
+            serde_json::from_slice(&body.await.unwrap()).unwrap(),
+        )
+    }
+
+    fn next(link: &str) -> Option<&str> {
+        let (next, _) = link.split_once(">; rel=\"next\"")?;
+        next.strip_prefix('<')
+    }
+
+    fn usernames(users: &serde_json::Value) -> Vec<&str> {
+        let users = users.as_array().unwrap();
+        users
+            .iter()
+            .map(|user| user["username"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_pages_stable_while_users_are_created() {
+        let state = AppState::test(&Config::default());
+        for username in ["bilbo", "frodo", "merry", "pippin", "sam"] {
+            create(&state, username, true, 0).await;
+        }
+
+        let (status, link, users) = read(&state, "/users?sort=username&limit=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(usernames(&users), ["bilbo", "frodo"]);
+        // a user before the position and one after it
+        create(&state, "aragorn", true, 0).await;
+        create(&state, "tom", true, 0).await;
+        let (_, link, users) = read(&state, next(&link).unwrap()).await;
+        assert_eq!(usernames(&users), ["merry", "pippin"]);
+        let (_, link, users) = read(&state, next(&link).unwrap()).await;
+        assert_eq!(usernames(&users), ["sam", "tom"]);
+        assert_eq!(next(&link), None);
+    }
+
+    #[tokio::test]
+    async fn sorts_and_filters_every_page_the_same() {
+        let state = AppState::test(&Config::default());
+        for (username, active, sign_in_count) in [
+            ("bilbo", true, 3),
+            ("frodo", false, 5),
+            ("merry", true, 5),
+            ("pippin", true, 1),
+            ("sam", true, 8),
+        ] {
+            create(&state, username, active, sign_in_count).await;
+        }
+
+        let uri = "/users?sort=-sign_in_count&active=true&sign_in_count[gte]=2&limit=2";
+        let (_, link, users) = read(&state, uri).await;
+        assert_eq!(usernames(&users), ["sam", "merry"]);
+        let first = "</users?limit=2&sort=-sign_in_count&active=true&sign_in_count%5Bgte%5D=2>; \
+                     rel=\"first\"";
+        assert!(link.ends_with(first), "{link}");
+        // the cursor keeps the sort and the filters
+        let next = next(&link).unwrap();
+        assert!(next.starts_with("/users?limit=2&cursor="));
+        let (_, link, users) = read(&state, next).await;
+        assert_eq!(usernames(&users), ["bilbo"]);
+        assert!(!link.contains("rel=\"next\""));
+
+        // they can be repeated with the cursor, not changed
+        let filters = "sort=-sign_in_count&active=true&sign_in_count[gte]=2";
+        let (status, _, _) = read(&state, &format!("{next}&{filters}")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, problem) = read(&state, &format!("{next}&sort=username")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["errors"][0]["field"], "cursor");
+    }
+
+    #[tokio::test]
+    async fn refuses_the_identifiers_sqlite_cannot_store() {
+        let state = AppState::test(&Config::default());
+
+        let (status, _, _) = read(&state, &format!("/users/{MAX_ID}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = read(&state, &format!("/users/{}", MAX_ID + 1)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        for (key, id) in [(Scalar::Int(0), u64::MAX), (Scalar::Int(u64::MAX), 0)] {
+            let cursor = pagination::encode_cursor(&UserCursor {
+                query: UserQuery::default(),
+                key,
+                id,
+            });
+            let (status, _, _) = read(&state, &format!("/users?cursor={cursor}")).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    async fn post_user(
+        state: &AppState,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let app = Router::new()
+            .route("/users", post(create_user))
+            .with_state(state.clone());
+        let request = Request::post("/users")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        (
+            status,
+            serde_json::from_slice(&body.await.unwrap()).unwrap(),
+        )
+    }
+
+    // the fields of the errors of a problem
+    fn fields(problem: &serde_json::Value) -> Vec<&str> {
+        let errors = problem["errors"].as_array().unwrap();
+        errors
+            .iter()
+            .map(|error| error["field"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reports_every_invalid_field_at_once() {
+        let state = AppState::test(&Config::default());
+        let body = serde_json::json!({
+            "username": "S",
+            "email": "sam",
+            "sign_in_count": MAX_SIGN_IN_COUNT + 1,
+            "password": "short",
+        });
+
+        let (status, problem) = post_user(&state, body).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["code"], "invalid_body");
+        assert_eq!(
+            fields(&problem),
+            ["username", "email", "sign_in_count", "password"]
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_a_username_or_an_email_used_with_another_case() {
+        let state = AppState::test(&Config::default());
+        create(&state, "sam", true, 0).await;
+
+        let body = serde_json::json!({ "username": "frodo", "email": "Sam@Example.COM" });
+        let (status, problem) = post_user(&state, body).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(fields(&problem), ["email"]);
+        // the usernames of the body are lower case, the ones stored before may not be
+        let body = CreateUser {
+            username: String::from("SAM"),
+            email: String::from("SAM@example.com"),
+            active: None,
+            sign_in_count: None,
+            password: None,
+        };
+        let created = state.users.create(body, None, Violations::default());
+        let violations = created.await.unwrap().unwrap_err();
+        assert!(violations.has("username") && violations.has("email"));
+        let body = serde_json::json!({ "username": "frodo", "email": "frodo@example.com" });
+        assert_eq!(post_user(&state, body).await.0, StatusCode::CREATED);
+    }
+
+    #[test]
+    fn refuses_a_sign_in_count_that_sqlite_cannot_store() {
+        let body = |sign_in_count| UpdateUser {
+            username: None,
+            email: None,
+            active: None,
+            sign_in_count: Some(sign_in_count),
+        };
+        assert!(body(MAX_SIGN_IN_COUNT).check().is_empty());
+        assert!(body(MAX_SIGN_IN_COUNT + 1).check().has("sign_in_count"));
+        assert!(Filter::parse("sign_in_count[gte]", &u64::MAX.to_string()).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+// VALIDATION
+// The fields of a body are all checked before answering, and every violation is reported at once
+// as an error of its field, so a client can show them all next to their inputs :
+//   {"code": "invalid_body", "errors": [
+//     {"field": "username", "message": "must be 3 to 32 characters among a-z, 0-9, '.', '_' and '-'"},
+//     {"field": "email", "message": "is already used by another user"}]}
+// Each resource checks its own fields (see 'check_user' in 'users.rs'), this module only collects
+// the violations and holds the rules shared by several resources.
+use crate::error::{AppError, ErrorCode};
+
+// the maximum length of an address (RFC 5321)
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_EMAIL_LOCAL_LEN: usize = 64;
+
+// The violations found in a body.
+#[derive(Debug, Default)]
+pub struct Violations(Vec<(String, String)>);
+
+impl Violations {
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push((field.into(), message.into()));
+    }
+
+    // Adds the violation of the check, if any.
+    pub fn check(&mut self, field: &str, check: Result<(), String>) {
+        if let Err(message) = check {
+            self.add(field, message);
+        }
+    }
+
+    // whether the field already has a violation
+    pub fn has(&self, field: &str) -> bool {
+        self.0.iter().any(|(violated, _)| violated == field)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Violations> for AppError {
+    fn from(violations: Violations) -> Self {
+        let error =
+            AppError::new(ErrorCode::InvalidBody).with_detail("the body has invalid fields");
+        violations
+            .0
+            .into_iter()
+            .fold(error, |error, (field, message)| {
+                error.with_field(field, message)
+            })
+    }
+}
+
+// The syntax of an address, such as 'sam.gamegie@shire.com' : the common subset of RFC 5322 that
+// the mail servers accept, without quoted strings nor comments.
+pub fn check_email(email: &str) -> Result<(), String> {
+    let invalid = || {
+        Err(String::from(
+            "must be an email address such as sam.gamegie@shire.com",
+        ))
+    };
+    if email.len() > MAX_EMAIL_LEN {
+        return Err(format!("must be at most {MAX_EMAIL_LEN} characters"));
+    }
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return invalid();
+    };
+    let local_valid = !local.is_empty()
+        && local.len() <= MAX_EMAIL_LOCAL_LEN
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-".contains(c))
+        });
+    // at least two labels : a top level domain alone does not receive mails
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_valid = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if local_valid && domain_valid {
+        Ok(())
+    } else {
+        invalid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_common_addresses_only() {
+        for email in ["sam.gamegie@shire.com", "sam+hobbit@mail.shire.com"] {
+            assert!(check_email(email).is_ok(), "{email}");
+        }
+        let long = format!("{}@shire.com", "s".repeat(MAX_EMAIL_LOCAL_LEN + 1));
+        for email in [
+            "sam",
+            "sam@shire",
+            "@shire.com",
+            "sam..gamegie@shire.com",
+            &long,
+        ] {
+            assert!(check_email(email).is_err(), "{email}");
+        }
+    }
+}
@@ -7,24 +7,19 @@ enum IpAddrKind {
     V6,
 }
 
-// enums can also hold different types and definitions
-// Defining an enum with variants like below is similar to defining different kinds of struct.
-// The difference is the enum holds all the variants are grouped together under the 'Message' type.
-enum Message {
-    // simple variant
-    Quit,
-    // typed variant
-    Write(String),
-    // structure variant
-    Move { x: i32, y: i32 },
-    // tuple variant
-    ChangeColor(i32, i32, i32),
-}
+// enums can also hold different types and definitions : see the 'Message' enum in 'message.rs'
+mod message;
+use message::Message;
 
-// enums can be implemented, exactly like structures
+// enums can be implemented, exactly like structures.
+// Here the message is written as the JSON text the REST API sends over a WebSocket : the API
+// implements its own 'send' in 'src/api/rest/axum/messages.rs', which broadcasts that text.
 impl Message {
     fn send(&self) {
-        // write function body
+        match serde_json::to_string(self) {
+            Ok(json) => println!("Message sent : {json}"),
+            Err(err) => println!("Message not sent : {err}"),
+        }
     }
 }
 // If we used the different structs instead of one enum with different variants, each of which has
@@ -0,0 +1,18 @@
+// enums can also hold different types and definitions
+// Defining an enum with variants like below is similar to defining different kinds of struct.
+// The difference is the enum holds all the variants are grouped together under the 'Message' type.
+// This enum lives in its own module so the REST API (src/api/rest/axum/messages.rs) exchanges the
+// very same type. 'Serialize' and 'Deserialize' let the crate 'serde' convert it to and from JSON :
+// the variant goes in 'type' and its fields in 'data', e.g. {"type": "write", "data": "hello"}.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Message {
+    // simple variant
+    Quit,
+    // typed variant
+    Write(String),
+    // structure variant
+    Move { x: i32, y: i32 },
+    // tuple variant
+    ChangeColor(i32, i32, i32),
+}